@@ -1,8 +1,13 @@
+use std::env;
 use std::fs::File;
 use std::path::Path;
 
+use chrono::{DateTime, Utc};
+use clickhouse_rs::Options;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+
+use crate::statistics::database::CURRENT_SEASON;
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct Config {
@@ -13,6 +18,23 @@ pub struct Config {
     #[serde(default = "HashMap::new")]
     pub kickbacks: HashMap<String, Kickback>,
     pub statistics: Option<StatisticsConfig>,
+    pub performance_alerts: Option<PerformanceAlertsConfig>,
+    #[serde(default)]
+    pub log_format: LogFormat,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    Plain,
+    // one JSON object per line, for log aggregators
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Plain
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -25,6 +47,38 @@ pub struct DiscordConfig {
     pub player_avatar_url: Option<String>,
     #[serde(default)]
     pub error_webhook: Option<ErrorWebhookConfig>,
+    // relayed game chat longer than this many characters is cut off with an ellipsis
+    #[serde(default = "default_relay_max_length")]
+    pub relay_max_length: usize,
+    #[serde(default)]
+    pub relay_strip_formatting: bool,
+    // turn `§` formatting codes in relayed game chat into discord markdown where there's an
+    // equivalent, like bold and italics, and drop the rest. takes precedence over stripping them
+    #[serde(default)]
+    pub relay_formatting_as_markdown: bool,
+    // also send relayed name colors to game servers as a `#RRGGBB` string, next to the plain number
+    #[serde(default)]
+    pub name_color_as_hex: bool,
+    // if set, only attachments hosted on one of these hosts are relayed to game servers
+    #[serde(default)]
+    pub attachment_hosts: Option<HashSet<String>>,
+    // how many replies deep a message relayed to game servers can go before the rest of the chain
+    // is replaced with a placeholder. at most 8, however high this is set
+    #[serde(default = "default_max_reply_depth")]
+    pub max_reply_depth: usize,
+    // if set, replies are relayed with a quote of up to this many characters of the message they
+    // reply to, instead of the whole message
+    #[serde(default)]
+    pub reply_quote_length: Option<usize>,
+}
+
+fn default_max_reply_depth() -> usize {
+    1
+}
+
+fn default_relay_max_length() -> usize {
+    // discord rejects messages longer than this
+    2000
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -36,6 +90,24 @@ pub struct ErrorWebhookConfig {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct WebServerConfig {
     pub port: u16,
+    // how long a server's last status update is considered fresh for; unset means only a lost
+    // connection to the server marks its status as stale
+    #[serde(default)]
+    pub status_max_age_seconds: Option<u64>,
+    // compresses every response except the stat change stream, setting Content-Encoding to match
+    #[serde(default)]
+    pub compression: Option<ResponseCompression>,
+    // sent as `Authorization: Bearer <token>` to use routes that aren't public, like a player's
+    // moderation flags or the stat change stream. those routes aren't served at all without one
+    #[serde(default)]
+    pub admin_token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseCompression {
+    Gzip,
+    Brotli,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -59,17 +131,459 @@ pub struct Kickback {
     pub proxy_channel: String,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PerformanceAlertsConfig {
+    // fraction of a server's total memory that it can use before we consider it under memory pressure
+    pub memory_threshold: f32,
+    // how many consecutive performance samples must be over the threshold before alerting
+    #[serde(default = "default_memory_sustained_samples")]
+    pub memory_sustained_samples: u32,
+}
+
+fn default_memory_sustained_samples() -> u32 {
+    5
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct StatisticsConfig {
+    // both of these can be overridden by the NUCLEOID_STATISTICS_DATABASE_URL and
+    // NUCLEOID_STATISTICS_DATABASE_NAME environment variables, which take precedence over the config
     pub database_url: String,
     pub database_name: String,
+    // a replica for leaderboards and other read-only queries, so they don't compete with uploads.
+    // queries that uploads depend on, like working out stat changes, always use the primary
+    #[serde(default)]
+    pub read_database_url: Option<String>,
+    // namespace -> stat -> thresholds to announce when a player's total crosses them
+    #[serde(default)]
+    pub milestones: HashMap<String, HashMap<String, Vec<f64>>>,
+    // namespace -> the only stats it may upload; namespaces without an entry may upload anything
+    #[serde(default)]
+    pub stat_allowlists: HashMap<String, HashSet<String>>,
+    // whether rejected stats are kept in the rejected_stats table rather than just logged
+    #[serde(default)]
+    pub record_rejected_stats: bool,
+    // how long recorded rejected stats are kept for; 0 or null keeps them forever
+    #[serde(default = "default_rejected_stats_retention_days")]
+    pub rejected_stats_retention_days: Option<u32>,
+    // how long applied bundle ids are remembered, which is how long a server can keep retrying a
+    // bundle without it being counted twice
+    #[serde(default = "default_applied_bundle_retention_days")]
+    pub applied_bundle_retention_days: u32,
+    #[serde(default)]
+    pub achievements: Vec<AchievementConfig>,
+    // namespace -> the stat it records time played in, for the network-wide playtime leaderboard
+    #[serde(default)]
+    pub playtime_stats: HashMap<String, String>,
+    // network-wide stat -> namespace -> the stat the namespace records it as, so that minigames
+    // naming the same thing differently (`kills` and `eliminations`) can be ranked together.
+    // queries on a single namespace always use the names it actually uploads
+    #[serde(default)]
+    pub stat_aliases: HashMap<String, HashMap<String, String>>,
+    // namespace -> the stat a player's standing in it is judged by, like wins
+    #[serde(default)]
+    pub headline_stats: HashMap<String, String>,
+    // namespace -> stat -> how the stat should be presented
+    #[serde(default)]
+    pub stat_metadata: HashMap<String, HashMap<String, StatMetadataConfig>>,
+    // how many players' stats are sent at a time when streaming a whole namespace
+    #[serde(default = "default_stream_batch_size")]
+    pub stream_batch_size: usize,
+    // secret mixed into the pseudonyms that replace player uuids in anonymized exports. changing
+    // it means players no longer match up with earlier exports
+    #[serde(default)]
+    pub export_anonymization_salt: Option<String>,
+    // read queries running longer than this are stopped by the database
+    #[serde(default = "default_query_timeout_seconds")]
+    pub query_timeout_seconds: u64,
+    // database operations taking longer than this are logged as warnings
+    #[serde(default = "default_slow_operation_threshold_ms")]
+    pub slow_operation_threshold_ms: u64,
+    // connections kept open to the database, and the most that may be open at once. these
+    // override pool_min and pool_max from the database url, which otherwise default to 10 and 20
+    #[serde(default)]
+    pub min_pool_size: Option<usize>,
+    #[serde(default)]
+    pub max_pool_size: Option<usize>,
+    // how often each namespace may upload bundles, unless it has its own limit below
+    #[serde(default)]
+    pub upload_rate_limit: Option<RateLimitConfig>,
+    #[serde(default)]
+    pub namespace_upload_rate_limits: HashMap<String, RateLimitConfig>,
+    // bundles with more players or more stats for a single player than this are assumed to be
+    // broken and rejected outright
+    #[serde(default = "default_max_players_per_bundle")]
+    pub max_players_per_bundle: usize,
+    #[serde(default = "default_max_stats_per_player")]
+    pub max_stats_per_player: usize,
+    // namespace -> how many of each player's stats in it are read back, largest first. the rest
+    // are still stored but left out of the player's stats and exports. meant for namespaces with
+    // a stat per opponent or the like, where only the biggest matter
+    #[serde(default)]
+    pub top_stats_per_player: HashMap<String, usize>,
+    // an external service to tell about rejected stats, milestones and processed bundles
+    #[serde(default)]
+    pub webhook: Option<WebhookConfig>,
+    // where to look up the usernames of players that have stats but no profile
+    #[serde(default)]
+    pub username_resolver: Option<UsernameResolverConfig>,
+    // how long a resolved username is reused for before it's looked up again
+    #[serde(default = "default_name_cache_ttl_seconds")]
+    pub name_cache_ttl_seconds: u64,
+    // leaderboards that are recomputed in the background rather than on every request
+    #[serde(default)]
+    pub leaderboard_snapshots: Vec<LeaderboardSnapshotConfig>,
+    #[serde(default = "default_leaderboard_refresh_interval_seconds")]
+    pub leaderboard_refresh_interval_seconds: u64,
+    // namespace -> stats for which a player's best single game is tracked
+    #[serde(default)]
+    pub personal_best_stats: HashMap<String, HashSet<String>>,
+    // namespace -> how its players' stats shrink while they're away. nothing decays unless listed
+    #[serde(default)]
+    pub stat_decay: HashMap<String, StatDecayConfig>,
+    #[serde(default = "default_stat_decay_interval_seconds")]
+    pub stat_decay_interval_seconds: u64,
+    // namespace -> its past seasons, which are the stats from games played between their dates
+    #[serde(default)]
+    pub seasons: HashMap<String, Vec<SeasonConfig>>,
+    // reuses recent leaderboard and player stats results. nothing is cached unless set
+    #[serde(default)]
+    pub result_cache: Option<ResultCacheConfig>,
+    // how many messages can be waiting for the statistics database at once. uploads arriving
+    // while it's full are turned away for the server to retry later, rather than queueing
+    // without limit and using ever more memory. unset means no limit, so nothing is ever turned
+    // away however far behind the database falls
+    #[serde(default)]
+    pub mailbox_capacity: Option<usize>,
+}
+
+impl StatisticsConfig {
+    /// Catches settings that would otherwise only fail once they're used, long after startup, or
+    /// never fail at all and quietly do nothing.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.database_url.trim().is_empty() {
+            return Err("database_url is empty".to_owned());
+        }
+        if let Err(e) = self.database_url.parse::<Options>() {
+            return Err(format!("database_url couldn't be parsed: {}", e));
+        }
+        if let Some(read_database_url) = &self.read_database_url {
+            if read_database_url.trim().is_empty() {
+                return Err("read_database_url is empty; leave it out to read from database_url".to_owned());
+            }
+            if let Err(e) = read_database_url.parse::<Options>() {
+                return Err(format!("read_database_url couldn't be parsed: {}", e));
+            }
+        }
+        if self.database_name.trim().is_empty() {
+            return Err("database_name is empty".to_owned());
+        }
+
+        if let (Some(min), Some(max)) = (self.min_pool_size, self.max_pool_size) {
+            if min > max {
+                return Err(format!("min_pool_size ({}) is larger than max_pool_size ({})", min, max));
+            }
+        }
+        if self.max_pool_size == Some(0) {
+            return Err("max_pool_size must be at least 1".to_owned());
+        }
+
+        let limits = [
+            ("query_timeout_seconds", self.query_timeout_seconds as usize),
+            ("stream_batch_size", self.stream_batch_size),
+            ("max_players_per_bundle", self.max_players_per_bundle),
+            ("max_stats_per_player", self.max_stats_per_player),
+            ("leaderboard_refresh_interval_seconds", self.leaderboard_refresh_interval_seconds as usize),
+        ];
+        for (name, value) in &limits {
+            if *value == 0 {
+                return Err(format!("{} must be at least 1", name));
+            }
+        }
+
+        for (namespace, keep) in &self.top_stats_per_player {
+            if *keep == 0 {
+                return Err(format!("top_stats_per_player for {} must keep at least 1 stat", namespace));
+            }
+        }
+
+        if let Some(limit) = &self.upload_rate_limit {
+            limit.validate("upload_rate_limit")?;
+        }
+        for (namespace, limit) in &self.namespace_upload_rate_limits {
+            limit.validate(&format!("namespace_upload_rate_limits.{}", namespace))?;
+        }
+
+        for (namespace, milestones) in &self.milestones {
+            if milestones.values().flatten().any(|milestone| !milestone.is_finite()) {
+                return Err(format!("milestones for {} must be finite numbers", namespace));
+            }
+        }
+
+        let mut achievement_ids = HashSet::new();
+        for achievement in &self.achievements {
+            if !achievement_ids.insert(&achievement.id) {
+                return Err(format!("there is more than one achievement with the id '{}'", achievement.id));
+            }
+            if !achievement.threshold.is_finite() {
+                return Err(format!("the threshold of achievement '{}' must be a finite number", achievement.id));
+            }
+        }
+
+        for (stat, aliases) in &self.stat_aliases {
+            if aliases.is_empty() {
+                return Err(format!("the network-wide stat '{}' isn't recorded by any namespace", stat));
+            }
+        }
+
+        for (namespace, decay) in &self.stat_decay {
+            if decay.stats.is_empty() {
+                return Err(format!("stat_decay for {} has no stats to decay", namespace));
+            }
+            if !(0.0..1.0).contains(&decay.factor) {
+                return Err(format!("the stat_decay factor for {} must be at least 0 and less than 1", namespace));
+            }
+        }
+
+        for (namespace, seasons) in &self.seasons {
+            let mut names = HashSet::new();
+            for season in seasons {
+                if season.start >= season.end {
+                    return Err(format!("season '{}' of {} ends before it starts", season.name, namespace));
+                }
+                if season.name == CURRENT_SEASON {
+                    return Err(format!("seasons can't be named '{}', which means the stats as they are now", CURRENT_SEASON));
+                }
+                if !names.insert(&season.name) {
+                    return Err(format!("there is more than one season of {} named '{}'", namespace, season.name));
+                }
+            }
+        }
+
+        if let Some(webhook) = &self.webhook {
+            if webhook.url.trim().is_empty() {
+                return Err("the webhook url is empty".to_owned());
+            }
+        }
+        if let Some(resolver) = &self.username_resolver {
+            if !resolver.url.contains("{uuid}") {
+                return Err("the username resolver url must contain {uuid}".to_owned());
+            }
+            if resolver.batch_size == 0 {
+                return Err("the username resolver batch_size must be at least 1".to_owned());
+            }
+        }
+        if self.mailbox_capacity == Some(0) {
+            return Err("mailbox_capacity must be at least 1; leave it out for no limit".to_owned());
+        }
+        if self.applied_bundle_retention_days == 0 {
+            return Err("applied_bundle_retention_days must be at least 1".to_owned());
+        }
+        if let Some(cache) = &self.result_cache {
+            if cache.ttl_seconds == 0 {
+                return Err("the result cache ttl_seconds must be at least 1; leave result_cache out to disable it".to_owned());
+            }
+            if cache.capacity == 0 {
+                return Err("the result cache capacity must be at least 1; leave result_cache out to disable it".to_owned());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ResultCacheConfig {
+    pub ttl_seconds: u64,
+    // how many results each kind of query keeps
+    #[serde(default = "default_result_cache_capacity")]
+    pub capacity: usize,
+}
+
+fn default_result_cache_capacity() -> usize {
+    1000
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SeasonConfig {
+    pub name: String,
+    pub start: DateTime<Utc>,
+    // exclusive
+    pub end: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StatDecayConfig {
+    // only total stats can decay
+    pub stats: Vec<String>,
+    // the fraction of each total that's kept every time decay runs, like 0.95
+    pub factor: f64,
+    // players last seen longer ago than this have their stats decayed
+    pub inactive_days: u32,
+}
+
+fn default_stat_decay_interval_seconds() -> u64 {
+    24 * 60 * 60
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LeaderboardSnapshotConfig {
+    pub namespace: String,
+    pub stat: String,
+}
+
+fn default_leaderboard_refresh_interval_seconds() -> u64 {
+    5 * 60
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WebhookConfig {
+    pub url: String,
+    // when set, each request is signed with an HMAC-SHA256 of its body in X-Nucleoid-Signature
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default = "default_webhook_timeout_seconds")]
+    pub timeout_seconds: u64,
+    // how many more times to try sending an event after the first attempt fails
+    #[serde(default = "default_webhook_retries")]
+    pub retries: u32,
+}
+
+fn default_webhook_timeout_seconds() -> u64 {
+    5
+}
+
+fn default_webhook_retries() -> u32 {
+    3
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UsernameResolverConfig {
+    // {uuid} is replaced with the player's uuid, without dashes
+    #[serde(default = "default_username_resolver_url")]
+    pub url: String,
+    // how many players to look up each time usernames are backfilled
+    #[serde(default = "default_username_resolver_batch_size")]
+    pub batch_size: u32,
+    // the delay between lookups, to stay under the service's rate limit
+    #[serde(default = "default_username_resolver_interval_ms")]
+    pub request_interval_ms: u64,
+    // how long to wait before looking up a player the resolver didn't know or failed on again
+    #[serde(default = "default_username_resolver_retry_hours")]
+    pub retry_failed_after_hours: u64,
+}
+
+fn default_username_resolver_url() -> String {
+    "https://sessionserver.mojang.com/session/minecraft/profile/{uuid}".to_owned()
+}
+
+fn default_username_resolver_batch_size() -> u32 {
+    50
+}
+
+fn default_username_resolver_interval_ms() -> u64 {
+    1000
+}
+
+fn default_username_resolver_retry_hours() -> u64 {
+    24
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RateLimitConfig {
+    pub per_minute: f64,
+    // how many can be sent at once before the per minute limit applies
+    pub burst: u32,
+}
+
+impl RateLimitConfig {
+    fn validate(&self, name: &str) -> Result<(), String> {
+        if !self.per_minute.is_finite() || self.per_minute <= 0.0 {
+            return Err(format!("{}.per_minute must be a positive number", name));
+        }
+        if self.burst == 0 {
+            return Err(format!("{}.burst must be at least 1", name));
+        }
+        Ok(())
+    }
+}
+
+fn default_query_timeout_seconds() -> u64 {
+    60
+}
+
+fn default_slow_operation_threshold_ms() -> u64 {
+    5000
+}
+
+fn default_max_players_per_bundle() -> usize {
+    1000
+}
+
+fn default_max_stats_per_player() -> usize {
+    1000
+}
+
+fn default_name_cache_ttl_seconds() -> u64 {
+    10 * 60
+}
+
+fn default_stream_batch_size() -> usize {
+    1000
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct StatMetadataConfig {
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub unit: StatUnit,
+    #[serde(default)]
+    pub higher_is_better: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StatUnit {
+    Count,
+    Duration,
+    Distance,
+    Currency,
+    Percentage,
+}
+
+impl Default for StatUnit {
+    fn default() -> Self {
+        StatUnit::Count
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AchievementConfig {
+    // stored alongside unlocks, so this shouldn't change once players have started unlocking it
+    pub id: String,
+    pub name: String,
+    pub namespace: String,
+    pub stat: String,
+    // unlocked once a player's total for the stat reaches this
+    pub threshold: f64,
+}
+
+fn default_rejected_stats_retention_days() -> Option<u32> {
+    Some(90)
+}
+
+fn default_applied_bundle_retention_days() -> u32 {
+    7
 }
 
 pub(super) fn load() -> Config {
     let path = Path::new("config.json");
     if path.exists() {
         let mut file = File::open(path).expect("failed to open config");
-        serde_json::from_reader(&mut file).expect("failed to parse config")
+        let mut config: Config = serde_json::from_reader(&mut file).expect("failed to parse config");
+        apply_env_overrides(&mut config);
+        config
     } else {
         let config = Config::default();
 
@@ -79,3 +593,85 @@ pub(super) fn load() -> Config {
         config
     }
 }
+
+// Lets tests and one-off deployments point at another database without editing the config file.
+fn apply_env_overrides(config: &mut Config) {
+    if let Some(statistics) = &mut config.statistics {
+        if let Ok(database_url) = env::var("NUCLEOID_STATISTICS_DATABASE_URL") {
+            statistics.database_url = database_url;
+        }
+        if let Ok(database_name) = env::var("NUCLEOID_STATISTICS_DATABASE_NAME") {
+            statistics.database_name = database_name;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statistics_config() -> StatisticsConfig {
+        serde_json::from_value(serde_json::json!({
+            "database_url": "tcp://localhost:9000",
+            "database_name": "nucleoid_statistics",
+        })).unwrap()
+    }
+
+    #[test]
+    fn accepts_a_minimal_statistics_config() {
+        assert_eq!(statistics_config().validate(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_an_empty_database_name() {
+        let mut config = statistics_config();
+        config.database_name = " ".to_owned();
+        assert_eq!(config.validate(), Err("database_name is empty".to_owned()));
+    }
+
+    #[test]
+    fn rejects_an_empty_database_url() {
+        let mut config = statistics_config();
+        config.database_url = String::new();
+        assert_eq!(config.validate(), Err("database_url is empty".to_owned()));
+    }
+
+    #[test]
+    fn rejects_a_database_url_that_cant_be_parsed() {
+        let mut config = statistics_config();
+        config.database_url = "not a url".to_owned();
+        assert!(config.validate().unwrap_err().starts_with("database_url couldn't be parsed"));
+    }
+
+    #[test]
+    fn rejects_a_read_database_url_that_cant_be_parsed() {
+        let mut config = statistics_config();
+        config.read_database_url = Some("not a url".to_owned());
+        assert!(config.validate().unwrap_err().starts_with("read_database_url couldn't be parsed"));
+
+        config.read_database_url = Some(String::new());
+        assert!(config.validate().unwrap_err().starts_with("read_database_url is empty"));
+    }
+
+    #[test]
+    fn applied_bundles_are_remembered_for_a_week_by_default() {
+        assert_eq!(statistics_config().applied_bundle_retention_days, 7);
+
+        let mut config = statistics_config();
+        config.applied_bundle_retention_days = 0;
+        assert_eq!(config.validate(), Err("applied_bundle_retention_days must be at least 1".to_owned()));
+    }
+
+    #[test]
+    fn rejects_a_result_cache_that_would_never_hit() {
+        let mut config = statistics_config();
+        config.result_cache = Some(ResultCacheConfig { ttl_seconds: 0, capacity: 10 });
+        assert!(config.validate().unwrap_err().starts_with("the result cache ttl_seconds must be at least 1"));
+
+        config.result_cache = Some(ResultCacheConfig { ttl_seconds: 60, capacity: 0 });
+        assert!(config.validate().unwrap_err().starts_with("the result cache capacity must be at least 1"));
+
+        config.result_cache = Some(ResultCacheConfig { ttl_seconds: 60, capacity: 10 });
+        assert_eq!(config.validate(), Ok(()));
+    }
+}
@@ -1,7 +1,8 @@
 use std::collections::HashMap;
-use std::time::SystemTime;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use async_trait::async_trait;
+use uuid::Uuid;
 use xtra::prelude::*;
 
 use crate::Config;
@@ -9,7 +10,8 @@ use crate::database::{self, DatabaseClient};
 use crate::discord::{self, DiscordClient, ReportError};
 use crate::integrations::{self, IntegrationsClient};
 use crate::model::*;
-use crate::statistics::database::{StatisticDatabaseController, UploadStatsBundle};
+use crate::statistics::database::{StatisticDatabaseController, SubscribeStatChanges, UpdatePlayerProfiles, UploadStatsBundle, UploadStatsBundles};
+use crate::statistics::model::{BundleUploadReport, GameStatsUpload};
 
 // TODO: use numerical channel ids internally?
 pub struct Controller {
@@ -19,6 +21,15 @@ pub struct Controller {
     statistics: Option<Address<StatisticDatabaseController>>,
     integration_clients: HashMap<String, Address<IntegrationsClient>>,
     status_by_channel: HashMap<String, ServerStatus>,
+    status_updated_at: HashMap<String, SystemTime>,
+    last_performance: HashMap<String, (ServerPerformance, SystemTime)>,
+    memory_pressure: HashMap<String, MemoryPressure>,
+}
+
+#[derive(Default)]
+struct MemoryPressure {
+    samples: u32,
+    alerted: bool,
 }
 
 impl Controller {
@@ -30,7 +41,68 @@ impl Controller {
             statistics: None,
             integration_clients: HashMap::new(),
             status_by_channel: HashMap::new(),
+            status_updated_at: HashMap::new(),
+            last_performance: HashMap::new(),
+            memory_pressure: HashMap::new(),
+        }
+    }
+
+    async fn report_error(&self, title: String, description: String, fields: Option<HashMap<String, String>>) {
+        if let Some(discord) = &self.discord {
+            let _ = discord.do_send_async(ReportError { title, description, fields }).await;
+        }
+    }
+
+    fn online_player_name(&self, channel: &str, player: Uuid) -> String {
+        // The player is most likely still online, so use their name from the server's status if we can.
+        self.status_by_channel.get(channel)
+            .and_then(|status| {
+                status.players.iter()
+                    .find(|online| Uuid::parse_str(&online.id).ok() == Some(player))
+                    .map(|online| online.name.clone())
+            })
+            .unwrap_or_else(|| player.to_string())
+    }
+
+    async fn check_memory_pressure(&mut self, channel: &str, performance: &ServerPerformance) {
+        let alerts = match &self.config.performance_alerts {
+            Some(alerts) => alerts,
+            None => return,
+        };
+
+        if performance.total_memory == 0 {
+            return;
+        }
+
+        let usage = performance.used_memory as f64 / performance.total_memory as f64;
+
+        let pressure = self.memory_pressure.entry(channel.to_owned()).or_default();
+        if usage <= alerts.memory_threshold as f64 {
+            // Back under the threshold, so the next time it's crossed deserves a new alert.
+            *pressure = MemoryPressure::default();
+            return;
+        }
+
+        pressure.samples += 1;
+        if pressure.alerted || pressure.samples < alerts.memory_sustained_samples {
+            return;
         }
+        pressure.alerted = true;
+
+        let mut fields = HashMap::new();
+        fields.insert("Used memory".to_owned(), format!("{}MB", performance.used_memory / (1024 * 1024)));
+        fields.insert("Total memory".to_owned(), format!("{}MB", performance.total_memory / (1024 * 1024)));
+        fields.insert("Dimensions".to_owned(), performance.dimensions.to_string());
+        fields.insert("Chunks".to_owned(), performance.chunks.to_string());
+        fields.insert("Entities".to_owned(), performance.entities.to_string());
+        fields.insert("TPS".to_owned(), performance.tps.to_string());
+
+        let title = format!("High memory usage on {}", channel);
+        let description = format!(
+            "{} has been using {:.0}% of its memory for the last {} samples",
+            channel, usage * 100.0, pressure.samples,
+        );
+        self.report_error(title, description, Some(fields)).await;
     }
 }
 
@@ -178,10 +250,39 @@ impl Message for ServerSystemMessage {
     type Result = ();
 }
 
+pub struct MilestoneReached {
+    pub channel: String,
+    pub player: Uuid,
+    pub namespace: String,
+    pub stat: String,
+    pub milestone: f64,
+}
+
+impl Message for MilestoneReached {
+    type Result = ();
+}
+
+pub struct AchievementUnlocked {
+    pub channel: String,
+    pub player: Uuid,
+    pub achievement: String,
+}
+
+impl Message for AchievementUnlocked {
+    type Result = ();
+}
+
 pub struct GetStatus(pub String);
 
 impl Message for GetStatus {
-    type Result = Option<ServerStatus>;
+    type Result = Option<CachedStatus>;
+}
+
+pub struct CachedStatus {
+    pub status: ServerStatus,
+    pub updated_at: SystemTime,
+    // Whether the server is still connected and so still sending us status updates.
+    pub connected: bool,
 }
 
 pub struct BackendError {
@@ -199,6 +300,8 @@ impl Handler<RegisterIntegrationsClient> for Controller {
     async fn handle(&mut self, message: RegisterIntegrationsClient, _ctx: &mut Context<Self>) {
         self.integration_clients.insert(message.channel.clone(), message.client);
 
+        self.status_updated_at.insert(message.channel.clone(), SystemTime::now());
+
         let status = self.status_by_channel.entry(message.channel)
             .or_insert_with(|| ServerStatus::default());
         status.game_version = message.game_version;
@@ -304,6 +407,8 @@ impl Handler<OutgoingServerChange> for Controller {
 #[async_trait]
 impl Handler<StatusUpdate> for Controller {
     async fn handle(&mut self, message: StatusUpdate, _ctx: &mut Context<Self>) {
+        self.status_updated_at.insert(message.channel.clone(), SystemTime::now());
+
         let status = self.status_by_channel.entry(message.channel.clone())
             .or_insert_with(|| ServerStatus::default());
 
@@ -326,6 +431,20 @@ impl Handler<StatusUpdate> for Controller {
             }).await;
         }
 
+        // Profiles are written again with the next status update, so one can be skipped rather
+        // than hold everything else up.
+        match &self.statistics {
+            Some(statistics) if statistics_busy(statistics) => {
+                log::warn!("Skipping player profiles from '{}': {}", message.channel, STATISTICS_BUSY);
+            }
+            Some(statistics) => {
+                let _ = statistics.do_send_async(UpdatePlayerProfiles {
+                    players: status.players.clone(),
+                }).await;
+            }
+            None => {}
+        }
+
         if let Some(database) = &self.database {
             let _ = database.do_send_async(database::WriteStatus {
                 channel: message.channel.clone(),
@@ -339,6 +458,21 @@ impl Handler<StatusUpdate> for Controller {
 #[async_trait]
 impl Handler<PerformanceUpdate> for Controller {
     async fn handle(&mut self, message: PerformanceUpdate, _ctx: &mut Context<Self>) {
+        let now = SystemTime::now();
+
+        if let Some((previous, previous_time)) = self.last_performance.get(&message.channel) {
+            let delta = message.performance.delta_since(previous);
+            let elapsed = now.duration_since(*previous_time).unwrap_or_default();
+            log::debug!(
+                "[{}] over the last {}s: {:+} entities, {:+} chunks, {:+} dimensions, {:+}MB used memory, {:+.2}ms average tick",
+                message.channel, elapsed.as_secs(), delta.entities, delta.chunks, delta.dimensions,
+                delta.used_memory / (1024 * 1024), delta.average_tick_ms,
+            );
+        }
+        self.last_performance.insert(message.channel.clone(), (message.performance.clone(), now));
+
+        self.check_memory_pressure(&message.channel, &message.performance).await;
+
         if let Some(database) = &self.database {
             let _ = database.do_send_async(database::WritePerformance {
                 channel: message.channel,
@@ -382,6 +516,9 @@ impl Handler<ServerLifecycleStop> for Controller {
     async fn handle(&mut self, message: ServerLifecycleStop, _ctx: &mut Context<Self>) {
         println!("[{}] stopped", message.channel);
         self.status_by_channel.remove(&message.channel);
+        self.status_updated_at.remove(&message.channel);
+        self.last_performance.remove(&message.channel);
+        self.memory_pressure.remove(&message.channel);
 
         if let Some(discord) = &self.discord {
             let content = if message.crash {
@@ -419,30 +556,202 @@ impl Handler<ServerSystemMessage> for Controller {
 }
 
 #[async_trait]
-impl Handler<GetStatus> for Controller {
-    async fn handle(&mut self, message: GetStatus, _ctx: &mut Context<Self>) -> Option<ServerStatus> {
-        self.status_by_channel.get(&message.0).cloned()
+impl Handler<MilestoneReached> for Controller {
+    async fn handle(&mut self, message: MilestoneReached, _ctx: &mut Context<Self>) {
+        let name = self.online_player_name(&message.channel, message.player);
+
+        println!("[{}] {} reached {} {} in {}", message.channel, name, message.milestone, message.stat, message.namespace);
+
+        if let Some(discord) = &self.discord {
+            let _ = discord.do_send_async(discord::SendSystem {
+                channel: message.channel,
+                content: format!("{} has reached {} {} in {}!", name, message.milestone, message.stat, message.namespace),
+            }).await;
+        }
     }
 }
 
 #[async_trait]
-impl Handler<BackendError> for Controller {
-    async fn handle(&mut self, message: BackendError, _ctx: &mut Context<Self>) {
+impl Handler<AchievementUnlocked> for Controller {
+    async fn handle(&mut self, message: AchievementUnlocked, _ctx: &mut Context<Self>) {
+        let name = self.online_player_name(&message.channel, message.player);
+
+        println!("[{}] {} unlocked {}", message.channel, name, message.achievement);
+
         if let Some(discord) = &self.discord {
-            let _ = discord.do_send_async(ReportError {
-                title: message.title,
-                description: message.description,
-                fields: message.fields,
+            let _ = discord.do_send_async(discord::SendSystem {
+                channel: message.channel,
+                content: format!("{} has unlocked the achievement {}!", name, message.achievement),
             }).await;
         }
     }
 }
 
+#[async_trait]
+impl Handler<GetStatus> for Controller {
+    async fn handle(&mut self, message: GetStatus, _ctx: &mut Context<Self>) -> Option<CachedStatus> {
+        let status = self.status_by_channel.get(&message.0)?;
+        let updated_at = self.status_updated_at.get(&message.0).copied().unwrap_or(UNIX_EPOCH);
+
+        Some(CachedStatus {
+            status: status.clone(),
+            updated_at,
+            connected: self.integration_clients.contains_key(&message.0),
+        })
+    }
+}
+
+#[async_trait]
+impl Handler<BackendError> for Controller {
+    async fn handle(&mut self, message: BackendError, _ctx: &mut Context<Self>) {
+        self.report_error(message.title, message.description, message.fields).await;
+    }
+}
+
+// Waiting for room in a full statistics mailbox would hold up everything else the controller
+// does, so uploads are turned away instead.
+fn statistics_busy<A: Actor>(statistics: &Address<A>) -> bool {
+    mailbox_full(statistics.len(), statistics.capacity())
+}
+
+// A mailbox without a capacity is never full.
+fn mailbox_full(len: usize, capacity: Option<usize>) -> bool {
+    capacity.map_or(false, |capacity| len >= capacity)
+}
+
+// Every bundle in a batch gets its own report, even when none of them could be looked at.
+fn report_each(uploads: &[GameStatsUpload], report: fn(Uuid, String) -> BundleUploadReport, error: &str) -> Vec<BundleUploadReport> {
+    uploads.iter()
+        .map(|upload| report(upload.game_id, error.to_owned()))
+        .collect()
+}
+
+// Reports for a batch turned away because the statistics database is busy, if it is.
+fn turn_away_if_busy<A: Actor>(statistics: &Address<A>, uploads: &[GameStatsUpload]) -> Option<Vec<BundleUploadReport>> {
+    if statistics_busy(statistics) {
+        Some(report_each(uploads, BundleUploadReport::retry_later, STATISTICS_BUSY))
+    } else {
+        None
+    }
+}
+
+const STATISTICS_BUSY: &str = "the statistics database is busy";
+const STATISTICS_DISABLED: &str = "statistics are not enabled";
+
 #[async_trait]
 impl Handler<UploadStatsBundle> for Controller {
     async fn handle(&mut self, message: UploadStatsBundle, _ctx: &mut Context<Self>) -> <UploadStatsBundle as Message>::Result {
-        if let Some(statistics) = &self.statistics {
-            statistics.send(message).await.expect("statistics controller disconnected")
+        match &self.statistics {
+            Some(statistics) if statistics_busy(statistics) => {
+                log::warn!("Turning away stats bundle {} from '{}': {}", message.game_id, message.server, STATISTICS_BUSY);
+                BundleUploadReport::retry_later(message.game_id, STATISTICS_BUSY.to_owned())
+            }
+            Some(statistics) => statistics.send(message).await.expect("statistics controller disconnected"),
+            None => BundleUploadReport::failed(message.game_id, STATISTICS_DISABLED.to_owned()),
+        }
+    }
+}
+
+#[async_trait]
+impl Handler<UploadStatsBundles> for Controller {
+    async fn handle(&mut self, message: UploadStatsBundles, _ctx: &mut Context<Self>) -> <UploadStatsBundles as Message>::Result {
+        match &self.statistics {
+            Some(statistics) => match turn_away_if_busy(statistics, &message.uploads) {
+                Some(reports) => {
+                    log::warn!("Turning away {} stats bundles from '{}': {}", message.uploads.len(), message.server, STATISTICS_BUSY);
+                    reports
+                }
+                None => statistics.send(message).await.expect("statistics controller disconnected"),
+            },
+            None => report_each(&message.uploads, BundleUploadReport::failed, STATISTICS_DISABLED),
+        }
+    }
+}
+
+#[async_trait]
+impl Handler<SubscribeStatChanges> for Controller {
+    async fn handle(&mut self, message: SubscribeStatChanges, _ctx: &mut Context<Self>) -> <SubscribeStatChanges as Message>::Result {
+        match &self.statistics {
+            Some(statistics) => statistics.send(message).await.ok().flatten(),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::statistics::model::UploadErrorKind;
+
+    use super::*;
+
+    fn uploads(count: usize) -> Vec<GameStatsUpload> {
+        (0..count)
+            .map(|_| GameStatsUpload {
+                game_id: Uuid::new_v4(),
+                bundle: serde_json::from_value(serde_json::json!({
+                    "namespace": "bedwars",
+                    "stats": { "global": null, "players": {} },
+                })).unwrap(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bounded_mailboxes_are_full_at_their_capacity() {
+        assert!(!mailbox_full(0, Some(2)));
+        assert!(!mailbox_full(1, Some(2)));
+        assert!(mailbox_full(2, Some(2)));
+        assert!(mailbox_full(3, Some(2)));
+        assert!(!mailbox_full(usize::MAX, None));
+    }
+
+    #[test]
+    fn every_turned_away_bundle_is_reported() {
+        let uploads = uploads(3);
+
+        let reports = report_each(&uploads, BundleUploadReport::failed, STATISTICS_DISABLED);
+        assert_eq!(reports.len(), 3);
+        for (report, upload) in reports.iter().zip(&uploads) {
+            assert_eq!(report.game_id, upload.game_id);
+            assert_eq!(report.error.as_deref(), Some(STATISTICS_DISABLED));
+            assert_eq!(report.error_kind, None);
+        }
+
+        let reports = report_each(&uploads, BundleUploadReport::retry_later, STATISTICS_BUSY);
+        assert!(reports.iter().all(|report| report.error_kind == Some(UploadErrorKind::Retryable)));
+    }
+
+    struct Idle;
+
+    impl Actor for Idle {}
+
+    struct Ping;
+
+    impl Message for Ping {
+        type Result = ();
+    }
+
+    #[async_trait]
+    impl Handler<Ping> for Idle {
+        async fn handle(&mut self, _message: Ping, _ctx: &mut Context<Self>) {}
+    }
+
+    #[test]
+    fn uploads_are_turned_away_from_a_full_mailbox() {
+        // never spawned, so nothing is ever taken out of the mailbox
+        let manager = Idle.create(Some(2));
+        let address = manager.address.clone();
+        let uploads = uploads(2);
+
+        assert!(!statistics_busy(&address));
+        assert!(turn_away_if_busy(&address, &uploads).is_none());
+
+        for _ in 0..2 {
+            address.do_send(Ping).unwrap();
         }
+        assert!(statistics_busy(&address));
+        let reports = turn_away_if_busy(&address, &uploads).unwrap();
+        assert_eq!(reports.len(), 2);
+        assert!(reports.iter().all(|report| report.error_kind == Some(UploadErrorKind::Retryable)));
     }
 }
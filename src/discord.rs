@@ -148,7 +148,14 @@ pub async fn run(controller: Address<Controller>, config: DiscordConfig) {
     };
     let address = actor.create(None).spawn(&mut TokioGlobal);
 
-    let handler = DiscordHandler { controller: controller.clone(), discord: address.clone() };
+    let handler = DiscordHandler {
+        controller: controller.clone(),
+        discord: address.clone(),
+        attachment_hosts: config.attachment_hosts.clone(),
+        max_reply_depth: config.max_reply_depth,
+        reply_quote_length: config.reply_quote_length,
+        name_color_as_hex: config.name_color_as_hex,
+    };
 
     let mut client = Client::builder(config.token)
         .event_handler(handler)
@@ -249,11 +256,19 @@ impl Handler<SendChat> for DiscordClient {
             let relay_store = data.get::<RelayStoreKey>().unwrap();
             if let Some(relay) = relay_store.channel_to_relay.get(&send_chat.channel) {
                 let avatar_url = &self.config.player_avatar_url;
+                let formatting = if self.config.relay_formatting_as_markdown {
+                    RelayedFormatting::Markdown
+                } else if self.config.relay_strip_formatting {
+                    RelayedFormatting::Strip
+                } else {
+                    RelayedFormatting::Keep
+                };
+                let content = sanitize_relayed_content(&send_chat.content, self.config.relay_max_length, formatting);
 
                 let result = relay.webhook.execute(&cache_and_http.http, false, move |webhook| {
                     let mut webhook = webhook
                         .username(send_chat.sender.name)
-                        .content(send_chat.content);
+                        .content(content);
 
                     webhook.0.insert("allowed_mentions", json!({"parse": []}));
 
@@ -405,6 +420,10 @@ impl Handler<ReportError> for DiscordClient {
 struct DiscordHandler {
     controller: Address<Controller>,
     discord: Address<DiscordClient>,
+    attachment_hosts: Option<HashSet<String>>,
+    max_reply_depth: usize,
+    reply_quote_length: Option<usize>,
+    name_color_as_hex: bool,
 }
 
 impl DiscordHandler {
@@ -647,6 +666,10 @@ impl DiscordHandler {
             chat.replying_to = Some(Box::new(replying_to));
         }
 
+        match self.reply_quote_length {
+            Some(quote_length) => chat.quote_reply(quote_length),
+            None => chat.truncate_replies(self.max_reply_depth),
+        }
         chat
     }
 
@@ -659,17 +682,31 @@ impl DiscordHandler {
         };
 
         let name_color = self.get_sender_name_color(ctx, message).await;
+        let name_color_hex = if self.name_color_as_hex { name_color } else { None };
 
         let content = self.sanitize_message_content(ctx, message).await;
 
         let attachments = message.attachments.iter()
-            .map(|attachment| ChatAttachment {
-                name: attachment.filename.clone(),
-                url: attachment.url.clone(),
+            .filter_map(|attachment| {
+                let result = ChatAttachment::new(
+                    attachment.filename.clone(),
+                    attachment.url.clone(),
+                    self.attachment_hosts.as_ref(),
+                );
+                match result {
+                    Ok(attachment) => Some(attachment),
+                    Err(reason) => {
+                        warn!("dropping attachment {} ({}): {}", attachment.filename, attachment.url, reason);
+                        None
+                    }
+                }
             })
             .collect();
 
-        ChatMessage { sender, sender_user, content, name_color, attachments, replying_to: None }
+        ChatMessage {
+            sender, sender_user, content, name_color, name_color_hex, attachments,
+            replying_to: None, reply_quote: None,
+        }
     }
 
     async fn sender_name(&self, ctx: &SerenityContext, message: &SerenityMessage) -> String {
@@ -696,20 +733,48 @@ impl DiscordHandler {
         content
     }
 
-    async fn get_sender_name_color(&self, ctx: &SerenityContext, message: &SerenityMessage) -> Option<u32> {
+    async fn get_sender_name_color(&self, ctx: &SerenityContext, message: &SerenityMessage) -> Option<NameColor> {
         if let (Some(member), Some(guild)) = (&message.member, message.guild_id) {
             if let Some(guild) = ctx.cache.guild(guild).await {
                 return member.roles.iter()
                     .filter_map(|id| guild.roles.get(id))
                     .filter(|role| role.colour.0 != 0)
                     .max_by_key(|role| role.position)
-                    .map(|role| role.colour.0);
+                    .map(|role| NameColor::from_rgb(role.colour.0));
             }
         }
         None
     }
 }
 
+/// What happens to `§` formatting codes in chat relayed to Discord.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum RelayedFormatting {
+    Keep,
+    Strip,
+    Markdown,
+}
+
+/// Cleans up chat coming from a game server before it is relayed to Discord: control characters are
+/// dropped, `§` formatting codes are kept, removed or converted to markdown, and the result is cut
+/// to `max_length` characters.
+fn sanitize_relayed_content(content: &str, max_length: usize, formatting: RelayedFormatting) -> String {
+    let content = match formatting {
+        RelayedFormatting::Keep => content.to_owned(),
+        RelayedFormatting::Strip => strip_formatting_codes(content),
+        RelayedFormatting::Markdown => formatting_codes_to_markdown(content),
+    };
+    let sanitized: String = content.chars().filter(|c| !c.is_control()).collect();
+
+    if sanitized.chars().count() > max_length {
+        let mut truncated: String = sanitized.chars().take(max_length.saturating_sub(1)).collect();
+        truncated.push('\u{2026}');
+        truncated
+    } else {
+        sanitized
+    }
+}
+
 #[async_trait]
 impl EventHandler for DiscordHandler {
     async fn message(&self, ctx: SerenityContext, message: SerenityMessage) {
@@ -764,3 +829,52 @@ enum CommandError {
     #[error("You are not allowed to do this!")]
     NotAllowed,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relayed_formatting_can_be_kept_stripped_or_converted() {
+        let content = "\u{a7}lwon\u{a7}r the \u{a7}agame";
+        assert_eq!(sanitize_relayed_content(content, 100, RelayedFormatting::Keep), content);
+        assert_eq!(sanitize_relayed_content(content, 100, RelayedFormatting::Strip), "won the game");
+        assert_eq!(sanitize_relayed_content(content, 100, RelayedFormatting::Markdown), "**won** the game");
+    }
+
+    #[test]
+    fn relayed_chat_is_cut_to_length_after_formatting() {
+        let content = "\u{a7}a\u{a7}bhello\u{7}world";
+        assert_eq!(sanitize_relayed_content(content, 100, RelayedFormatting::Strip), "helloworld");
+        assert_eq!(sanitize_relayed_content(content, 6, RelayedFormatting::Strip), "hello\u{2026}");
+    }
+
+    #[test]
+    fn relayed_chat_at_the_limit_is_not_truncated() {
+        assert_eq!(sanitize_relayed_content("hello", 5, RelayedFormatting::Keep), "hello");
+        assert_eq!(sanitize_relayed_content("hello!", 5, RelayedFormatting::Keep), "hell\u{2026}");
+        assert_eq!(sanitize_relayed_content("hello", 1, RelayedFormatting::Keep), "\u{2026}");
+        assert_eq!(sanitize_relayed_content("", 0, RelayedFormatting::Keep), "");
+    }
+
+    #[test]
+    fn relayed_chat_is_truncated_by_characters_not_bytes() {
+        let content = "\u{e9}\u{e9}\u{e9}\u{e9}";
+        assert_eq!(sanitize_relayed_content(content, 4, RelayedFormatting::Keep), content);
+        assert_eq!(sanitize_relayed_content(content, 3, RelayedFormatting::Keep), "\u{e9}\u{e9}\u{2026}");
+    }
+
+    #[test]
+    fn control_characters_do_not_count_towards_the_limit() {
+        let content = "he\nllo\u{0}\r\t";
+        assert_eq!(sanitize_relayed_content(content, 5, RelayedFormatting::Keep), "hello");
+    }
+
+    #[test]
+    fn stripping_removes_every_formatting_code() {
+        let content = "\u{a7}6\u{a7}l[VIP]\u{a7}r \u{a7}kx\u{a7}r Gegy";
+        assert_eq!(sanitize_relayed_content(content, 100, RelayedFormatting::Strip), "[VIP] x Gegy");
+        // a trailing section sign has no code to remove
+        assert_eq!(sanitize_relayed_content("gg\u{a7}", 100, RelayedFormatting::Strip), "gg");
+    }
+}
@@ -14,8 +14,8 @@ use xtra::prelude::*;
 use crate::{IntegrationsConfig, TokioGlobal};
 use crate::controller::*;
 use crate::model::*;
-use crate::statistics::model::GameStatsBundle;
-use crate::statistics::database::UploadStatsBundle;
+use crate::statistics::model::{BundleUploadReport, GameStatsBundle, GameStatsUpload, UploadErrorKind};
+use crate::statistics::database::{UploadStatsBundle, UploadStatsBundles};
 use uuid::Uuid;
 
 const MAX_FRAME_LENGTH: usize = 4 * 1024 * 1024;
@@ -142,6 +142,16 @@ pub enum IncomingMessage {
     UploadStatistics {
         bundle: GameStatsBundle,
         game_id: Uuid,
+        #[serde(default)]
+        dry_run: bool,
+        #[serde(default)]
+        correlation_id: Option<String>,
+    },
+    #[serde(rename = "upload_statistics_batch")]
+    UploadStatisticsBatch {
+        uploads: Vec<GameStatsUpload>,
+        #[serde(default)]
+        correlation_id: Option<String>,
     },
 }
 
@@ -166,6 +176,12 @@ pub enum OutgoingMessage {
         from_server: String,
         to_server: String,
     },
+    // A stats bundle that couldn't be taken right now and should be sent again after a while.
+    #[serde(rename = "retry_statistics")]
+    RetryStatistics {
+        game_id: Uuid,
+        reason: String,
+    },
 }
 
 impl Message for OutgoingMessage {
@@ -215,7 +231,7 @@ impl Handler<HandleIncomingMessage> for IntegrationsClient {
                         let system_message = ServerSystemMessage { channel: self.channel.clone(), content };
                         self.controller.do_send_async(system_message).await
                     }
-                    UploadStatistics { bundle, game_id } => {
+                    UploadStatistics { bundle, game_id, dry_run, correlation_id } => {
                         if let Some(global) = &bundle.stats.global {
                             log::debug!("server '{}' uploaded {} player statistics and {} global statistics in statistics bundle for {}",
                                 self.channel, bundle.stats.players.len(), global.len(), bundle.namespace);
@@ -227,8 +243,33 @@ impl Handler<HandleIncomingMessage> for IntegrationsClient {
                             game_id,
                             bundle,
                             server: self.channel.clone(),
+                            dry_run,
+                            correlation_id,
                         };
-                        self.controller.do_send_async(upload_bundle_message).await
+                        // Waiting for the report means a server sending bundles faster than they can
+                        // be written is slowed down to match.
+                        match self.controller.send(upload_bundle_message).await {
+                            Ok(report) => {
+                                self.ask_to_retry(&[report]).await;
+                                Ok(())
+                            }
+                            Err(e) => Err(e),
+                        }
+                    }
+                    UploadStatisticsBatch { uploads, correlation_id } => {
+                        log::debug!("server '{}' uploaded a batch of {} statistics bundles", self.channel, uploads.len());
+                        let upload_bundles_message = UploadStatsBundles {
+                            server: self.channel.clone(),
+                            uploads,
+                            correlation_id,
+                        };
+                        match self.controller.send(upload_bundles_message).await {
+                            Ok(reports) => {
+                                self.ask_to_retry(&reports).await;
+                                Ok(())
+                            }
+                            Err(e) => Err(e),
+                        }
                     }
                     _ => {
                         warn!("received unexpected message from integrations client: {:?}", message);
@@ -251,6 +292,20 @@ impl Handler<HandleIncomingMessage> for IntegrationsClient {
     }
 }
 
+impl IntegrationsClient {
+    async fn ask_to_retry(&mut self, reports: &[BundleUploadReport]) {
+        for report in reports {
+            if report.error_kind == Some(UploadErrorKind::Retryable) {
+                let retry = OutgoingMessage::RetryStatistics {
+                    game_id: report.game_id,
+                    reason: report.error.clone().unwrap_or_default(),
+                };
+                let _ = self.sink.send(retry).await;
+            }
+        }
+    }
+}
+
 #[async_trait]
 impl Handler<OutgoingMessage> for IntegrationsClient {
     async fn handle(&mut self, message: OutgoingMessage, _ctx: &mut Context<Self>) {
@@ -278,7 +333,7 @@ fn split_framed(stream: TcpStream) -> (impl Sink<OutgoingMessage, Error = Error>
 
     let stream = stream.map(|result| {
         HandleIncomingMessage(match result {
-            Ok(bytes) => serde_json::from_slice(bytes.as_ref()).map_err(Error::Json),
+            Ok(bytes) => decode_message(bytes.as_ref()),
             Err(err) => Err(err.into()),
         })
     });
@@ -286,6 +341,15 @@ fn split_framed(stream: TcpStream) -> (impl Sink<OutgoingMessage, Error = Error>
     (sink, stream)
 }
 
+// Servers sending a lot of stats can use MessagePack instead of JSON, with structs encoded as maps.
+// A JSON message is always an object, so any frame that doesn't start like one is MessagePack.
+fn decode_message(bytes: &[u8]) -> Result<IncomingMessage> {
+    match bytes.iter().find(|byte| !byte.is_ascii_whitespace()) {
+        Some(b'{') | None => serde_json::from_slice(bytes).map_err(Error::Json),
+        Some(_) => rmp_serde::from_read_ref(bytes).map_err(Error::MessagePack),
+    }
+}
+
 type Result<T> = std::result::Result<T, Error>;
 
 #[derive(thiserror::Error, Debug)]
@@ -294,6 +358,100 @@ enum Error {
     Io(#[from] io::Error),
     #[error("invalid json")]
     Json(#[from] serde_json::Error),
+    #[error("invalid messagepack")]
+    MessagePack(#[from] rmp_serde::decode::Error),
     #[error("missing handshake")]
     MissingHandshake,
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::statistics::model::{Decimal, StatValue, UploadStat};
+
+    use super::*;
+
+    fn upload_statistics() -> serde_json::Value {
+        serde_json::json!({
+            "type": "upload_statistics",
+            "body": {
+                "game_id": "5b0a86e1-8a3b-4a89-9d2c-6c0f3a1a2b3c",
+                "bundle": {
+                    "namespace": "bedwars",
+                    "stats": {
+                        "global": { "games_played": { "type": "int_total", "value": 1 } },
+                        "players": {
+                            "c6c84a95-3bcb-4dd4-b2a6-0b4dd6ebd0a8": {
+                                "kills": { "type": "int_total", "value": 4 },
+                                "fastest_win": { "type": "float_min", "value": 93.5 },
+                                "coins": { "type": "decimal_total", "value": "12.3400" },
+                            },
+                        },
+                    },
+                    "bundle_id": "0e5f5d4e-3cd1-4d7e-a1a4-64b1e5f0a7d2",
+                },
+                "dry_run": true,
+            },
+        })
+    }
+
+    #[test]
+    fn messagepack_messages_decode_like_json() {
+        let json = serde_json::to_vec(&upload_statistics()).unwrap();
+        let msgpack = rmp_serde::to_vec_named(&upload_statistics()).unwrap();
+
+        for bytes in &[json, msgpack] {
+            match decode_message(bytes).unwrap() {
+                IncomingMessage::UploadStatistics { bundle, game_id, dry_run, correlation_id } => {
+                    assert_eq!(game_id.to_string(), "5b0a86e1-8a3b-4a89-9d2c-6c0f3a1a2b3c");
+                    assert!(dry_run);
+                    assert_eq!(correlation_id, None);
+                    assert_eq!(bundle.namespace, "bedwars");
+
+                    let player: Uuid = "c6c84a95-3bcb-4dd4-b2a6-0b4dd6ebd0a8".parse().unwrap();
+                    let stats = &bundle.stats.players[&player];
+                    assert!(matches!(stats["kills"], UploadStat::IntTotal(4)));
+                    assert!(matches!(stats["fastest_win"], UploadStat::FloatMin(v) if v == 93.5));
+                    match &stats["coins"] {
+                        UploadStat::DecimalTotal(coins) => assert_eq!(*coins, "12.34".parse::<Decimal>().unwrap()),
+                        stat => panic!("expected a decimal, got {:?}", stat),
+                    }
+                    assert!(matches!(bundle.stats.global.as_ref().unwrap()["games_played"], UploadStat::IntTotal(1)));
+                }
+                message => panic!("expected an upload, got {:?}", message),
+            }
+        }
+    }
+
+    #[test]
+    fn messagepack_batches_decode() {
+        let upload = upload_statistics()["body"].clone();
+        let batch = serde_json::json!({
+            "type": "upload_statistics_batch",
+            "body": { "uploads": [upload.clone(), upload], "correlation_id": "abc" },
+        });
+
+        let bytes = rmp_serde::to_vec_named(&batch).unwrap();
+        match decode_message(&bytes).unwrap() {
+            IncomingMessage::UploadStatisticsBatch { uploads, correlation_id } => {
+                assert_eq!(uploads.len(), 2);
+                assert_eq!(correlation_id.as_deref(), Some("abc"));
+            }
+            message => panic!("expected a batch, got {:?}", message),
+        }
+    }
+
+    #[test]
+    fn broken_messagepack_is_an_error() {
+        let mut bytes = rmp_serde::to_vec_named(&upload_statistics()).unwrap();
+        bytes.truncate(bytes.len() / 2);
+        assert!(matches!(decode_message(&bytes), Err(Error::MessagePack(_))));
+    }
+
+    #[test]
+    fn stat_values_survive_messagepack() {
+        let values = vec![StatValue::Int(i64::MAX), StatValue::Float(0.1), StatValue::Decimal("-3.5".parse().unwrap())];
+        let bytes = rmp_serde::to_vec_named(&values).unwrap();
+        let decoded: Vec<StatValue> = rmp_serde::from_read_ref(&bytes).unwrap();
+        assert_eq!(decoded, values);
+    }
+}
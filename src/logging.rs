@@ -0,0 +1,18 @@
+use tracing_subscriber::EnvFilter;
+
+use crate::LogFormat;
+
+/// Both formats are filtered by `RUST_LOG`, as env_logger always has been.
+pub fn init(format: LogFormat) {
+    match format {
+        LogFormat::Plain => env_logger::init(),
+        LogFormat::Json => {
+            // This also picks up records from the `log` macros, which most of the backend still uses.
+            tracing_subscriber::fmt()
+                .json()
+                .with_env_filter(EnvFilter::from_default_env())
+                .with_current_span(true)
+                .init();
+        }
+    }
+}
@@ -16,6 +16,7 @@ mod persistent;
 mod config;
 mod database;
 mod statistics;
+mod logging;
 
 pub struct TokioGlobal;
 
@@ -27,9 +28,8 @@ impl Spawner for TokioGlobal {
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
-
     let config = config::load();
+    logging::init(config.log_format);
     let controller = Controller::new(config.clone()).await
         .create(None)
         .spawn(&mut TokioGlobal);
@@ -1,4 +1,8 @@
-use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use reqwest::Url;
+use serde::{Deserialize, Serialize, Serializer};
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct ServerStatus {
@@ -28,16 +32,225 @@ pub struct Game {
     pub player_count: u16,
 }
 
+/// The most replies a relayed chat message can be nested in, whatever is configured.
+pub const MAX_REPLY_DEPTH: usize = 8;
+
 #[derive(Serialize, Debug)]
 pub struct ChatMessage {
     pub sender: String,
     pub sender_user: DiscordUser,
     pub content: String,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub name_color: Option<u32>,
+    pub name_color: Option<NameColor>,
+    // the same color as a `#RRGGBB` string, for integrations that opt into it
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_hex_name_color")]
+    pub name_color_hex: Option<NameColor>,
     pub attachments: Vec<ChatAttachment>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub replying_to: Option<Box<ChatMessage>>,
+    // `sender: content` of the message being replied to, when replies are quoted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_quote: Option<String>,
+}
+
+impl ChatMessage {
+    /// The message content with any Minecraft formatting codes removed.
+    pub fn plain_content(&self) -> String {
+        strip_formatting_codes(&self.content)
+    }
+
+    /// Cuts the reply chain off after `max_depth` replies, replacing anything further with a `…`
+    /// placeholder so that serializing the message can never recurse too deeply. However deep
+    /// `max_depth` is, no more than [`MAX_REPLY_DEPTH`] replies are kept.
+    pub fn truncate_replies(&mut self, max_depth: usize) {
+        let mut current = self;
+        for _ in 0..max_depth.min(MAX_REPLY_DEPTH) {
+            current = match current.replying_to {
+                Some(ref mut replying_to) => &mut **replying_to,
+                None => return,
+            };
+        }
+
+        if let Some(mut truncated) = current.replying_to.take() {
+            // Unlink the rest of the chain one message at a time rather than dropping it recursively.
+            let mut rest = truncated.replying_to.take();
+            while let Some(mut message) = rest {
+                rest = message.replying_to.take();
+            }
+
+            truncated.content = "\u{2026}".to_owned();
+            truncated.attachments.clear();
+            current.replying_to = Some(truncated);
+        }
+    }
+
+    /// Replaces the message being replied to with a plain-text quote of its sender and the start
+    /// of its content, cut to `max_length` characters. Nothing nested is left to be relayed, so
+    /// its mentions and attachments can't be sent on again.
+    pub fn quote_reply(&mut self, max_length: usize) {
+        let mut replying_to = match self.replying_to.take() {
+            Some(replying_to) => replying_to,
+            None => return,
+        };
+
+        let mut rest = replying_to.replying_to.take();
+        while let Some(mut message) = rest {
+            rest = message.replying_to.take();
+        }
+
+        let content = replying_to.plain_content();
+        let content = if content.chars().count() > max_length {
+            let mut truncated: String = content.chars().take(max_length.saturating_sub(1)).collect();
+            truncated.push('\u{2026}');
+            truncated
+        } else {
+            content
+        };
+
+        self.reply_quote = Some(format!("{}: {}", replying_to.sender, content));
+    }
+}
+
+const FORMATTING_CODE_PREFIX: char = '\u{a7}';
+
+/// Removes legacy `§` formatting codes. Unknown codes and a trailing `§` are removed too.
+pub fn strip_formatting_codes(content: &str) -> String {
+    let mut result = String::with_capacity(content.len());
+
+    let mut chars = content.chars();
+    while let Some(c) = chars.next() {
+        if c == FORMATTING_CODE_PREFIX {
+            chars.next();
+        } else {
+            result.push(c);
+        }
+    }
+
+    result
+}
+
+/// Converts the legacy `§` style codes with a sensible Discord equivalent into markdown. Colors and
+/// `§r` end any open styles, as they do in game, and everything else is dropped.
+pub fn formatting_codes_to_markdown(content: &str) -> String {
+    let mut result = String::with_capacity(content.len());
+    let mut open_styles: Vec<&'static str> = Vec::new();
+
+    let mut chars = content.chars();
+    while let Some(c) = chars.next() {
+        if c != FORMATTING_CODE_PREFIX {
+            result.push(c);
+            continue;
+        }
+
+        let code = match chars.next() {
+            Some(code) => code.to_ascii_lowercase(),
+            None => break,
+        };
+
+        match markdown_for_style(code) {
+            Some(style) => {
+                if !open_styles.contains(&style) {
+                    result.push_str(style);
+                    open_styles.push(style);
+                }
+            }
+            None if code == 'r' || code.is_digit(16) => close_styles(&mut result, &mut open_styles),
+            None => (),
+        }
+    }
+
+    close_styles(&mut result, &mut open_styles);
+    result
+}
+
+fn markdown_for_style(code: char) -> Option<&'static str> {
+    match code {
+        'k' => Some("||"),
+        'l' => Some("**"),
+        'm' => Some("~~"),
+        'n' => Some("__"),
+        'o' => Some("*"),
+        _ => None,
+    }
+}
+
+fn close_styles(result: &mut String, open_styles: &mut Vec<&'static str>) {
+    while let Some(style) = open_styles.pop() {
+        result.push_str(style);
+    }
+}
+
+/// A 24-bit RGB color used to render a player's name. This serializes as the plain `u32` value that
+/// integrations already expect; use [`NameColor::serialize_hex`] where a `#RRGGBB` string is wanted.
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct NameColor(u32);
+
+impl NameColor {
+    // the colors behind the `§0`-`§f` formatting codes
+    const MINECRAFT_CODES: [u32; 16] = [
+        0x000000, 0x0000AA, 0x00AA00, 0x00AAAA, 0xAA0000, 0xAA00AA, 0xFFAA00, 0xAAAAAA,
+        0x555555, 0x5555FF, 0x55FF55, 0x55FFFF, 0xFF5555, 0xFF55FF, 0xFFFF55, 0xFFFFFF,
+    ];
+
+    /// Any bits above the 24-bit RGB range (such as an alpha channel) are discarded.
+    pub fn from_rgb(rgb: u32) -> NameColor {
+        NameColor(rgb & 0xFFFFFF)
+    }
+
+    /// Takes `RRGGBB`, with or without a leading `#`.
+    pub fn from_hex(hex: &str) -> Option<NameColor> {
+        let hex = hex.strip_prefix('#').unwrap_or(hex);
+        // from_str_radix alone would also take a sign, like `+12345`
+        if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        u32::from_str_radix(hex, 16).ok().map(NameColor)
+    }
+
+    /// Takes the character after a `§`, like `6` for gold. Formatting codes that aren't colors
+    /// give `None`.
+    pub fn from_minecraft_code(code: char) -> Option<NameColor> {
+        code.to_digit(16).map(|index| NameColor(Self::MINECRAFT_CODES[index as usize]))
+    }
+
+    pub fn rgb(&self) -> u32 {
+        self.0
+    }
+
+    pub fn to_hex(&self) -> String {
+        format!("#{:06X}", self.rgb())
+    }
+
+    pub fn serialize_hex<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
+/// Takes `#RRGGBB` or a `§` color code like `§6`.
+impl FromStr for NameColor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let color = match s.strip_prefix('\u{a7}') {
+            Some(code) => {
+                let mut chars = code.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(code), None) => NameColor::from_minecraft_code(code),
+                    _ => None,
+                }
+            }
+            None => NameColor::from_hex(s),
+        };
+        color.ok_or_else(|| format!("invalid name color: {}", s))
+    }
+}
+
+fn serialize_hex_name_color<S: Serializer>(color: &Option<NameColor>, serializer: S) -> Result<S::Ok, S::Error> {
+    match color {
+        Some(color) => color.serialize_hex(serializer),
+        None => serializer.serialize_none(),
+    }
 }
 
 #[derive(Serialize, Debug)]
@@ -53,6 +266,26 @@ pub struct ChatAttachment {
     pub url: String,
 }
 
+impl ChatAttachment {
+    /// Only accepts well-formed http(s) URLs, optionally restricted to the given hosts.
+    pub fn new(name: String, url: String, allowed_hosts: Option<&HashSet<String>>) -> Result<ChatAttachment, String> {
+        let parsed = Url::parse(&url).map_err(|err| format!("malformed url: {}", err))?;
+
+        if parsed.scheme() != "http" && parsed.scheme() != "https" {
+            return Err(format!("unsupported scheme: {}", parsed.scheme()));
+        }
+
+        let host = parsed.host_str().ok_or_else(|| "missing host".to_owned())?;
+        if let Some(allowed_hosts) = allowed_hosts {
+            if !allowed_hosts.contains(&host.to_ascii_lowercase()) {
+                return Err(format!("host not allowed: {}", host));
+            }
+        }
+
+        Ok(ChatAttachment { name, url })
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct ServerPerformance {
     pub average_tick_ms: f32,
@@ -63,3 +296,277 @@ pub struct ServerPerformance {
     pub used_memory: u64,
     pub total_memory: u64,
 }
+
+/// The change between two [`ServerPerformance`] samples. Counters can shrink as well as grow (for
+/// example when a dimension is unloaded), so every field is signed.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ServerPerformanceDelta {
+    pub average_tick_ms: f32,
+    pub dimensions: i32,
+    pub entities: i64,
+    pub chunks: i64,
+    pub used_memory: i64,
+    pub total_memory: i64,
+}
+
+impl ServerPerformance {
+    pub fn delta_since(&self, previous: &ServerPerformance) -> ServerPerformanceDelta {
+        ServerPerformanceDelta {
+            average_tick_ms: self.average_tick_ms - previous.average_tick_ms,
+            dimensions: self.dimensions as i32 - previous.dimensions as i32,
+            entities: self.entities as i64 - previous.entities as i64,
+            chunks: self.chunks as i64 - previous.chunks as i64,
+            used_memory: self.used_memory as i64 - previous.used_memory as i64,
+            total_memory: self.total_memory as i64 - previous.total_memory as i64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_colors_parse_from_hex() {
+        assert_eq!(NameColor::from_hex("#FFAA00"), Some(NameColor::from_rgb(0xFFAA00)));
+        assert_eq!(NameColor::from_hex("55ffff"), Some(NameColor::from_rgb(0x55FFFF)));
+    }
+
+    #[test]
+    fn name_colors_must_be_six_hex_digits() {
+        assert_eq!(NameColor::from_hex("+12345"), None);
+        assert_eq!(NameColor::from_hex("#-12345"), None);
+        assert_eq!(NameColor::from_hex("12G456"), None);
+        assert_eq!(NameColor::from_hex("#FFF"), None);
+        assert_eq!(NameColor::from_hex("FFAA001"), None);
+        // six bytes, but not six digits
+        assert_eq!(NameColor::from_hex("ééé"), None);
+    }
+
+    #[test]
+    fn name_colors_drop_anything_above_rgb() {
+        let color = NameColor::from_rgb(0x80FFAA00);
+        assert_eq!(color.rgb(), 0xFFAA00);
+        assert_eq!(color.to_hex(), "#FFAA00");
+        assert_eq!(serde_json::to_value(color).unwrap(), serde_json::json!(0xFFAA00));
+    }
+
+    #[test]
+    fn name_colors_come_from_minecraft_codes() {
+        assert_eq!(NameColor::from_minecraft_code('6'), Some(NameColor::from_rgb(0xFFAA00)));
+        assert_eq!(NameColor::from_minecraft_code('F'), Some(NameColor::from_rgb(0xFFFFFF)));
+        assert_eq!(NameColor::from_minecraft_code('l'), None);
+        assert_eq!(NameColor::from_minecraft_code('g'), None);
+    }
+
+    #[test]
+    fn name_colors_parse_from_hex_or_codes() {
+        assert_eq!("#55FFFF".parse(), Ok(NameColor::from_rgb(0x55FFFF)));
+        assert_eq!("\u{a7}c".parse(), Ok(NameColor::from_rgb(0xFF5555)));
+        assert!("\u{a7}l".parse::<NameColor>().is_err());
+        assert!("\u{a7}cc".parse::<NameColor>().is_err());
+        assert!("red".parse::<NameColor>().is_err());
+    }
+
+    #[test]
+    fn name_colors_can_serialize_as_hex() {
+        let mut message = chat("hi");
+        message.name_color = Some(NameColor::from_rgb(0xFFAA00));
+        message.name_color_hex = message.name_color;
+        let json = serde_json::to_value(&message).unwrap();
+        assert_eq!(json["name_color"], serde_json::json!(0xFFAA00));
+        assert_eq!(json["name_color_hex"], serde_json::json!("#FFAA00"));
+
+        message.name_color_hex = None;
+        let json = serde_json::to_value(&message).unwrap();
+        assert!(json.get("name_color_hex").is_none());
+    }
+
+    #[test]
+    fn formatting_codes_are_stripped() {
+        assert_eq!(strip_formatting_codes("\u{a7}aGreen \u{a7}lbold"), "Green bold");
+        // unknown codes go too, as does a trailing prefix
+        assert_eq!(strip_formatting_codes("\u{a7}zodd\u{a7}"), "odd");
+        assert_eq!(strip_formatting_codes("no codes"), "no codes");
+    }
+
+    #[test]
+    fn formatting_codes_become_markdown() {
+        assert_eq!(formatting_codes_to_markdown("\u{a7}lbold\u{a7}r plain"), "**bold** plain");
+        assert_eq!(formatting_codes_to_markdown("\u{a7}l\u{a7}obold italic"), "***bold italic***");
+        // colors end open styles, as they do in game
+        assert_eq!(formatting_codes_to_markdown("\u{a7}mgone\u{a7}cred"), "~~gone~~red");
+        assert_eq!(formatting_codes_to_markdown("\u{a7}Lsame\u{a7}l twice"), "**same twice**");
+        assert_eq!(formatting_codes_to_markdown("\u{a7}nopen"), "__open__");
+        assert_eq!(formatting_codes_to_markdown("\u{a7}zodd\u{a7}"), "odd");
+    }
+
+    fn chat(content: &str) -> ChatMessage {
+        ChatMessage {
+            sender: "Gegy".to_owned(),
+            sender_user: DiscordUser { id: 1, name: "Gegy".to_owned(), discriminator: 1 },
+            content: content.to_owned(),
+            name_color: None,
+            name_color_hex: None,
+            attachments: Vec::new(),
+            replying_to: None,
+            reply_quote: None,
+        }
+    }
+
+    fn reply_chain(depth: usize) -> ChatMessage {
+        let mut message = chat("0");
+        for i in 1..=depth {
+            let mut reply = chat(&i.to_string());
+            reply.replying_to = Some(Box::new(message));
+            message = reply;
+        }
+        message
+    }
+
+    fn reply_depth(message: &ChatMessage) -> usize {
+        let mut depth = 0;
+        let mut current = message;
+        while let Some(replying_to) = &current.replying_to {
+            depth += 1;
+            current = replying_to;
+        }
+        depth
+    }
+
+    #[test]
+    fn replies_past_the_max_depth_are_replaced() {
+        let mut message = reply_chain(5);
+        message.truncate_replies(2);
+
+        assert_eq!(reply_depth(&message), 3);
+        let placeholder = message.replying_to.as_ref().unwrap().replying_to.as_ref().unwrap().replying_to.as_ref().unwrap();
+        assert_eq!(placeholder.content, "\u{2026}");
+        assert!(placeholder.replying_to.is_none());
+    }
+
+    #[test]
+    fn short_reply_chains_are_left_alone() {
+        let mut message = reply_chain(1);
+        message.truncate_replies(1);
+
+        assert_eq!(reply_depth(&message), 1);
+        assert_eq!(message.replying_to.as_ref().unwrap().content, "0");
+    }
+
+    #[test]
+    fn very_deep_reply_chains_are_cut_without_overflowing() {
+        let mut message = reply_chain(100_000);
+        message.truncate_replies(usize::MAX);
+
+        assert_eq!(reply_depth(&message), MAX_REPLY_DEPTH + 1);
+        assert!(serde_json::to_string(&message).is_ok());
+    }
+
+    fn performance(entities: u32, chunks: u32, used_memory: u64, average_tick_ms: f32) -> ServerPerformance {
+        ServerPerformance {
+            average_tick_ms,
+            tps: 20,
+            dimensions: 3,
+            entities,
+            chunks,
+            used_memory,
+            total_memory: 8 << 30,
+        }
+    }
+
+    #[test]
+    fn performance_deltas_show_growth() {
+        let previous = performance(1_000, 400, 2 << 30, 12.5);
+        let delta = performance(5_000, 650, 3 << 30, 20.0).delta_since(&previous);
+
+        assert_eq!(delta.entities, 4_000);
+        assert_eq!(delta.chunks, 250);
+        assert_eq!(delta.used_memory, 1 << 30);
+        assert_eq!(delta.total_memory, 0);
+        assert_eq!(delta.dimensions, 0);
+        assert!((delta.average_tick_ms - 7.5).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn performance_deltas_go_negative_when_counters_shrink() {
+        let previous = performance(5_000, 650, 3 << 30, 20.0);
+        let mut current = performance(1_000, 400, 2 << 30, 12.5);
+        // a dimension was unloaded
+        current.dimensions = 2;
+        let delta = current.delta_since(&previous);
+
+        assert_eq!(delta.entities, -4_000);
+        assert_eq!(delta.chunks, -250);
+        assert_eq!(delta.used_memory, -(1 << 30));
+        assert_eq!(delta.dimensions, -1);
+        assert!((delta.average_tick_ms + 7.5).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn performance_deltas_cover_the_whole_range() {
+        let previous = ServerPerformance { entities: u32::MAX, ..ServerPerformance::default() };
+        let delta = ServerPerformance::default().delta_since(&previous);
+        assert_eq!(delta.entities, -(u32::MAX as i64));
+    }
+
+    fn attachment(url: &str, allowed_hosts: Option<&[&str]>) -> Result<ChatAttachment, String> {
+        let allowed_hosts: Option<HashSet<String>> = allowed_hosts.map(|hosts| hosts.iter().map(|host| host.to_string()).collect());
+        ChatAttachment::new("image.png".to_owned(), url.to_owned(), allowed_hosts.as_ref())
+    }
+
+    #[test]
+    fn http_and_https_attachments_are_accepted() {
+        let accepted = attachment("https://cdn.discordapp.com/attachments/1/2/image.png", None).unwrap();
+        assert_eq!(accepted.url, "https://cdn.discordapp.com/attachments/1/2/image.png");
+        assert!(attachment("http://example.com/image.png", None).is_ok());
+    }
+
+    #[test]
+    fn attachments_that_arent_http_urls_are_refused() {
+        assert_eq!(attachment("ftp://example.com/image.png", None).unwrap_err(), "unsupported scheme: ftp");
+        assert_eq!(attachment("javascript:alert(1)", None).unwrap_err(), "unsupported scheme: javascript");
+        assert!(attachment("not a url", None).unwrap_err().starts_with("malformed url"));
+    }
+
+    #[test]
+    fn attachments_can_be_limited_to_some_hosts() {
+        let hosts: &[&str] = &["cdn.discordapp.com"];
+        assert!(attachment("https://cdn.discordapp.com/image.png", Some(hosts)).is_ok());
+        // hosts are compared case-insensitively
+        assert!(attachment("https://CDN.discordapp.com/image.png", Some(hosts)).is_ok());
+        assert_eq!(attachment("https://evil.example/image.png", Some(hosts)).unwrap_err(), "host not allowed: evil.example");
+    }
+
+    #[test]
+    fn replies_are_quoted_without_their_nesting() {
+        let mut message = reply_chain(3);
+        message.replying_to.as_mut().unwrap().content = "\u{a7}lgood\u{a7}r game @everyone".to_owned();
+        message.quote_reply(100);
+
+        assert_eq!(message.reply_quote.as_deref(), Some("Gegy: good game @everyone"));
+        assert!(message.replying_to.is_none());
+
+        let json = serde_json::to_value(&message).unwrap();
+        assert!(json.get("replying_to").is_none());
+        assert_eq!(json["reply_quote"], "Gegy: good game @everyone");
+    }
+
+    #[test]
+    fn long_reply_quotes_are_truncated() {
+        let mut message = reply_chain(1);
+        message.replying_to.as_mut().unwrap().content = "a very long message".to_owned();
+        message.quote_reply(6);
+
+        assert_eq!(message.reply_quote.as_deref(), Some("Gegy: a ver\u{2026}"));
+    }
+
+    #[test]
+    fn messages_that_arent_replies_have_no_quote() {
+        let mut message = chat("hello");
+        message.quote_reply(100);
+
+        assert!(message.reply_quote.is_none());
+        assert!(serde_json::to_value(&message).unwrap().get("reply_quote").is_none());
+    }
+}
@@ -0,0 +1,42 @@
+use async_trait::async_trait;
+use log::{info, warn};
+use xtra::Address;
+
+use crate::{BackendError, Controller};
+
+/// Somewhere for the statistics database to report problems that need someone's attention.
+#[async_trait]
+pub trait AlertSink: Send + Sync {
+    async fn alert(&self, alert: BackendError);
+}
+
+/// Forwards alerts to the controller, which posts them to Discord.
+#[async_trait]
+impl AlertSink for Address<Controller> {
+    async fn alert(&self, alert: BackendError) {
+        let _ = self.do_send_async(alert).await;
+    }
+}
+
+/// Drops every alert.
+pub struct NoopAlertSink;
+
+#[async_trait]
+impl AlertSink for NoopAlertSink {
+    async fn alert(&self, _alert: BackendError) {}
+}
+
+/// Writes alerts to the log instead of sending them anywhere.
+pub struct LoggingAlertSink;
+
+#[async_trait]
+impl AlertSink for LoggingAlertSink {
+    async fn alert(&self, alert: BackendError) {
+        warn!("{}: {}", alert.title, alert.description);
+        if let Some(fields) = alert.fields {
+            for (name, value) in fields {
+                info!("  {}: {}", name, value);
+            }
+        }
+    }
+}
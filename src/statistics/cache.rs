@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::ResultCacheConfig;
+
+/// Recent results of a read query, each tagged with the namespaces it read from so that writes
+/// to those namespaces can drop them.
+pub struct ResultCache<K, V> {
+    ttl: Duration,
+    capacity: usize,
+    entries: HashMap<K, CachedResult<V>>,
+    hits: u64,
+    misses: u64,
+}
+
+struct CachedResult<V> {
+    value: V,
+    // empty if the query read from every namespace
+    namespaces: Vec<String>,
+    cached_at: Instant,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+impl<K: Hash + Eq, V: Clone> ResultCache<K, V> {
+    pub fn new(config: &ResultCacheConfig) -> ResultCache<K, V> {
+        ResultCache {
+            ttl: Duration::from_secs(config.ttl_seconds),
+            capacity: config.capacity,
+            entries: HashMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        self.get_at(key, Instant::now())
+    }
+
+    /// Looks up a result as of the time `now`, missing if it had expired by then.
+    pub fn get_at(&mut self, key: &K, now: Instant) -> Option<V> {
+        let ttl = self.ttl;
+        match self.entries.get(key) {
+            Some(cached) if now.saturating_duration_since(cached.cached_at) < ttl => {
+                self.hits += 1;
+                Some(cached.value.clone())
+            }
+            _ => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: K, namespaces: Vec<String>, value: V) {
+        self.insert_at(key, namespaces, value, Instant::now())
+    }
+
+    /// Caches a result as of the time `now`, clearing out whatever had expired by then if full.
+    pub fn insert_at(&mut self, key: K, namespaces: Vec<String>, value: V, now: Instant) {
+        if self.entries.len() >= self.capacity {
+            let ttl = self.ttl;
+            self.entries.retain(|_, cached| now.saturating_duration_since(cached.cached_at) < ttl);
+            // Everything's still fresh, so this result just isn't cached.
+            if self.entries.len() >= self.capacity {
+                return;
+            }
+        }
+
+        self.entries.insert(key, CachedResult { value, namespaces, cached_at: now });
+    }
+
+    /// Drops every result that `reads_namespace` says could have read from the namespace.
+    pub fn invalidate<F: Fn(&str) -> bool>(&mut self, reads_namespace: F) {
+        self.entries.retain(|_, cached| {
+            !cached.namespaces.is_empty() && !cached.namespaces.iter().any(|namespace| reads_namespace(namespace))
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats { hits: self.hits, misses: self.misses, entries: self.entries.len() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(ttl_seconds: u64, capacity: usize) -> ResultCache<&'static str, u32> {
+        ResultCache::new(&ResultCacheConfig { ttl_seconds, capacity })
+    }
+
+    #[test]
+    fn invalidating_a_namespace_drops_only_results_that_read_it() {
+        let mut cache = cache(60, 10);
+        cache.insert("bedwars", vec!["bedwars".to_owned()], 1);
+        cache.insert("skywars", vec!["skywars".to_owned()], 2);
+        cache.insert("everything", Vec::new(), 3);
+
+        cache.invalidate(|namespace| namespace == "bedwars");
+
+        assert_eq!(cache.get(&"bedwars"), None);
+        assert_eq!(cache.get(&"skywars"), Some(2));
+        // results that read from every namespace could have read from any of them
+        assert_eq!(cache.get(&"everything"), None);
+    }
+
+    #[test]
+    fn hits_and_misses_are_counted() {
+        let mut cache = cache(60, 10);
+        cache.insert("bedwars", vec!["bedwars".to_owned()], 1);
+
+        assert_eq!(cache.get(&"bedwars"), Some(1));
+        assert_eq!(cache.get(&"skywars"), None);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+    }
+
+    #[test]
+    fn results_past_capacity_are_not_cached() {
+        let mut cache = cache(60, 1);
+        cache.insert("bedwars", vec!["bedwars".to_owned()], 1);
+        cache.insert("skywars", vec!["skywars".to_owned()], 2);
+
+        assert_eq!(cache.get(&"bedwars"), Some(1));
+        assert_eq!(cache.get(&"skywars"), None);
+    }
+
+    #[test]
+    fn results_expire_after_the_ttl() {
+        let mut cache = cache(60, 10);
+        let start = Instant::now();
+        cache.insert_at("bedwars", vec!["bedwars".to_owned()], 1, start);
+
+        assert_eq!(cache.get_at(&"bedwars", start + Duration::from_secs(59)), Some(1));
+        assert_eq!(cache.get_at(&"bedwars", start + Duration::from_secs(60)), None);
+    }
+
+    #[test]
+    fn expired_results_make_room_for_new_ones() {
+        let mut cache = cache(60, 1);
+        let start = Instant::now();
+        cache.insert_at("bedwars", vec!["bedwars".to_owned()], 1, start);
+
+        let later = start + Duration::from_secs(60);
+        cache.insert_at("skywars", vec!["skywars".to_owned()], 2, later);
+
+        assert_eq!(cache.get_at(&"skywars", later), Some(2));
+        assert_eq!(cache.stats().entries, 1);
+    }
+}
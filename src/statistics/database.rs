@@ -1,70 +1,272 @@
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use async_trait::async_trait;
-use chrono::Utc;
+use chrono::{DateTime, TimeZone, Utc};
 use chrono_tz::Tz;
-use clickhouse_rs::{Block, Pool, row};
-use log::warn;
+use clickhouse_rs::{Block, ClientHandle, Options, Pool, row};
+use clickhouse_rs::types::{Complex, Row};
+use futures::StreamExt;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
 use uuid::Uuid;
 use xtra::{Actor, Address, Context, Handler, Message};
 
-use crate::{Controller, StatisticsConfig};
-use crate::statistics::model::{GameStatsBundle, initialise_database, PlayerStatsResponse};
+use crate::{AchievementConfig, AchievementUnlocked, BackendError, Controller, MilestoneReached, SeasonConfig, StatDecayConfig, StatisticsConfig};
+use crate::model::Player;
+use crate::statistics::alert::AlertSink;
+use crate::statistics::cache::{CacheStats, ResultCache};
+use crate::statistics::names::NameCache;
+use crate::statistics::rate_limit::TokenBucket;
+use crate::statistics::username::{resolve_batch, UsernameResolver};
+use crate::statistics::webhook::{Webhook, WebhookEvent};
+use crate::statistics::model::{BundleUploadReport, ChangedStats, DECIMAL_PLACES, GameStatsBundle, GlobalStatComparison, GlobalStatMismatch, GameStatsUpload, initialise_database, LeaderboardEntry, LeaderboardRank, LeaderboardResponse, LeaderboardWindow, NamespaceActivity, NamespaceStanding, NamespaceSchema, NamespaceStat, PersonalBest, PlayerAchievement, PlayerOverview, PlayerProfile, PlayerStatsDocument, PlaytimeEntry, PlayerStatsResponse, RankedLeaderboardEntry, RenamedStat, ReplayReport, ResetStatsReport, TrendingEntry, RejectedStat, StatChange, StatChanged, StatMetadata, StatSchema, StatScope, StatThresholdMatch, StatValue, StorageStats, StoredStat, TableMaintenance, TableStorage, TrendGranularity, TrendPoint, TypedPlayerStatsResponse, UploadedBundle, UploadErrorKind, UploadStat, UsernameBackfillReport};
+
+pub const MAX_LEADERBOARD_SIZE: u32 = 100;
+pub const MAX_PLAYER_SEARCH_RESULTS: u32 = 20;
+pub const MAX_COMPARED_PLAYERS: usize = 16;
+pub const MAX_LEADERBOARDS_PER_REQUEST: usize = 8;
+pub const MAX_THRESHOLD_MATCHES: u32 = 500;
+pub const MAX_LEADERBOARD_COHORT: usize = 200;
+pub const MAX_COMPARED_SEASONS: usize = 8;
+pub const MAX_LEADERBOARD_WINDOW_RADIUS: u32 = 25;
+pub const MAX_TREND_POINTS: i64 = 1000;
+pub const MAX_COMPARED_NAMESPACES: usize = 16;
+pub const CURRENT_SEASON: &str = "current";
+
+// Status updates are frequent, so only rewrite a profile we've already seen this often.
+const PLAYER_PROFILE_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// The most players whose profiles are remembered as up to date at once.
+const MAX_KNOWN_PLAYERS: usize = 10_000;
+
+// Only this many of a namespace's stat rows are looked at when working out its schema.
+const SCHEMA_SAMPLE_SIZE: u32 = 100_000;
+
+// How many of the players left out of a bundle have their errors included in its report.
+const MAX_REPORTED_PLAYER_ERRORS: usize = 5;
+
+// Stat change events are best-effort: slow subscribers that fall this far behind will miss events,
+// as will subscribers reconnecting after more than this many have been sent.
+const STAT_CHANGE_CAPACITY: usize = 256;
 
 pub struct StatisticDatabaseController {
-    _controller: Address<Controller>,
+    controller: Address<Controller>,
+    alerts: Box<dyn AlertSink>,
+    webhook: Option<Webhook>,
+    username_resolver: Option<Arc<UsernameResolver>>,
+    // Held while a username backfill runs in the background.
+    username_backfill: Arc<Mutex<()>>,
     pool: Pool,
-    _config: StatisticsConfig,
+    // Used by read-only queries that can tolerate replication lag. The same as `pool` unless a
+    // separate read database is configured.
+    read_pool: Pool,
+    config: StatisticsConfig,
+    stat_changes: broadcast::Sender<StatChanged>,
+    // The latest changes sent, oldest first, for subscribers picking up where they left off.
+    recent_stat_changes: VecDeque<StatChanged>,
+    next_stat_change_id: u64,
+    known_players: HashMap<Uuid, (String, Instant)>,
+    names: NameCache,
+    upload_buckets: HashMap<String, TokenBucket>,
+    // Namespaces we've already raised an alert for, until they're allowed to upload again.
+    throttled_namespaces: HashSet<String>,
+    // (namespace, stat) -> the full top leaderboard and when it was computed
+    leaderboard_snapshots: HashMap<(String, String), (Vec<LeaderboardEntry>, SystemTime)>,
+    leaderboard_cache: Option<ResultCache<GetLeaderboard, LeaderboardResponse>>,
+    player_stats_cache: Option<ResultCache<GetPlayerStats, Option<PlayerStatsResponse>>>,
 }
 
 impl StatisticDatabaseController {
-    pub async fn connect(controller: &Address<Controller>, config: &StatisticsConfig) -> Result<Self, StatisticsDatabaseError> {
-        let handler = Self {
-            _controller: controller.clone(),
-            pool: Pool::new(config.database_url.clone()),
-            _config: config.clone(),
+    pub async fn connect(controller: &Address<Controller>, alerts: Box<dyn AlertSink>, config: &StatisticsConfig) -> Result<Self, StatisticsDatabaseError> {
+        config.validate().map_err(StatisticsDatabaseError::InvalidConfig)?;
+
+        let (stat_changes, _) = broadcast::channel(STAT_CHANGE_CAPACITY);
+
+        let pool = Pool::new(pool_options(&config.database_url, config)?);
+        let read_pool = match &config.read_database_url {
+            Some(read_database_url) => Pool::new(pool_options(read_database_url, config)?),
+            None => pool.clone(),
+        };
+
+        let mut handler = Self {
+            controller: controller.clone(),
+            alerts,
+            webhook: config.webhook.as_ref().map(Webhook::new),
+            username_resolver: config.username_resolver.as_ref().map(|config| Arc::new(UsernameResolver::new(config))),
+            username_backfill: Arc::new(Mutex::new(())),
+            pool,
+            read_pool,
+            config: config.clone(),
+            stat_changes,
+            recent_stat_changes: VecDeque::with_capacity(STAT_CHANGE_CAPACITY),
+            next_stat_change_id: 1,
+            known_players: HashMap::new(),
+            names: NameCache::new(Duration::from_secs(config.name_cache_ttl_seconds)),
+            upload_buckets: HashMap::new(),
+            throttled_namespaces: HashSet::new(),
+            leaderboard_snapshots: HashMap::new(),
+            leaderboard_cache: config.result_cache.as_ref().map(ResultCache::new),
+            player_stats_cache: config.result_cache.as_ref().map(ResultCache::new),
         };
 
-        initialise_database(&handler.pool).await?;
+        initialise_database(&handler.pool, config).await?;
+        if config.read_database_url.is_some() {
+            // See if we can connect to the replica too
+            handler.read_pool.get_handle().await?.ping().await?;
+        }
+        handler.next_stat_change_id = handler.first_stat_change_id().await?;
 
         Ok(handler)
     }
 
-    async fn get_player_stats(&self, player_id: &Uuid, namespace: &Option<String>) -> Result<Option<PlayerStatsResponse>, StatisticsDatabaseError> {
+    /// An id past every change sent before the last restart. Each change that's sent has had a
+    /// player stat row written for it first, so there are always at least as many rows as ids
+    /// handed out and the ids don't need storing themselves.
+    async fn first_stat_change_id(&self) -> Result<u64, StatisticsDatabaseError> {
         let mut handle = self.pool.get_handle().await?;
-
-        let cond = match namespace {
-            Some(namespace) => format!("player_id = '{}' AND namespace = '{}'", player_id, namespace),
-            None => format!("player_id = '{}'", player_id),
+        let block = self.fetch_all(&mut handle, "SELECT COUNT() AS row_count FROM player_statistics".to_owned()).await?;
+        let row_count: u64 = match block.rows().next() {
+            Some(row) => row.get("row_count")?,
+            None => 0,
         };
+        Ok(row_count + 1)
+    }
+
+    /// Runs a read query, which the server will give up on if it takes longer than the configured
+    /// query timeout.
+    #[tracing::instrument(level = "debug", skip(self, handle, sql), fields(operation = "select"))]
+    async fn fetch_all(&self, handle: &mut ClientHandle, sql: String) -> Result<Block<Complex>, StatisticsDatabaseError> {
+        let sql = format!("{}\n            SETTINGS max_execution_time = {}", sql, self.config.query_timeout_seconds);
+
+        let started = Instant::now();
+        let block = handle.query(&sql).fetch_all().await?;
+        self.warn_if_slow("select", started, || sql.split_whitespace().collect::<Vec<_>>().join(" "));
+
+        Ok(block)
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, handle, block), fields(operation = "insert", rows = block.row_count()))]
+    async fn insert_block(&self, handle: &mut ClientHandle, table: &str, block: Block) -> Result<(), StatisticsDatabaseError> {
+        let rows = block.row_count();
+        if rows > 0 {
+            let started = Instant::now();
+            handle.insert(table, block).await?;
+            self.warn_if_slow("insert", started, || format!("{} rows into {}", rows, table));
+        }
+        Ok(())
+    }
+
+    // `describe` is only called when the operation was slow, so it can be as expensive as it likes.
+    fn warn_if_slow<F: FnOnce() -> String>(&self, operation: &str, started: Instant, describe: F) {
+        let elapsed = started.elapsed();
+        if elapsed >= Duration::from_millis(self.config.slow_operation_threshold_ms) {
+            warn!("Slow statistics {} took {:.2}s: {}", operation, elapsed.as_secs_f64(), describe());
+        }
+    }
+
+    /// Drops any cached results that could have read from the namespace, after it's been written to.
+    fn invalidate_cached_results(&mut self, namespace: &str) {
+        // A rolled up result for a parent namespace reads from its sub-namespaces too.
+        let reads_namespace = |cached: &str| is_in_namespace(namespace, cached);
+        if let Some(cache) = &mut self.leaderboard_cache {
+            cache.invalidate(reads_namespace);
+        }
+        if let Some(cache) = &mut self.player_stats_cache {
+            cache.invalidate(reads_namespace);
+        }
+    }
+
+    async fn get_player_stats(&self, player_id: &Uuid, namespaces: &[String], rollup: bool, omit_zero: bool) -> Result<Option<PlayerStatsResponse>, StatisticsDatabaseError> {
+        let stats = self.get_typed_player_stats(player_id, namespaces, rollup, omit_zero).await?;
+
+        Ok(stats.map(|stats| {
+            stats.into_iter()
+                .map(|(namespace, stats)| {
+                    let stats = stats.into_iter()
+                        .map(|(key, value)| (key, value.as_f64()))
+                        .collect();
+                    (namespace, stats)
+                })
+                .collect()
+        }))
+    }
+
+    /// With `omit_zero`, totals that come to zero are left out. Other kinds of stat are always
+    /// included, since a best time or lowest score of zero still means something.
+    #[tracing::instrument(level = "debug", skip(self), fields(table = "player_statistics"))]
+    async fn get_typed_player_stats(&self, player_id: &Uuid, namespaces: &[String], rollup: bool, omit_zero: bool) -> Result<Option<TypedPlayerStatsResponse>, StatisticsDatabaseError> {
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let cond = player_stats_condition(player_id, namespaces, rollup);
+
+        let decimal_total = decimal_total_sql();
+        let having = omit_zero_having_sql(omit_zero);
 
+        // Integer stats are summed separately as Int64 so that large counters don't lose precision,
+        // and decimal stats as Decimal128 so that they add up exactly.
         let sql = format!(
             r#"
             SELECT
                 namespace,
                 key,
-                SUM(value)
+                multiIf(startsWith(type, 'int_'), 'int', startsWith(type, 'decimal_'), 'decimal', 'float') AS kind,
+                SUM(value) AS float_total,
+                SUM(toInt64(value)) AS int_total,
+                toString({}) AS decimal_total
             FROM player_statistics
             WHERE
                 {}
             GROUP BY
                 namespace,
-                key
+                key,
+                kind{}
             ORDER BY
                 key ASC
-            "#, cond);
+            "#, decimal_total, cond, having);
 
-        let block = handle.query(sql).fetch_all().await?;
+        let block = self.fetch_all(&mut handle, sql).await?;
 
-        let mut result = HashMap::new();
+        let mut result: TypedPlayerStatsResponse = HashMap::new();
         for row in block.rows() {
-            let namespace: String = row.get("namespace")?;
+            let row_namespace: String = row.get("namespace")?;
+            // Sub-namespaces are reported as part of the namespace they're being rolled up into.
+            let namespace = if rollup {
+                namespaces.iter()
+                    .find(|namespace| is_in_namespace(&row_namespace, namespace))
+                    .cloned()
+                    .unwrap_or_else(|| root_namespace(&row_namespace).to_owned())
+            } else {
+                row_namespace
+            };
             let key: String = row.get("key")?;
-            let value: f64 = row.get("sum(value)")?;
-            if !result.contains_key(&namespace) {
-                result.insert(namespace.clone(), HashMap::new());
+            let kind: String = row.get("kind")?;
+            let value = match kind.as_str() {
+                "int" => StatValue::Int(row.get("int_total")?),
+                "decimal" => {
+                    let total: String = row.get("decimal_total")?;
+                    match total.parse() {
+                        Ok(total) => StatValue::Decimal(total),
+                        Err(_) => StatValue::Float(row.get("float_total")?),
+                    }
+                }
+                _ => StatValue::Float(row.get("float_total")?),
+            };
+
+            let stats = result.entry(namespace).or_insert_with(HashMap::new);
+            let value = match stats.remove(&key) {
+                Some(existing) => existing.combine(value),
+                None => value,
+            };
+            stats.insert(key, value);
+        }
+
+        for (namespace, stats) in result.iter_mut() {
+            if let Some(&keep) = self.config.top_stats_per_player.get(namespace) {
+                keep_top_stats(stats, keep);
             }
-            result.get_mut(&namespace).unwrap().insert(key, value);
         }
 
         if result.is_empty() {
@@ -74,12 +276,159 @@ impl StatisticDatabaseController {
         }
     }
 
+    /// The namespace's global totals, flattened to floats like player stats are.
+    async fn get_global_stats(&self, namespace: &str) -> Result<Option<HashMap<String, f64>>, StatisticsDatabaseError> {
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let sql = format!(r#"
+            SELECT key, SUM(value) AS total
+                FROM global_statistics
+                WHERE namespace = {}
+                GROUP BY key"#, quote(namespace));
+
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let mut stats = HashMap::with_capacity(block.row_count());
+        for row in block.rows() {
+            stats.insert(row.get("key")?, row.get("total")?);
+        }
+
+        if stats.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(stats))
+        }
+    }
+
+    /// Players' totals for the given stats side by side. Stats a player doesn't have are left out
+    /// rather than reported as zero.
+    async fn compare_players(&self, players: &[Uuid], namespace: &str, stats: &[String]) -> Result<HashMap<Uuid, HashMap<String, f64>>, StatisticsDatabaseError> {
+        if players.len() > MAX_COMPARED_PLAYERS {
+            return Err(StatisticsDatabaseError::InvalidRequest(format!("at most {} players can be compared", MAX_COMPARED_PLAYERS)));
+        }
+        if players.is_empty() || stats.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let players = players.iter()
+            .map(|player| format!("'{}'", player))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let keys = stats.iter()
+            .map(|stat| quote(stat))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let sql = format!(r#"
+            SELECT player_id, key, SUM(value) AS total
+                FROM player_statistics
+                WHERE namespace = {} AND player_id IN ({}) AND key IN ({})
+                GROUP BY player_id, key"#, quote(namespace), players, keys);
+
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let mut comparison: HashMap<Uuid, HashMap<String, f64>> = HashMap::new();
+        for row in block.rows() {
+            let player: Uuid = row.get("player_id")?;
+            let key: String = row.get("key")?;
+            let total: f64 = row.get("total")?;
+            comparison.entry(player).or_insert_with(HashMap::new).insert(key, total);
+        }
+
+        Ok(comparison)
+    }
+
+    /// A player's stats in each of the given seasons. The current season is all of their stats,
+    /// including any corrections, while past seasons only have what was earned in games played
+    /// during them.
+    async fn compare_player_across_seasons(&self, player_id: &Uuid, namespace: &str, seasons: &[String]) -> Result<HashMap<String, HashMap<String, f64>>, StatisticsDatabaseError> {
+        if seasons.len() > MAX_COMPARED_SEASONS {
+            return Err(StatisticsDatabaseError::InvalidRequest(format!("at most {} seasons can be compared", MAX_COMPARED_SEASONS)));
+        }
+
+        let configured = self.config.seasons.get(namespace).map(Vec::as_slice).unwrap_or(&[]);
+
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let mut comparison = HashMap::with_capacity(seasons.len());
+        for name in seasons {
+            let games = season_games_condition(namespace, configured, name)?;
+
+            let sql = format!(r#"
+                SELECT key, SUM(value) AS total
+                    FROM player_statistics
+                    WHERE namespace = {} AND player_id = '{}'{}
+                    GROUP BY key"#, quote(namespace), player_id, games);
+
+            let block = self.fetch_all(&mut handle, sql).await?;
+
+            let mut stats = HashMap::with_capacity(block.row_count());
+            for row in block.rows() {
+                stats.insert(row.get("key")?, row.get("total")?);
+            }
+            comparison.insert(name.clone(), stats);
+        }
+
+        Ok(comparison)
+    }
+
+    /// A player's best single game for each of the namespace's personal best stats.
+    ///
+    /// Bundles carry each stat's value for that game alone, which is what gets stored per game
+    /// before being summed into totals. So a stat's personal best is simply its best stored value,
+    /// and bundles need nothing extra to support this. Whether best means highest or lowest comes
+    /// from the stat's metadata.
+    async fn get_personal_bests(&self, player_id: &Uuid, namespace: &str) -> Result<HashMap<String, PersonalBest>, StatisticsDatabaseError> {
+        let stats = match self.config.personal_best_stats.get(namespace) {
+            Some(stats) if !stats.is_empty() => stats,
+            _ => return Ok(HashMap::new()),
+        };
+
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let keys = stats.iter()
+            .map(|stat| quote(stat))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let lower_is_better = stats.iter()
+            .filter(|stat| !self.stat_metadata(namespace, stat).higher_is_better)
+            .map(|stat| quote(stat))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        // Negating the stats where lower is better lets a single argMax find every best. Corrections
+        // aren't games, so they can't be a best.
+        let sql = format!(r#"
+            SELECT key, argMax(value, score) AS best, argMax(game_id, score) AS best_game
+                FROM (
+                    SELECT key, value, game_id, if(has([{}], key), -value, value) AS score
+                        FROM player_statistics
+                        WHERE player_id = '{}' AND namespace = {} AND key IN ({}) AND {}
+                )
+                GROUP BY key"#, lower_is_better, player_id, quote(namespace), keys, NOT_A_CORRECTION);
+
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let mut bests = HashMap::with_capacity(block.row_count());
+        for row in block.rows() {
+            let key: String = row.get("key")?;
+            bests.insert(key, PersonalBest {
+                value: row.get("best")?,
+                game_id: row.get("best_game")?,
+            });
+        }
+
+        Ok(bests)
+    }
+
     async fn get_game_stats(&self, game_id: &Uuid) -> Result<Option<HashMap<Uuid, PlayerStatsResponse>>, StatisticsDatabaseError> {
-        let mut handle = self.pool.get_handle().await?;
+        let mut handle = self.read_pool.get_handle().await?;
 
         let game_sql = format!("SELECT game_id FROM games WHERE game_id = '{}'", game_id);
 
-        if handle.query(game_sql).fetch_all().await?.is_empty() {
+        if self.fetch_all(&mut handle, game_sql).await?.is_empty() {
             return Ok(None);
         }
 
@@ -95,8 +444,8 @@ impl StatisticDatabaseController {
                 FROM global_statistics
                 WHERE game_id = '{}'"#, game_id);
 
-        let players_res = handle.query(players_sql).fetch_all().await?;
-        let global_res = handle.query(global_sql).fetch_all().await?;
+        let players_res = self.fetch_all(&mut handle, players_sql).await?;
+        let global_res = self.fetch_all(&mut handle, global_sql).await?;
 
         if players_res.is_empty() && global_res.is_empty() {
             return Ok(None);
@@ -141,122 +490,5143 @@ impl StatisticDatabaseController {
         Ok(Some(players))
     }
 
-    async fn upload_stats_bundle(&self, game_id: Uuid, server: &String, bundle: GameStatsBundle) -> Result<Uuid, StatisticsDatabaseError> {
-        let mut handle = self.pool.get_handle().await?;
+    async fn update_player_profiles(&mut self, players: Vec<Player>) -> Result<(), StatisticsDatabaseError> {
+        let now = Instant::now();
+        let last_seen = Utc::now().with_timezone(&Tz::GMT);
+
+        let mut block = Block::new();
+        for player in players {
+            let id = match Uuid::parse_str(&player.id) {
+                Ok(id) => id,
+                Err(_) => continue,
+            };
 
-        // Steps to insert a whole stats bundle
-        {
-            let date_played = Utc::now().with_timezone(&Tz::GMT);
+            let up_to_date = match self.known_players.get(&id) {
+                Some((name, updated)) => *name == player.name && now.duration_since(*updated) < PLAYER_PROFILE_REFRESH_INTERVAL,
+                None => false,
+            };
+            if up_to_date {
+                continue;
+            }
 
-            // 1. Insert a row into the games table and record the allocated ID
-            let mut block = Block::with_capacity(1);
             block.push(row! {
-                game_id: game_id,
-                namespace: bundle.namespace.clone(),
-                player_count: bundle.stats.players.len() as u32,
-                server: server.clone(),
-                date_played: date_played,
+                player_id: id,
+                username: player.name.clone(),
+                last_seen: last_seen,
+                username_lower: player.name.to_lowercase(),
             })?;
+            self.names.insert(id, Some(player.name.clone()));
+            if self.known_players.len() >= MAX_KNOWN_PLAYERS && !self.known_players.contains_key(&id) {
+                forget_known_players(&mut self.known_players, now);
+            }
+            self.known_players.insert(id, (player.name, now));
+        }
 
-            handle.insert("games", block).await?;
+        if block.row_count() > 0 {
+            let mut handle = self.pool.get_handle().await?;
+            self.insert_block(&mut handle, "players", block).await?;
         }
 
-        {
-            // 2. Insert all player statistics into the player_statistics table
-            let mut block = Block::with_capacity(bundle.stats.players.len());
-            for (player, stats) in bundle.stats.players {
-                for (key, stat) in stats {
-                    let value: f64 = stat.clone().into();
-                    block.push(row! {
-                        game_id: game_id,
-                        player_id: player,
-                        namespace: bundle.namespace.clone(),
-                        key: key.clone(),
-                        value: value,
-                        type: stat.clone().get_type(),
-                    })?;
+        Ok(())
+    }
+
+    async fn search_players(&self, prefix: &str, limit: u32) -> Result<Vec<PlayerProfile>, StatisticsDatabaseError> {
+        if prefix.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut handle = self.read_pool.get_handle().await?;
+
+        // startsWith rather than LIKE or a regex, so there's nothing in the prefix that can be
+        // interpreted as a pattern. Only players who have ever had a matching username are
+        // grouped, rather than every profile, and then checked against their current one.
+        let prefix = quote(&prefix.to_lowercase());
+        let sql = format!(r#"
+            SELECT player_id, argMax(username, last_seen) AS name, argMax(username_lower, last_seen) AS name_lower
+                FROM players
+                WHERE player_id IN (SELECT player_id FROM players WHERE startsWith(username_lower, {prefix}))
+                GROUP BY player_id
+                HAVING startsWith(name_lower, {prefix})
+                ORDER BY name_lower ASC
+                LIMIT {limit}"#, prefix = prefix, limit = limit.min(MAX_PLAYER_SEARCH_RESULTS));
+
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let mut players = Vec::with_capacity(block.row_count());
+        for row in block.rows() {
+            players.push(PlayerProfile {
+                id: row.get("player_id")?,
+                username: row.get("name")?,
+            });
+        }
+
+        Ok(players)
+    }
+
+    /// Finds the player currently using a username, ignoring case.
+    async fn lookup_player(&self, username: &str) -> Result<Option<PlayerProfile>, StatisticsDatabaseError> {
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let sql = format!(r#"
+            SELECT player_id, argMax(username, last_seen) AS name, argMax(username_lower, last_seen) AS name_lower, max(last_seen) AS seen
+                FROM players
+                GROUP BY player_id
+                HAVING name_lower = {}
+                ORDER BY seen DESC
+                LIMIT 1"#, quote(&username.to_lowercase()));
+
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        match block.rows().next() {
+            Some(row) => Ok(Some(PlayerProfile {
+                id: row.get("player_id")?,
+                username: row.get("name")?,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    async fn flag_player(&mut self, player_id: Uuid, flag: &str, value: bool) -> Result<(), StatisticsDatabaseError> {
+        if flag.is_empty() {
+            return Err(StatisticsDatabaseError::InvalidRequest("flags need a name".to_owned()));
+        }
+
+        let mut handle = self.pool.get_handle().await?;
+
+        let mut block = Block::new();
+        block.push(row! {
+            player_id: player_id,
+            flag: flag.to_owned(),
+            value: value as u8,
+            updated_at: Utc::now().with_timezone(&Tz::GMT),
+        })?;
+        self.insert_block(&mut handle, "player_flags", block).await?;
+
+        // Any snapshot could include the player, so they're all recomputed on the next refresh.
+        if HIDING_FLAGS.contains(&flag) {
+            self.leaderboard_snapshots.clear();
+            if let Some(cache) = &mut self.leaderboard_cache {
+                cache.clear();
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn get_flags(&self, player_id: &Uuid) -> Result<Vec<String>, StatisticsDatabaseError> {
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let sql = format!(r#"
+            SELECT flag
+                FROM player_flags
+                WHERE player_id = '{}'
+                GROUP BY flag
+                HAVING argMax(value, updated_at) = 1
+                ORDER BY flag"#, player_id);
+
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let mut flags = Vec::with_capacity(block.row_count());
+        for row in block.rows() {
+            flags.push(row.get("flag")?);
+        }
+
+        Ok(flags)
+    }
+
+    async fn resolve_name(&mut self, player_id: &Uuid) -> Result<Option<String>, StatisticsDatabaseError> {
+        let mut names = self.resolve_names(&[*player_id]).await?;
+        Ok(names.remove(player_id))
+    }
+
+    /// Finds the usernames of players, first from recently resolved names and then from their
+    /// profiles. Players that can't be resolved are left out. This never asks the username
+    /// resolver, so that reads don't wait on it: profiles it knows about are filled in by
+    /// [`BackfillUsernames`] instead.
+    async fn resolve_names(&mut self, players: &[Uuid]) -> Result<HashMap<Uuid, String>, StatisticsDatabaseError> {
+        let mut names = HashMap::with_capacity(players.len());
+        let mut missing = Vec::new();
+        for player in players {
+            match self.names.get(player) {
+                Some(Some(name)) => {
+                    names.insert(*player, name.to_owned());
                 }
+                Some(None) => {}
+                None => missing.push(*player),
             }
-            handle.insert("player_statistics", block).await?;
+        }
+        if missing.is_empty() {
+            return Ok(names);
         }
 
-        // 3. Insert all global statistics into the global_statistics table
-        if let Some(global) = bundle.stats.global {
-            let mut block = Block::with_capacity(global.len());
-            for (key, stat) in global {
-                let value: f64 = stat.clone().into();
-                block.push(row! {
-                    game_id: game_id,
-                    namespace: bundle.namespace.clone(),
-                    key: key.clone(),
-                    value: value,
-                    type: stat.get_type(),
-                })?;
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let ids = missing.iter()
+            .map(|player| format!("'{}'", player))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sql = format!(r#"
+            SELECT player_id, argMax(username, last_seen) AS name
+                FROM players
+                WHERE player_id IN ({})
+                GROUP BY player_id"#, ids);
+
+        let block = self.fetch_all(&mut handle, sql).await?;
+        let mut found = HashMap::with_capacity(block.row_count());
+        for row in block.rows() {
+            let player: Uuid = row.get("player_id")?;
+            let name: String = row.get("name")?;
+            if !name.is_empty() {
+                found.insert(player, name);
+            }
+        }
+
+        for player in missing {
+            let name = found.remove(&player);
+            if let Some(name) = &name {
+                names.insert(player, name.clone());
             }
-            handle.insert("global_statistics", block).await?;
+            self.names.insert(player, name);
         }
 
-        Ok(game_id)
+        Ok(names)
     }
-}
 
-impl Actor for StatisticDatabaseController {}
+    /// Gathers a player's profile, stats, achievements and leaderboard places in one go. Each part
+    /// is loaded separately, and one failing is logged and left out rather than failing the rest.
+    async fn get_player_overview(&mut self, player_id: &Uuid, namespaces: &[String]) -> PlayerOverview {
+        let username = self.resolve_name(player_id).await
+            .unwrap_or_else(|e| {
+                warn!("Failed to get the username of {} for their overview: {}", player_id, e);
+                None
+            });
 
-pub struct GetPlayerStats {
-    pub uuid: Uuid,
-    pub namespace: Option<String>,
-}
+        let stats = match self.get_player_stats(player_id, namespaces, false, false).await {
+            Ok(stats) => Some(stats.unwrap_or_default()),
+            Err(e) => {
+                warn!("Failed to get the stats of {} for their overview: {}", player_id, e);
+                None
+            }
+        };
 
-impl Message for GetPlayerStats {
-    type Result = Result<Option<PlayerStatsResponse>, StatisticsDatabaseError>;
-}
+        let achievements = match self.get_player_achievements(player_id).await {
+            Ok(achievements) => Some(achievements),
+            Err(e) => {
+                warn!("Failed to get the achievements of {} for their overview: {}", player_id, e);
+                None
+            }
+        };
 
-#[async_trait]
-impl Handler<GetPlayerStats> for StatisticDatabaseController {
-    async fn handle(&mut self, message: GetPlayerStats, _ctx: &mut Context<Self>) -> <GetPlayerStats as Message>::Result {
-        self.get_player_stats(&message.uuid, &message.namespace).await
+        let mut ranks = Vec::new();
+        for ((namespace, stat), (entries, _)) in &self.leaderboard_snapshots {
+            if !namespaces.is_empty() && !namespaces.contains(namespace) {
+                continue;
+            }
+            if let Some(index) = entries.iter().position(|entry| entry.player == *player_id) {
+                ranks.push(LeaderboardRank { namespace: namespace.clone(), stat: stat.clone(), rank: index + 1 });
+            }
+        }
+        ranks.sort_by(|a, b| (&a.namespace, &a.stat).cmp(&(&b.namespace, &b.stat)));
+
+        PlayerOverview { uuid: *player_id, username, stats, achievements, ranks }
     }
-}
 
-pub struct GetGameStats(pub Uuid);
+    async fn query_leaderboard(&self, request: &GetLeaderboard) -> Result<Vec<LeaderboardEntry>, StatisticsDatabaseError> {
+        let mut handle = self.read_pool.get_handle().await?;
 
-impl Message for GetGameStats {
-    type Result = Result<Option<HashMap<Uuid, PlayerStatsResponse>>, StatisticsDatabaseError>;
-}
+        let sql = leaderboard_sql(request, self.higher_is_better(request));
+        let block = self.fetch_all(&mut handle, sql).await?;
 
-#[async_trait]
-impl Handler<GetGameStats> for StatisticDatabaseController {
-    async fn handle(&mut self, message: GetGameStats, _ctx: &mut Context<Self>) -> <GetGameStats as Message>::Result {
-        self.get_game_stats(&message.0).await
+        let mut leaderboard = Vec::with_capacity(block.row_count());
+        for row in block.rows() {
+            leaderboard.push(LeaderboardEntry {
+                player: row.get("player_id")?,
+                value: row.get("total")?,
+            });
+        }
+
+        Ok(leaderboard)
     }
-}
 
-#[derive(Debug)]
-pub struct UploadStatsBundle {
-    pub game_id: Uuid,
-    pub server: String,
-    pub bundle: GameStatsBundle,
-}
+    /// Leaderboards for several stats at once, each sorted and limited separately.
+    async fn get_leaderboards(&self, namespace: &str, stats: &[String], limit: u32, rollup: bool, include_flagged: bool) -> Result<HashMap<String, Vec<LeaderboardEntry>>, StatisticsDatabaseError> {
+        if stats.len() > MAX_LEADERBOARDS_PER_REQUEST {
+            return Err(StatisticsDatabaseError::InvalidRequest(format!("at most {} leaderboards can be requested at once", MAX_LEADERBOARDS_PER_REQUEST)));
+        }
 
-impl Message for UploadStatsBundle {
-    type Result = ();
-}
+        let mut leaderboards: HashMap<String, Vec<LeaderboardEntry>> = stats.iter()
+            .map(|stat| (stat.clone(), Vec::new()))
+            .collect();
+        if stats.is_empty() {
+            return Ok(leaderboards);
+        }
 
-#[async_trait]
-impl Handler<UploadStatsBundle> for StatisticDatabaseController {
-    async fn handle(&mut self, message: UploadStatsBundle, _ctx: &mut Context<Self>) -> <UploadStatsBundle as Message>::Result {
-        if let Err(e) = self.upload_stats_bundle(
-            message.game_id, &message.server.clone(), message.bundle.clone()
-        ).await {
-            warn!("Failed to upload stats bundle {:?}: {}", message, e);
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let lower_is_better = stats.iter()
+            .filter(|stat| !self.stat_metadata(namespace, stat).higher_is_better)
+            .collect::<Vec<_>>();
+        let condition = format!("{}{}", namespace_condition(namespace, rollup), hidden_players_condition(include_flagged));
+        let block = self.fetch_all(&mut handle, leaderboards_sql(&condition, stats, &lower_is_better, limit)).await?;
+
+        for row in block.rows() {
+            let key: String = row.get("key")?;
+            leaderboards.entry(key).or_insert_with(Vec::new).push(LeaderboardEntry {
+                player: row.get("player_id")?,
+                value: row.get("total")?,
+            });
         }
+
+        Ok(leaderboards)
     }
-}
 
-#[derive(thiserror::Error, Debug)]
-pub enum StatisticsDatabaseError {
-    #[error("a database error occurred: {0}")]
-    ClickHouseError(#[from] clickhouse_rs::errors::Error),
-    #[error("unknown error")]
-    UnknownError,
+    /// Every player whose total for a stat is within the bounds, either of which may be left open,
+    /// highest first.
+    async fn find_players_by_stat_threshold(&self, namespace: &str, stat: &str, min: Option<f64>, max: Option<f64>, limit: u32) -> Result<Vec<StatThresholdMatch>, StatisticsDatabaseError> {
+        if namespace.is_empty() || stat.is_empty() {
+            return Err(StatisticsDatabaseError::InvalidRequest("a namespace and stat are required".to_owned()));
+        }
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+        if min.iter().chain(max.iter()).any(|bound| !bound.is_finite()) {
+            return Err(StatisticsDatabaseError::InvalidRequest("bounds must be finite numbers".to_owned()));
+        }
+
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let mut bounds = Vec::new();
+        if let Some(min) = min {
+            bounds.push(format!("total >= {}", min));
+        }
+        if let Some(max) = max {
+            bounds.push(format!("total <= {}", max));
+        }
+        let having = if bounds.is_empty() {
+            String::new()
+        } else {
+            format!("HAVING {}", bounds.join(" AND "))
+        };
+
+        let totals = format!(r#"
+            SELECT player_id, SUM(value) AS total
+                FROM player_statistics
+                WHERE namespace = {} AND key = {}
+                GROUP BY player_id
+                {}
+                ORDER BY total DESC
+                LIMIT {}"#, quote(namespace), quote(stat), having, limit.min(MAX_THRESHOLD_MATCHES));
+
+        let block = self.fetch_all(&mut handle, join_usernames(&totals, "total")).await?;
+
+        let mut matches = Vec::with_capacity(block.row_count());
+        for row in block.rows() {
+            let username: String = row.get("username")?;
+            matches.push(StatThresholdMatch {
+                player: row.get("player_id")?,
+                username: if username.is_empty() { None } else { Some(username) },
+                value: row.get("total")?,
+            });
+        }
+
+        Ok(matches)
+    }
+
+    /// The fraction of players this player outranks on a stat, or `None` if they have no value for
+    /// it or are hidden from rankings. Only players who have a value for the stat count towards the total: a player who never
+    /// played the game hasn't been beaten by anyone.
+    async fn get_player_percentile(&self, player_id: &Uuid, namespace: &str, stat: &str) -> Result<Option<f64>, StatisticsDatabaseError> {
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let condition = format!("namespace = {} AND key = {}", quote(namespace), quote(stat));
+
+        let sql = format!(r#"
+            SELECT SUM(value) AS total
+                FROM player_statistics
+                WHERE {} AND player_id = '{}'{}
+                GROUP BY player_id"#, condition, player_id, hidden_players_condition(false));
+        let block = self.fetch_all(&mut handle, sql).await?;
+        let player_total: f64 = match block.rows().next() {
+            Some(row) => row.get("total")?,
+            None => return Ok(None),
+        };
+
+        let outranked = if self.stat_metadata(namespace, stat).higher_is_better { "<" } else { ">" };
+        let sql = format!(r#"
+            SELECT countIf(total {} {}) AS outranked, COUNT() AS players
+                FROM (
+                    SELECT player_id, SUM(value) AS total
+                        FROM player_statistics
+                        WHERE {}{}
+                        GROUP BY player_id
+                )"#, outranked, player_total, condition, hidden_players_condition(false));
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let row = match block.rows().next() {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let outranked: u64 = row.get("outranked")?;
+        let players: u64 = row.get("players")?;
+
+        if players == 0 {
+            return Ok(None);
+        }
+        Ok(Some(outranked as f64 / players as f64))
+    }
+
+    /// The players ranked up to `radius` places above and below a player on a stat, along with the
+    /// player's own rank. Near either end of the leaderboard the window is simply cut short. `None`
+    /// if the player has no value for the stat or is hidden from leaderboards.
+    async fn get_leaderboard_window(&mut self, namespace: &str, stat: &str, player_id: &Uuid, radius: u32) -> Result<Option<LeaderboardWindow>, StatisticsDatabaseError> {
+        let radius = u64::from(radius.min(MAX_LEADERBOARD_WINDOW_RADIUS));
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let totals = format!(r#"
+            SELECT player_id, SUM(value) AS total
+                FROM player_statistics
+                WHERE namespace = {} AND key = {}{}
+                GROUP BY player_id"#, quote(namespace), quote(stat), hidden_players_condition(false));
+
+        let sql = format!("SELECT total FROM ({}) WHERE player_id = '{}'", totals, player_id);
+        let block = self.fetch_all(&mut handle, sql).await?;
+        let player_total: f64 = match block.rows().next() {
+            Some(row) => row.get("total")?,
+            None => return Ok(None),
+        };
+
+        // Ties are broken by uuid so that the player's rank matches their place in the window.
+        let (ahead, order) = if self.stat_metadata(namespace, stat).higher_is_better { (">", "DESC") } else { ("<", "ASC") };
+        let sql = format!(r#"
+            SELECT countIf(total {ahead} {value} OR (total = {value} AND player_id < toUUID('{player}'))) AS ahead
+                FROM ({totals})"#, ahead = ahead, value = player_total, player = player_id, totals = totals);
+        let block = self.fetch_all(&mut handle, sql).await?;
+        let ahead: u64 = match block.rows().next() {
+            Some(row) => row.get("ahead")?,
+            None => 0,
+        };
+
+        let offset = ahead.saturating_sub(radius);
+        let sql = format!(r#"
+            SELECT player_id, total
+                FROM ({})
+                ORDER BY total {}, player_id
+                LIMIT {} OFFSET {}"#, totals, order, ahead - offset + radius + 1, offset);
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let mut entries = Vec::with_capacity(block.row_count());
+        for (index, row) in block.rows().enumerate() {
+            entries.push(RankedLeaderboardEntry {
+                rank: offset as usize + index + 1,
+                player: row.get("player_id")?,
+                username: None,
+                value: row.get("total")?,
+            });
+        }
+
+        let players = entries.iter().map(|entry| entry.player).collect::<Vec<_>>();
+        let mut names = self.resolve_names(&players).await
+            .unwrap_or_else(|e| {
+                warn!("Failed to get usernames for the leaderboard around {}: {}", player_id, e);
+                HashMap::new()
+            });
+        for entry in &mut entries {
+            entry.username = names.remove(&entry.player);
+        }
+
+        Ok(Some(LeaderboardWindow { rank: ahead as usize + 1, entries }))
+    }
+
+    /// The player's rank and percentile on the headline stat of each namespace they've played that
+    /// has one, like `get_player_percentile` but for every namespace in two queries. Only the first
+    /// `MAX_COMPARED_NAMESPACES` namespaces alphabetically are included, and none at all for a
+    /// player who is hidden from rankings.
+    async fn compare_namespaces(&self, player_id: &Uuid) -> Result<Vec<NamespaceStanding>, StatisticsDatabaseError> {
+        if self.config.headline_stats.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let sql = format!(r#"
+            SELECT namespace, SUM(value) AS total
+                FROM player_statistics
+                WHERE player_id = '{}' AND ({}){}
+                GROUP BY namespace
+                ORDER BY namespace
+                LIMIT {}"#, player_id, aliased_stat_condition(&self.config.headline_stats), hidden_players_condition(false), MAX_COMPARED_NAMESPACES);
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let mut standings = Vec::with_capacity(block.row_count());
+        for row in block.rows() {
+            let namespace: String = row.get("namespace")?;
+            let stat = self.config.headline_stats.get(&namespace).cloned().unwrap_or_default();
+            standings.push(NamespaceStanding { namespace, stat, value: row.get("total")?, rank: 1, percentile: 0.0 });
+        }
+        if standings.is_empty() {
+            return Ok(standings);
+        }
+
+        // Which way is better differs between namespaces, so each is compared with its own operators.
+        let mut played = HashMap::with_capacity(standings.len());
+        let mut ahead = Vec::with_capacity(standings.len());
+        let mut outranked = Vec::with_capacity(standings.len());
+        for standing in &standings {
+            let (better, worse) = if self.stat_metadata(&standing.namespace, &standing.stat).higher_is_better { (">", "<") } else { ("<", ">") };
+            let namespace = quote(&standing.namespace);
+            ahead.push(format!("namespace = {}, total {} {}", namespace, better, standing.value));
+            outranked.push(format!("namespace = {}, total {} {}", namespace, worse, standing.value));
+            played.insert(standing.namespace.clone(), standing.stat.clone());
+        }
+
+        let sql = format!(r#"
+            SELECT namespace, countIf(multiIf({}, 0)) AS ahead, countIf(multiIf({}, 0)) AS outranked, COUNT() AS players
+                FROM (
+                    SELECT namespace, player_id, SUM(value) AS total
+                        FROM player_statistics
+                        WHERE ({}){}
+                        GROUP BY namespace, player_id
+                )
+                GROUP BY namespace"#,
+            ahead.join(", "), outranked.join(", "), aliased_stat_condition(&played), hidden_players_condition(false));
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        for row in block.rows() {
+            let namespace: String = row.get("namespace")?;
+            let ahead: u64 = row.get("ahead")?;
+            let outranked: u64 = row.get("outranked")?;
+            let players: u64 = row.get("players")?;
+            if let Some(standing) = standings.iter_mut().find(|standing| standing.namespace == namespace) {
+                standing.rank = ahead as usize + 1;
+                if players > 0 {
+                    standing.percentile = outranked as f64 / players as f64;
+                }
+            }
+        }
+
+        Ok(standings)
+    }
+
+    // The caller can choose which way a leaderboard is sorted, but by default it's whichever way
+    // the stat's metadata says is better.
+    fn higher_is_better(&self, request: &GetLeaderboard) -> bool {
+        higher_is_better(&self.config, request)
+    }
+
+    /// With `restrict_to`, only those players are ranked against each other, as for a friends
+    /// leaderboard.
+    async fn get_leaderboard(&self, request: &GetLeaderboard) -> Result<LeaderboardResponse, StatisticsDatabaseError> {
+        let limit = request.limit.min(MAX_LEADERBOARD_SIZE);
+
+        if let Some(players) = &request.restrict_to {
+            check_cohort(players)?;
+            if players.is_empty() {
+                return Ok(LeaderboardResponse { entries: Vec::new(), age_seconds: None });
+            }
+        }
+
+        // Snapshots always hold the largest leaderboard we serve, so any limit can be cut from one.
+        let default_order = self.higher_is_better(request) == self.stat_metadata(&request.namespace, &request.stat).higher_is_better;
+        let snapshot = if request.rollup || request.restrict_to.is_some() || request.include_flagged || !default_order {
+            None
+        } else {
+            self.leaderboard_snapshots.get(&(request.namespace.clone(), request.stat.clone()))
+        };
+        if let Some((entries, computed_at)) = snapshot {
+            let age = SystemTime::now().duration_since(*computed_at).unwrap_or_default();
+            return Ok(LeaderboardResponse {
+                entries: entries.iter().take(limit as usize).cloned().collect(),
+                age_seconds: Some(age.as_secs()),
+            });
+        }
+
+        Ok(LeaderboardResponse {
+            entries: self.query_leaderboard(request).await?,
+            age_seconds: None,
+        })
+    }
+
+    async fn refresh_leaderboard_snapshots(&mut self) {
+        for snapshot in &self.config.leaderboard_snapshots {
+            let request = GetLeaderboard {
+                namespace: snapshot.namespace.clone(),
+                stat: snapshot.stat.clone(),
+                limit: MAX_LEADERBOARD_SIZE,
+                rollup: false,
+                restrict_to: None,
+                include_flagged: false,
+                higher_is_better: None,
+            };
+            match self.query_leaderboard(&request).await {
+                Ok(entries) => {
+                    let key = (snapshot.namespace.clone(), snapshot.stat.clone());
+                    self.leaderboard_snapshots.insert(key, (entries, SystemTime::now()));
+                }
+                // Keep serving the old snapshot; it'll be retried on the next refresh.
+                Err(e) => warn!("Failed to refresh {} leaderboard for {}: {}", snapshot.stat, snapshot.namespace, e),
+            }
+        }
+    }
+
+    /// Shrinks the configured stats of players who haven't been seen for a while, so that they
+    /// drift down leaderboards. Like other corrections, each decay is its own row against the nil
+    /// game id. Players we've never seen in a status update have no real last seen time and so
+    /// never decay.
+    async fn decay_inactive_stats(&mut self) {
+        let mut decayed = Vec::new();
+        for (namespace, decay) in &self.config.stat_decay {
+            match self.decay_namespace(namespace, decay).await {
+                Ok((players, rows)) => {
+                    info!("Decayed {} stats for {} inactive players in {}", rows, players, namespace);
+                    for stat in &decay.stats {
+                        self.leaderboard_snapshots.remove(&(namespace.clone(), stat.clone()));
+                    }
+                    decayed.push(namespace.clone());
+                }
+                Err(e) => warn!("Failed to decay inactive stats in {}: {}", namespace, e),
+            }
+        }
+
+        for namespace in decayed {
+            self.invalidate_cached_results(&namespace);
+        }
+    }
+
+    async fn decay_namespace(&self, namespace: &str, decay: &StatDecayConfig) -> Result<(usize, usize), StatisticsDatabaseError> {
+        if decay.stats.is_empty() || !(0.0..1.0).contains(&decay.factor) {
+            return Ok((0, 0));
+        }
+
+        let mut handle = self.pool.get_handle().await?;
+
+        let keys = decay.stats.iter()
+            .map(|stat| quote(stat))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sql = format!(r#"
+            SELECT player_id, key, any(type) AS type, SUM(value) AS total
+                FROM player_statistics
+                WHERE namespace = {} AND key IN ({}) AND endsWith(type, '_total') AND player_id IN (
+                    SELECT player_id
+                        FROM players
+                        GROUP BY player_id
+                        HAVING max(last_seen) > toDateTime(0) AND max(last_seen) < now() - INTERVAL {} DAY
+                )
+                GROUP BY player_id, key
+                HAVING total > 0"#, quote(namespace), keys, decay.inactive_days);
+
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let mut players = HashSet::new();
+        let mut decayed = Block::new();
+        let mut decayed_totals: HashMap<String, (String, f64)> = HashMap::new();
+        for row in block.rows() {
+            let player: Uuid = row.get("player_id")?;
+            let key: String = row.get("key")?;
+            let ty: String = row.get("type")?;
+            let total: f64 = row.get("total")?;
+
+            let amount = round_to_type(&ty, total * (1.0 - decay.factor));
+            if amount <= 0.0 {
+                continue;
+            }
+
+            decayed_totals.entry(key.clone()).or_insert_with(|| (ty.clone(), 0.0)).1 += amount;
+            decayed.push(row! {
+                game_id: Uuid::nil(),
+                player_id: player,
+                namespace: namespace.to_owned(),
+                key: key,
+                value: -amount,
+                type: ty,
+            })?;
+            players.insert(player);
+        }
+
+        let rows = decayed.row_count();
+        self.insert_block(&mut handle, "player_statistics", decayed).await?;
+
+        // Like manual adjustments, global totals that track the stat come down by as much as the
+        // players' did, so they still agree when verified.
+        if !decayed_totals.is_empty() {
+            let keys = decayed_totals.keys()
+                .map(|key| quote(key))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let sql = format!(r#"
+                SELECT DISTINCT key
+                    FROM global_statistics
+                    WHERE namespace = {} AND key IN ({}) AND endsWith(type, '_total')"#, quote(namespace), keys);
+            let block = self.fetch_all(&mut handle, sql).await?;
+
+            let mut global_stats = Block::new();
+            for row in block.rows() {
+                let key: String = row.get("key")?;
+                if let Some((ty, amount)) = decayed_totals.remove(&key) {
+                    global_stats.push(row! {
+                        game_id: Uuid::nil(),
+                        namespace: namespace.to_owned(),
+                        key: key,
+                        value: -amount,
+                        type: ty,
+                    })?;
+                }
+            }
+            self.insert_block(&mut handle, "global_statistics", global_stats).await?;
+        }
+
+        Ok((players.len(), rows))
+    }
+
+    async fn get_weighted_leaderboard(&self, namespace: &str, weights: &HashMap<String, f64>, limit: u32, rollup: bool, include_flagged: bool) -> Result<Vec<LeaderboardEntry>, StatisticsDatabaseError> {
+        if weights.is_empty() {
+            return Err(StatisticsDatabaseError::InvalidRequest("at least one stat weight is required".to_owned()));
+        }
+        if weights.values().any(|weight| !weight.is_finite()) {
+            return Err(StatisticsDatabaseError::InvalidRequest("stat weights must be finite".to_owned()));
+        }
+
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let (keys, weights): (Vec<String>, Vec<String>) = weights.iter()
+            .map(|(key, weight)| (quote(key), format!("{:?}", weight)))
+            .unzip();
+        let keys = keys.join(", ");
+        let weights = weights.join(", ");
+
+        // Stats a player doesn't have simply contribute nothing to their score.
+        let sql = format!(r#"
+            SELECT player_id, SUM(value * transform(key, [{}], [{}], 0.0)) AS score
+                FROM player_statistics
+                WHERE {} AND key IN ({}){}
+                GROUP BY player_id
+                ORDER BY score DESC
+                LIMIT {}"#, keys, weights, namespace_condition(namespace, rollup), keys, hidden_players_condition(include_flagged), limit.min(MAX_LEADERBOARD_SIZE));
+
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let mut leaderboard = Vec::with_capacity(block.row_count());
+        for row in block.rows() {
+            leaderboard.push(LeaderboardEntry {
+                player: row.get("player_id")?,
+                value: row.get("score")?,
+            });
+        }
+
+        Ok(leaderboard)
+    }
+
+    async fn get_playtime_leaderboard(&self, limit: u32, include_flagged: bool) -> Result<Vec<PlaytimeEntry>, StatisticsDatabaseError> {
+        if self.config.playtime_stats.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let cond = aliased_stat_condition(&self.config.playtime_stats);
+
+        let totals = format!(r#"
+            SELECT player_id, SUM(value) AS playtime
+                FROM player_statistics
+                WHERE ({}){}
+                GROUP BY player_id
+                ORDER BY playtime DESC
+                LIMIT {}"#, cond, hidden_players_condition(include_flagged), limit.min(MAX_LEADERBOARD_SIZE));
+
+        let block = self.fetch_all(&mut handle, join_usernames(&totals, "playtime")).await?;
+
+        let mut leaderboard = Vec::with_capacity(block.row_count());
+        for row in block.rows() {
+            let username: String = row.get("username")?;
+            leaderboard.push(PlaytimeEntry {
+                player: row.get("player_id")?,
+                username: if username.is_empty() { None } else { Some(username) },
+                playtime: row.get("playtime")?,
+            });
+        }
+
+        Ok(leaderboard)
+    }
+
+    /// A leaderboard of a network-wide stat, summing whatever each namespace aliased to it calls it.
+    async fn get_network_leaderboard(&self, stat: &str, limit: u32) -> Result<Vec<LeaderboardEntry>, StatisticsDatabaseError> {
+        let aliases = match self.config.stat_aliases.get(stat) {
+            Some(aliases) if !aliases.is_empty() => aliases,
+            _ => return Err(StatisticsDatabaseError::InvalidRequest(format!("'{}' is not a network-wide stat", stat))),
+        };
+
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let sql = format!(r#"
+            SELECT player_id, SUM(value) AS total
+                FROM player_statistics
+                WHERE ({}){}
+                GROUP BY player_id
+                ORDER BY total DESC
+                LIMIT {}"#, aliased_stat_condition(aliases), hidden_players_condition(false), limit.min(MAX_LEADERBOARD_SIZE));
+
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let mut leaderboard = Vec::with_capacity(block.row_count());
+        for row in block.rows() {
+            leaderboard.push(LeaderboardEntry {
+                player: row.get("player_id")?,
+                value: row.get("total")?,
+            });
+        }
+
+        Ok(leaderboard)
+    }
+
+    /// A player's totals for every network-wide stat they have a value for.
+    async fn get_network_stats(&self, player_id: &Uuid) -> Result<HashMap<String, f64>, StatisticsDatabaseError> {
+        let sql = match network_stats_sql(player_id, &self.config.stat_aliases) {
+            Some(sql) => sql,
+            None => return Ok(HashMap::new()),
+        };
+
+        let mut handle = self.read_pool.get_handle().await?;
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let mut stats = HashMap::with_capacity(block.row_count());
+        for row in block.rows() {
+            let stat: String = row.get("stat")?;
+            stats.insert(stat, row.get("total")?);
+        }
+
+        Ok(stats)
+    }
+
+    /// The players whose stat grew the most over the last `window`, going by when their games
+    /// were played.
+    async fn get_trending(&self, namespace: &str, stat: &str, window: Duration, limit: u32) -> Result<Vec<TrendingEntry>, StatisticsDatabaseError> {
+        if window.as_secs() == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let namespace = quote(namespace);
+        let totals = format!(r#"
+            SELECT player_id, SUM(value) AS gained
+                FROM player_statistics
+                WHERE namespace = {} AND key = {} AND game_id IN (
+                    SELECT game_id
+                        FROM games
+                        WHERE namespace = {} AND date_played >= now() - INTERVAL {} SECOND
+                ){}
+                GROUP BY player_id
+                ORDER BY gained DESC
+                LIMIT {}"#, namespace, quote(stat), namespace, window.as_secs(), hidden_players_condition(false), limit.min(MAX_LEADERBOARD_SIZE));
+
+        let block = self.fetch_all(&mut handle, join_usernames(&totals, "gained")).await?;
+
+        let mut trending = Vec::with_capacity(block.row_count());
+        for row in block.rows() {
+            let username: String = row.get("username")?;
+            trending.push(TrendingEntry {
+                player: row.get("player_id")?,
+                username: if username.is_empty() { None } else { Some(username) },
+                gained: row.get("gained")?,
+            });
+        }
+
+        Ok(trending)
+    }
+
+    /// A stat summed over every player for each day or week from `from` up to `to`, going by when
+    /// games were played. Buckets without any games are zero rather than missing, so the series
+    /// has no gaps.
+    async fn get_namespace_trend(&self, namespace: &str, stat: &str, from: DateTime<Utc>, to: DateTime<Utc>, granularity: TrendGranularity) -> Result<Vec<TrendPoint>, StatisticsDatabaseError> {
+        if from >= to {
+            return Err(StatisticsDatabaseError::InvalidRequest("the trend must end after it starts".to_owned()));
+        }
+
+        let first = granularity.bucket_start(from);
+        let step = granularity.step();
+        let points = ((to - first).num_seconds() + step.num_seconds() - 1) / step.num_seconds();
+        if points > MAX_TREND_POINTS {
+            return Err(StatisticsDatabaseError::InvalidRequest(format!("at most {} points can be requested at once", MAX_TREND_POINTS)));
+        }
+
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let bucket = match granularity {
+            TrendGranularity::Day => "toStartOfDay(games.date_played, 'UTC')",
+            TrendGranularity::Week => "toDateTime(toMonday(games.date_played, 'UTC'), 'UTC')",
+        };
+        let sql = format!(r#"
+            SELECT toUnixTimestamp({bucket}) AS bucket_start, SUM(stats.value) AS total
+                FROM player_statistics AS stats
+                INNER JOIN (
+                    SELECT game_id, date_played
+                        FROM games
+                        WHERE namespace = {namespace} AND date_played >= toDateTime({from}) AND date_played < toDateTime({to})
+                ) AS games ON stats.game_id = games.game_id
+                WHERE stats.namespace = {namespace} AND stats.key = {stat}
+                GROUP BY bucket_start"#,
+            bucket = bucket, namespace = quote(namespace), stat = quote(stat), from = from.timestamp(), to = to.timestamp());
+
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let mut totals = HashMap::with_capacity(block.row_count());
+        for row in block.rows() {
+            let bucket_start: u32 = row.get("bucket_start")?;
+            let total: f64 = row.get("total")?;
+            totals.insert(i64::from(bucket_start), total);
+        }
+
+        let mut trend = Vec::with_capacity(points as usize);
+        let mut start = first;
+        while start < to {
+            trend.push(TrendPoint {
+                start,
+                value: totals.get(&start.timestamp()).copied().unwrap_or(0.0),
+            });
+            start = start + step;
+        }
+
+        Ok(trend)
+    }
+
+    /// Ranks namespaces by how many stats their games have written within the window.
+    async fn get_namespace_activity(&self, window: Duration, limit: u32) -> Result<Vec<NamespaceActivity>, StatisticsDatabaseError> {
+        if window.as_secs() == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let sql = format!(r#"
+            SELECT namespace, COUNT() AS stats_written, uniqExact(game_id) AS games
+                FROM player_statistics
+                WHERE game_id IN (
+                    SELECT game_id
+                        FROM games
+                        WHERE date_played >= now() - INTERVAL {} SECOND
+                )
+                GROUP BY namespace
+                ORDER BY stats_written DESC
+                LIMIT {}"#, window.as_secs(), limit.min(MAX_LEADERBOARD_SIZE));
+
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let mut activity = Vec::with_capacity(block.row_count());
+        for row in block.rows() {
+            activity.push(NamespaceActivity {
+                namespace: row.get("namespace")?,
+                games: row.get("games")?,
+                stats_written: row.get("stats_written")?,
+            });
+        }
+
+        Ok(activity)
+    }
+
+    fn stat_metadata(&self, namespace: &str, key: &str) -> StatMetadata {
+        stat_metadata(&self.config, namespace, key)
+    }
+
+    /// Metadata for every stat the namespace has recorded or configured.
+    async fn get_stat_metadata(&self, namespace: &str) -> Result<HashMap<String, StatMetadata>, StatisticsDatabaseError> {
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let sql = format!("SELECT DISTINCT key FROM player_statistics WHERE namespace = {}", quote(namespace));
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let mut keys: HashSet<String> = HashSet::new();
+        for row in block.rows() {
+            keys.insert(row.get("key")?);
+        }
+        if let Some(configured) = self.config.stat_metadata.get(namespace) {
+            keys.extend(configured.keys().cloned());
+        }
+
+        Ok(keys.into_iter()
+            .map(|key| {
+                let metadata = self.stat_metadata(namespace, &key);
+                (key, metadata)
+            })
+            .collect())
+    }
+
+    async fn get_namespace_schema(&self, namespace: &str) -> Result<NamespaceSchema, StatisticsDatabaseError> {
+        Ok(NamespaceSchema {
+            players: self.sample_stat_types("player_statistics", namespace).await?,
+            global: self.sample_stat_types("global_statistics", namespace).await?,
+        })
+    }
+
+    async fn sample_stat_types(&self, table: &str, namespace: &str) -> Result<HashMap<String, StatSchema>, StatisticsDatabaseError> {
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let sql = format!(r#"
+            SELECT key, type
+                FROM (
+                    SELECT key, type
+                        FROM {}
+                        WHERE namespace = {}
+                        LIMIT {}
+                )
+                GROUP BY key, type
+                ORDER BY key, type"#, table, quote(namespace), SCHEMA_SAMPLE_SIZE);
+
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let mut schema: HashMap<String, StatSchema> = HashMap::new();
+        for row in block.rows() {
+            let key: String = row.get("key")?;
+            let ty: String = row.get("type")?;
+            schema.entry(key).or_default().types.push(ty);
+        }
+
+        for stat in schema.values_mut() {
+            stat.inconsistent = stat.types.len() > 1;
+        }
+
+        Ok(schema)
+    }
+
+    async fn list_stats(&self, namespace: &str) -> Result<Vec<NamespaceStat>, StatisticsDatabaseError> {
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let sql = format!(r#"
+            SELECT key, max(player) AS player, max(global) AS global
+                FROM (
+                    SELECT DISTINCT key, 1 AS player, 0 AS global
+                        FROM player_statistics
+                        WHERE namespace = {ns}
+                    UNION ALL
+                    SELECT DISTINCT key, 0 AS player, 1 AS global
+                        FROM global_statistics
+                        WHERE namespace = {ns}
+                )
+                GROUP BY key
+                ORDER BY key"#, ns = quote(namespace));
+
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let mut stats = Vec::with_capacity(block.row_count());
+        for row in block.rows() {
+            let player: u8 = row.get("player")?;
+            let global: u8 = row.get("global")?;
+            let scope = match (player > 0, global > 0) {
+                (true, true) => StatScope::Both,
+                (false, true) => StatScope::Global,
+                _ => StatScope::Player,
+            };
+            stats.push(NamespaceStat { name: row.get("key")?, scope });
+        }
+
+        Ok(stats)
+    }
+
+    /// Totals of a player stat in the namespace, split by the server that uploaded them.
+    async fn get_stat_by_source(&self, namespace: &str, key: &str) -> Result<HashMap<String, f64>, StatisticsDatabaseError> {
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let namespace = quote(namespace);
+
+        // Games recorded before source_server existed fall back to the channel they came from.
+        let sql = format!(r#"
+            SELECT if(games.source_server = '', games.server, games.source_server) AS source, SUM(stats.value) AS total
+                FROM (
+                    SELECT game_id, value
+                        FROM player_statistics
+                        WHERE namespace = {} AND key = {}
+                ) AS stats
+                INNER JOIN (
+                    SELECT game_id, server, source_server
+                        FROM games
+                        WHERE namespace = {}
+                ) AS games ON stats.game_id = games.game_id
+                GROUP BY source"#, namespace, quote(key), namespace);
+
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let mut totals = HashMap::with_capacity(block.row_count());
+        for row in block.rows() {
+            let source: String = row.get("source")?;
+            let total: f64 = row.get("total")?;
+            totals.insert(source, total);
+        }
+
+        Ok(totals)
+    }
+
+    /// Compares every total-type global stat in the namespace against the sum of the same stat
+    /// across all players. Other stat types (like mins and maxes) can't be derived from players.
+    async fn compare_global_stats(&self, namespace: &str) -> Result<Vec<(GlobalStatComparison, String)>, StatisticsDatabaseError> {
+        let mut handle = self.pool.get_handle().await?;
+
+        let namespace = quote(namespace);
+        let sql = format!(r#"
+            SELECT global.key AS key, global.type AS type, global.total AS global_total, players.total AS player_total
+                FROM (
+                    SELECT key, any(type) AS type, SUM(value) AS total
+                        FROM global_statistics
+                        WHERE namespace = {} AND endsWith(type, '_total')
+                        GROUP BY key
+                ) AS global
+                LEFT JOIN (
+                    SELECT key, SUM(value) AS total
+                        FROM player_statistics
+                        WHERE namespace = {}
+                        GROUP BY key
+                ) AS players ON global.key = players.key
+                ORDER BY key"#, namespace, namespace);
+
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let mut comparisons = Vec::with_capacity(block.row_count());
+        for row in block.rows() {
+            let comparison = GlobalStatComparison {
+                key: row.get("key")?,
+                global_total: row.get("global_total")?,
+                player_total: row.get("player_total")?,
+            };
+            comparisons.push((comparison, row.get("type")?));
+        }
+
+        Ok(comparisons)
+    }
+
+    async fn verify_global_consistency(&self, namespace: &str) -> Result<Vec<GlobalStatMismatch>, StatisticsDatabaseError> {
+        let comparisons = self.compare_global_stats(namespace).await?;
+
+        Ok(comparisons.into_iter()
+            .filter_map(|(comparison, _)| {
+                let difference = comparison.global_total - comparison.player_total;
+                // Sums of floats can come out slightly differently depending on the order they're added in.
+                let tolerance = 1e-9 * comparison.player_total.abs().max(1.0);
+                if difference.abs() <= tolerance {
+                    return None;
+                }
+                Some(GlobalStatMismatch {
+                    key: comparison.key,
+                    global_total: comparison.global_total,
+                    player_total: comparison.player_total,
+                    difference,
+                })
+            })
+            .collect())
+    }
+
+    /// Brings each global total back in line with the sum over players by inserting a correcting
+    /// row, since rows are never updated in place. Corrections aren't part of any real game, so
+    /// they're recorded against the nil game id.
+    async fn recompute_global_stats(&self, namespace: &str) -> Result<Vec<GlobalStatComparison>, StatisticsDatabaseError> {
+        let comparisons = self.compare_global_stats(namespace).await?;
+
+        let mut corrections = Block::new();
+        for (comparison, ty) in &comparisons {
+            let difference = comparison.player_total - comparison.global_total;
+            if difference != 0.0 {
+                corrections.push(row! {
+                    game_id: Uuid::nil(),
+                    namespace: namespace.to_owned(),
+                    key: comparison.key.clone(),
+                    value: difference,
+                    type: ty.clone(),
+                })?;
+            }
+        }
+
+        if corrections.row_count() > 0 {
+            let mut handle = self.pool.get_handle().await?;
+            self.insert_block(&mut handle, "global_statistics", corrections).await?;
+        }
+
+        for (comparison, _) in &comparisons {
+            info!("Recomputed global {} in {}: {} -> {}", comparison.key, namespace, comparison.global_total, comparison.player_total);
+        }
+
+        Ok(comparisons.into_iter().map(|(comparison, _)| comparison).collect())
+    }
+
+    /// Applies a manual correction to one player's total, returning the new total. Like global
+    /// corrections, the adjustment is its own row against the nil game id. If the namespace keeps
+    /// a global total for the stat, that gets the same adjustment so the two stay consistent.
+    async fn adjust_player_stat(&mut self, adjustment: AdjustPlayerStat) -> Result<f64, StatisticsDatabaseError> {
+        if adjustment.actor.is_empty() {
+            return Err(StatisticsDatabaseError::InvalidRequest("adjustments must say who made them".to_owned()));
+        }
+        if !adjustment.delta.is_finite() {
+            return Err(StatisticsDatabaseError::InvalidRequest("the adjustment must be a finite number".to_owned()));
+        }
+
+        let mut handle = self.pool.get_handle().await?;
+
+        let condition = format!("namespace = {} AND key = {}", quote(&adjustment.namespace), quote(&adjustment.stat));
+
+        let sql = format!(r#"
+            SELECT any(type) AS type, SUM(value) AS total
+                FROM player_statistics
+                WHERE {} AND player_id = '{}'
+                GROUP BY player_id"#, condition, adjustment.uuid);
+        let block = self.fetch_all(&mut handle, sql).await?;
+        let (ty, total): (String, f64) = match block.rows().next() {
+            Some(row) => (row.get("type")?, row.get("total")?),
+            None => ("float_total".to_owned(), 0.0),
+        };
+        if !ty.ends_with("_total") {
+            return Err(StatisticsDatabaseError::InvalidRequest(format!("'{}' is a {} stat, so it can't be adjusted", adjustment.stat, ty)));
+        }
+        let delta = adjustment_delta(&ty, adjustment.delta)?;
+
+        let mut player_stats = Block::new();
+        player_stats.push(row! {
+            game_id: Uuid::nil(),
+            player_id: adjustment.uuid,
+            namespace: adjustment.namespace.clone(),
+            key: adjustment.stat.clone(),
+            value: delta,
+            type: ty.clone(),
+        })?;
+        self.insert_block(&mut handle, "player_statistics", player_stats).await?;
+
+        let sql = format!("SELECT COUNT() AS row_count FROM global_statistics WHERE {} AND endsWith(type, '_total')", condition);
+        let block = self.fetch_all(&mut handle, sql).await?;
+        let has_global: u64 = match block.rows().next() {
+            Some(row) => row.get("row_count")?,
+            None => 0,
+        };
+        if has_global > 0 {
+            let mut global_stats = Block::new();
+            global_stats.push(row! {
+                game_id: Uuid::nil(),
+                namespace: adjustment.namespace.clone(),
+                key: adjustment.stat.clone(),
+                value: delta,
+                type: ty.clone(),
+            })?;
+            self.insert_block(&mut handle, "global_statistics", global_stats).await?;
+        }
+
+        self.leaderboard_snapshots.remove(&(adjustment.namespace.clone(), adjustment.stat.clone()));
+        self.invalidate_cached_results(&adjustment.namespace);
+
+        let new_total = round_to_type(&ty, total + delta);
+        info!(
+            "{} adjusted {} in {} for {} by {} to {}: {}",
+            adjustment.actor, adjustment.stat, adjustment.namespace, adjustment.uuid, delta, new_total, adjustment.reason
+        );
+
+        let mut fields = HashMap::new();
+        fields.insert("Player".to_owned(), adjustment.uuid.to_string());
+        fields.insert("Stat".to_owned(), format!("{} in {}", adjustment.stat, adjustment.namespace));
+        fields.insert("Change".to_owned(), format!("{} -> {} ({:+})", total, new_total, delta));
+        fields.insert("Adjusted by".to_owned(), adjustment.actor);
+        self.alerts.alert(BackendError {
+            title: "Manually adjusted a player's stat".to_owned(),
+            description: adjustment.reason,
+            fields: Some(fields),
+        }).await;
+
+        Ok(new_total)
+    }
+
+    /// What's changed in a namespace since `since`, for incremental backups: the current totals of
+    /// every player who has had a stat written since then, and of every global stat written since
+    /// then. Renamed stats count as written when they were renamed. Rows from before writes were
+    /// timestamped count as written at the epoch, so a full export is still needed to start from.
+    async fn export_changes_since(&self, namespace: &str, since: DateTime<Utc>) -> Result<ChangedStats, StatisticsDatabaseError> {
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let namespace = quote(namespace);
+        let since = since.timestamp();
+
+        let sql = format!(r#"
+            SELECT player_id, key, SUM(value) AS total
+                FROM player_statistics
+                WHERE namespace = {} AND player_id IN (
+                    SELECT DISTINCT player_id
+                        FROM player_statistics
+                        WHERE namespace = {} AND recorded_at >= toDateTime({})
+                )
+                GROUP BY player_id, key
+                ORDER BY player_id"#, namespace, namespace, since);
+
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let mut changes = ChangedStats::default();
+        for row in block.rows() {
+            push_player_stat(&mut changes.players, row.get("player_id")?, row.get("key")?, row.get("total")?);
+        }
+
+        let sql = format!(r#"
+            SELECT key, SUM(value) AS total
+                FROM global_statistics
+                WHERE namespace = {}
+                GROUP BY key
+                HAVING max(recorded_at) >= toDateTime({})"#, namespace, since);
+
+        let block = self.fetch_all(&mut handle, sql).await?;
+        for row in block.rows() {
+            changes.global.insert(row.get("key")?, row.get("total")?);
+        }
+
+        Ok(changes)
+    }
+
+    /// Row counts and sizes for every table, taken from the server's part metadata rather than by
+    /// scanning, along with how many rows each namespace has.
+    async fn get_storage_stats(&self) -> Result<StorageStats, StatisticsDatabaseError> {
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let mut storage = StorageStats {
+            tables: self.table_storage(&mut handle).await?,
+            ..StorageStats::default()
+        };
+
+        let sql = "SELECT namespace, COUNT() AS row_count FROM player_statistics GROUP BY namespace".to_owned();
+        let block = self.fetch_all(&mut handle, sql).await?;
+        for row in block.rows() {
+            let namespace: String = row.get("namespace")?;
+            storage.namespaces.entry(namespace).or_default().player_rows = row.get("row_count")?;
+        }
+
+        let sql = "SELECT namespace, COUNT() AS row_count FROM global_statistics GROUP BY namespace".to_owned();
+        let block = self.fetch_all(&mut handle, sql).await?;
+        for row in block.rows() {
+            let namespace: String = row.get("namespace")?;
+            storage.namespaces.entry(namespace).or_default().global_rows = row.get("row_count")?;
+        }
+
+        Ok(storage)
+    }
+
+    async fn table_storage(&self, handle: &mut ClientHandle) -> Result<HashMap<String, TableStorage>, StatisticsDatabaseError> {
+        let sql = r#"
+            SELECT table, SUM(rows) AS total_rows, SUM(bytes_on_disk) AS total_bytes
+                FROM system.parts
+                WHERE database = currentDatabase() AND active
+                GROUP BY table"#.to_owned();
+        let block = self.fetch_all(handle, sql).await?;
+
+        let mut tables = HashMap::with_capacity(block.row_count());
+        for row in block.rows() {
+            tables.insert(row.get("table")?, TableStorage {
+                rows: row.get("total_rows")?,
+                bytes: row.get("total_bytes")?,
+            });
+        }
+
+        Ok(tables)
+    }
+
+    /// Merges each table's parts down as far as possible, which is when rows removed by mutations
+    /// and replaced player profiles actually leave the disk. This can take a long time on big
+    /// tables. A table that can't be optimized is reported along with why, rather than stopping
+    /// the rest.
+    async fn run_maintenance(&self) -> Result<HashMap<String, TableMaintenance>, StatisticsDatabaseError> {
+        let mut handle = self.pool.get_handle().await?;
+
+        let before = self.table_storage(&mut handle).await?;
+
+        let mut errors = HashMap::new();
+        for table in before.keys() {
+            let started = Instant::now();
+            if let Err(e) = handle.execute(format!("OPTIMIZE TABLE `{}` FINAL", table)).await {
+                warn!("Failed to optimize {}: {}", table, e);
+                errors.insert(table.clone(), e.to_string());
+            }
+            self.warn_if_slow("optimize", started, || table.clone());
+        }
+
+        let mut after = self.table_storage(&mut handle).await?;
+
+        let report = before.into_iter()
+            .map(|(table, before)| {
+                let maintenance = TableMaintenance {
+                    after: after.remove(&table).unwrap_or_default(),
+                    error: errors.remove(&table),
+                    before,
+                };
+                (table, maintenance)
+            })
+            .collect();
+
+        Ok(report)
+    }
+
+    /// Moves every row of a stat over to a new name. Totals are sums over rows, so merging into a
+    /// stat that already exists needs nothing more than the rename. The updates are run as
+    /// mutations, which the server applies in the background, so old names may linger briefly.
+    async fn rename_stat(&mut self, namespace: &str, old_name: &str, new_name: &str, merge: bool) -> Result<RenamedStat, StatisticsDatabaseError> {
+        if old_name == new_name {
+            return Err(StatisticsDatabaseError::InvalidRequest("the old and new names are the same".to_owned()));
+        }
+
+        let mut handle = self.pool.get_handle().await?;
+
+        if !merge {
+            let existing = self.count_stat_rows(&mut handle, "player_statistics", namespace, new_name).await?
+                + self.count_stat_rows(&mut handle, "global_statistics", namespace, new_name).await?;
+            if existing > 0 {
+                return Err(StatisticsDatabaseError::InvalidRequest(format!(
+                    "'{}' already exists in {}, so it can only be renamed by merging", new_name, namespace
+                )));
+            }
+        }
+
+        let renamed = RenamedStat {
+            player_rows: self.count_stat_rows(&mut handle, "player_statistics", namespace, old_name).await?,
+            global_rows: self.count_stat_rows(&mut handle, "global_statistics", namespace, old_name).await?,
+        };
+
+        for table in &["player_statistics", "global_statistics"] {
+            handle.execute(format!(
+                "ALTER TABLE {} UPDATE key = {}, recorded_at = now() WHERE namespace = {} AND key = {}",
+                table, quote(new_name), quote(namespace), quote(old_name)
+            )).await?;
+        }
+
+        self.leaderboard_snapshots.remove(&(namespace.to_owned(), old_name.to_owned()));
+        self.leaderboard_snapshots.remove(&(namespace.to_owned(), new_name.to_owned()));
+        self.invalidate_cached_results(namespace);
+
+        info!(
+            "Renamed {} in {} to {} ({} player rows, {} global rows)",
+            old_name, namespace, new_name, renamed.player_rows, renamed.global_rows
+        );
+
+        Ok(renamed)
+    }
+
+    /// Sets the given stats back to zero for every player in a namespace, and for the namespace's
+    /// global stats too if `global` is set, leaving its other stats alone. Each reset is a
+    /// correction row cancelling out the current total, so the games that built it up are still
+    /// on record.
+    async fn reset_stats(&mut self, namespace: &str, stats: &[String], global: bool) -> Result<ResetStatsReport, StatisticsDatabaseError> {
+        if stats.is_empty() {
+            return Err(StatisticsDatabaseError::InvalidRequest("at least one stat to reset is required".to_owned()));
+        }
+
+        let mut handle = self.pool.get_handle().await?;
+
+        let condition = stats_condition(namespace, stats);
+
+        // Only totals can be cancelled out; a max or min would still be the same afterwards.
+        let sql = format!(r#"
+            SELECT DISTINCT key, type
+                FROM (
+                    SELECT key, type FROM player_statistics WHERE {condition}
+                    UNION ALL
+                    SELECT key, type FROM global_statistics WHERE {condition}
+                )
+                WHERE NOT endsWith(type, '_total')"#, condition = condition);
+        let block = self.fetch_all(&mut handle, sql).await?;
+        if let Some(row) = block.rows().next() {
+            let key: String = row.get("key")?;
+            let ty: String = row.get("type")?;
+            return Err(StatisticsDatabaseError::InvalidRequest(format!("'{}' is a {} stat, so it can't be reset", key, ty)));
+        }
+
+        let sql = format!(r#"
+            SELECT player_id, key, any(type) AS type, SUM(value) AS total
+                FROM player_statistics
+                WHERE {}
+                GROUP BY player_id, key
+                HAVING total != 0"#, condition);
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let mut players = HashSet::new();
+        let mut player_resets = Block::new();
+        for row in block.rows() {
+            let player: Uuid = row.get("player_id")?;
+            let ty: String = row.get("type")?;
+            let total = round_to_type(&ty, row.get("total")?);
+            player_resets.push(row! {
+                game_id: Uuid::nil(),
+                player_id: player,
+                namespace: namespace.to_owned(),
+                key: row.get::<String, _>("key")?,
+                value: -total,
+                type: ty,
+            })?;
+            players.insert(player);
+        }
+
+        let mut global_resets = Block::new();
+        if global {
+            let sql = format!(r#"
+                SELECT key, any(type) AS type, SUM(value) AS total
+                    FROM global_statistics
+                    WHERE {}
+                    GROUP BY key
+                    HAVING total != 0"#, condition);
+            let block = self.fetch_all(&mut handle, sql).await?;
+
+            for row in block.rows() {
+                let ty: String = row.get("type")?;
+                let total = round_to_type(&ty, row.get("total")?);
+                global_resets.push(row! {
+                    game_id: Uuid::nil(),
+                    namespace: namespace.to_owned(),
+                    key: row.get::<String, _>("key")?,
+                    value: -total,
+                    type: ty,
+                })?;
+            }
+        }
+
+        let report = ResetStatsReport { players: players.len(), global_stats: global_resets.row_count() };
+        self.insert_block(&mut handle, "player_statistics", player_resets).await?;
+        self.insert_block(&mut handle, "global_statistics", global_resets).await?;
+
+        for stat in stats {
+            self.leaderboard_snapshots.remove(&(namespace.to_owned(), stat.clone()));
+        }
+        self.invalidate_cached_results(namespace);
+
+        info!("Reset {} in {} for {} players and {} global stats", stats.join(", "), namespace, report.players, report.global_stats);
+
+        Ok(report)
+    }
+
+    async fn count_stat_rows(&self, handle: &mut ClientHandle, table: &str, namespace: &str, key: &str) -> Result<u64, StatisticsDatabaseError> {
+        let sql = format!("SELECT COUNT() AS row_count FROM {} WHERE namespace = {} AND key = {}", table, quote(namespace), quote(key));
+        let block = self.fetch_all(handle, sql).await?;
+        match block.rows().next() {
+            Some(row) => Ok(row.get("row_count")?),
+            None => Ok(0),
+        }
+    }
+
+    /// Gives previously rejected stats in a namespace another go, after passing each through
+    /// `transform` if given. Stats that now pass validation are added to the game they were
+    /// originally uploaded with and removed from the rejected stats; the rest stay where they are.
+    /// Recovered stats don't count towards milestones or achievements.
+    async fn replay_rejected_stats(&mut self, namespace: &str, transform: Option<StatTransform>) -> Result<ReplayReport, StatisticsDatabaseError> {
+        let mut handle = self.pool.get_handle().await?;
+
+        let sql = format!(r#"
+            SELECT rejected_at, game_id, player_id, key, value, type
+                FROM rejected_stats
+                WHERE namespace = {}"#, quote(namespace));
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let mut report = ReplayReport::default();
+        let mut player_stats = Block::new();
+        let mut global_stats = Block::new();
+        let mut recovered = Vec::new();
+        let mut replayed_stats = HashSet::new();
+
+        for row in block.rows() {
+            let rejected_at: DateTime<Tz> = row.get("rejected_at")?;
+            let game_id: Uuid = row.get("game_id")?;
+            let player_id: Uuid = row.get("player_id")?;
+            let key: String = row.get("key")?;
+            let stored_value: f64 = row.get("value")?;
+            let ty: String = row.get("type")?;
+
+            let stat = match UploadStat::from_stored(&ty, stored_value) {
+                Some(stat) => stat,
+                None => {
+                    report.still_rejected += 1;
+                    continue;
+                }
+            };
+            let (new_key, stat) = match &transform {
+                Some(transform) => transform(key.clone(), stat),
+                None => (key.clone(), stat),
+            };
+            if check_stat(&self.config, namespace, &new_key, &stat).is_some() {
+                report.still_rejected += 1;
+                continue;
+            }
+
+            let ty = stat.get_type().to_owned();
+            let value: f64 = stat.into();
+            replayed_stats.insert(new_key.clone());
+            if player_id.is_nil() {
+                global_stats.push(row! {
+                    game_id: game_id,
+                    namespace: namespace.to_owned(),
+                    key: new_key,
+                    value: value,
+                    type: ty,
+                })?;
+            } else {
+                player_stats.push(row! {
+                    game_id: game_id,
+                    player_id: player_id,
+                    namespace: namespace.to_owned(),
+                    key: new_key,
+                    value: value,
+                    type: ty,
+                })?;
+            }
+
+            recovered.push(rejected_row(rejected_at, game_id, player_id, &key, stored_value));
+            report.recovered += 1;
+        }
+
+        self.insert_block(&mut handle, "player_statistics", player_stats).await?;
+        self.insert_block(&mut handle, "global_statistics", global_stats).await?;
+
+        // Only the rows that were replayed go, so the same stat rejected again since, or rejected
+        // by another game, is left for the next replay.
+        if !recovered.is_empty() {
+            handle.execute(format!(
+                "ALTER TABLE rejected_stats DELETE WHERE namespace = {} AND ({}) IN ({})",
+                quote(namespace), REJECTED_ROW_COLUMNS, recovered.join(", ")
+            )).await?;
+        }
+
+        for stat in replayed_stats {
+            self.leaderboard_snapshots.remove(&(namespace.to_owned(), stat));
+        }
+        self.invalidate_cached_results(namespace);
+
+        info!("Replayed rejected stats in {}: {} recovered, {} still rejected", namespace, report.recovered, report.still_rejected);
+
+        Ok(report)
+    }
+
+    async fn record_rejected_stats(&self, game_id: Uuid, namespace: &str, rejected: &[RejectedStat]) -> Result<(), StatisticsDatabaseError> {
+        let mut handle = self.pool.get_handle().await?;
+
+        let rejected_at = Utc::now().with_timezone(&Tz::GMT);
+
+        let mut block = Block::with_capacity(rejected.len());
+        for stat in rejected {
+            let value: f64 = stat.stat.clone().into();
+            block.push(row! {
+                rejected_at: rejected_at,
+                game_id: game_id,
+                player_id: stat.player.unwrap_or_else(Uuid::nil),
+                namespace: namespace.to_owned(),
+                key: stat.key.clone(),
+                value: value,
+                type: stat.stat.get_type(),
+                reason: stat.reason.clone(),
+            })?;
+        }
+        self.insert_block(&mut handle, "rejected_stats", block).await?;
+
+        Ok(())
+    }
+
+    /// Works out how a bundle changes each of its stats, going by each stat's type, from what's
+    /// stored for only the stats the bundle touches. `batch` holds the state of stats changed by
+    /// earlier bundles in the same batch, which aren't in the database yet, and is updated with
+    /// this bundle's changes. Nothing is written.
+    ///
+    /// Players whose stored values can't be read are returned with the error instead of failing
+    /// the whole bundle, and none of their stats are changed.
+    #[tracing::instrument(level = "debug", skip(self, bundle, batch), fields(namespace = %bundle.namespace))]
+    async fn compute_stat_changes(&self, bundle: &GameStatsBundle, batch: &mut HashMap<(String, Option<Uuid>, String), StoredStat>) -> Result<(Vec<StatChange>, Vec<(Uuid, String)>), StatisticsDatabaseError> {
+        let (stored, unreadable) = self.read_stored_stats(bundle, batch).await?;
+        Ok(apply_stored_stats(bundle, &stored, unreadable, batch))
+    }
+
+    /// Reads what's stored for the stats a bundle touches, other than those already in `batch`,
+    /// along with the players whose stored values can't be read.
+    async fn read_stored_stats(&self, bundle: &GameStatsBundle, batch: &HashMap<(String, Option<Uuid>, String), StoredStat>) -> Result<(HashMap<(Option<Uuid>, String), StoredStat>, HashMap<Uuid, String>), StatisticsDatabaseError> {
+        let namespace = &bundle.namespace;
+        let unseen = |player: Option<Uuid>, key: &String| !batch.contains_key(&(namespace.clone(), player, key.clone()));
+
+        let player_pairs = bundle.stats.players.iter()
+            .flat_map(|(player, stats)| stats.keys().map(move |key| (*player, key)))
+            .filter(|(player, key)| unseen(Some(*player), key))
+            .map(|(player, key)| format!("(toUUID('{}'), {})", player, quote(key)))
+            .collect::<Vec<_>>();
+        let global_keys = bundle.stats.global.iter()
+            .flat_map(|stats| stats.keys())
+            .filter(|key| unseen(None, key))
+            .map(|key| quote(key))
+            .collect::<Vec<_>>();
+
+        let mut stored = HashMap::new();
+        let mut unreadable = HashMap::new();
+        if !player_pairs.is_empty() || !global_keys.is_empty() {
+            let mut handle = self.pool.get_handle().await?;
+
+            if !player_pairs.is_empty() {
+                let sql = format!(r#"
+                    SELECT player_id, key, {}
+                        FROM player_statistics
+                        WHERE namespace = {} AND (player_id, key) IN ({})
+                        GROUP BY player_id, key"#, STORED_STAT_COLUMNS, quote(namespace), player_pairs.join(", "));
+                let block = self.fetch_all(&mut handle, sql).await?;
+                for row in block.rows() {
+                    let player: Uuid = row.get("player_id")?;
+                    let stat = row.get::<String, _>("key")
+                        .map_err(StatisticsDatabaseError::from)
+                        .and_then(|key| Ok((key, stored_stat(&row)?)));
+                    match stat {
+                        Ok((key, stat)) => {
+                            stored.insert((Some(player), key), stat);
+                        }
+                        Err(e) => {
+                            unreadable.entry(player).or_insert_with(|| e.to_string());
+                        }
+                    }
+                }
+            }
+
+            if !global_keys.is_empty() {
+                let sql = format!(r#"
+                    SELECT key, {}
+                        FROM global_statistics
+                        WHERE namespace = {} AND key IN ({})
+                        GROUP BY key"#, STORED_STAT_COLUMNS, quote(namespace), global_keys.join(", "));
+                let block = self.fetch_all(&mut handle, sql).await?;
+                for row in block.rows() {
+                    let key: String = row.get("key")?;
+                    stored.insert((None, key), stored_stat(&row)?);
+                }
+            }
+        }
+
+        Ok((stored, unreadable))
+    }
+
+    fn publish_stat_changes(&mut self, namespace: &str, changes: Vec<StatChange>) {
+        for changed in number_stat_changes(namespace, changes, &mut self.next_stat_change_id) {
+            if self.recent_stat_changes.len() >= STAT_CHANGE_CAPACITY {
+                self.recent_stat_changes.pop_front();
+            }
+            self.recent_stat_changes.push_back(changed.clone());
+
+            // An error here only means nobody is currently subscribed.
+            let _ = self.stat_changes.send(changed);
+        }
+    }
+
+    fn detect_milestones(&self, server: &str, namespace: &str, changes: &[StatChange]) -> Vec<MilestoneReached> {
+        let milestones = match self.config.milestones.get(namespace) {
+            Some(milestones) => milestones,
+            None => return Vec::new(),
+        };
+
+        let mut reached = Vec::new();
+        for change in changes {
+            if let (Some(player), Some(thresholds)) = (change.player, milestones.get(&change.key)) {
+                // A single bundle can cross several milestones at once, so announce them in order.
+                let mut crossed: Vec<f64> = thresholds.iter().copied()
+                    .filter(|&threshold| change.old_value < threshold && threshold <= change.new_value)
+                    .collect();
+                crossed.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+
+                for milestone in crossed {
+                    reached.push(MilestoneReached {
+                        channel: server.to_owned(),
+                        player,
+                        namespace: namespace.to_owned(),
+                        stat: change.key.clone(),
+                        milestone,
+                    });
+                }
+            }
+        }
+
+        reached
+    }
+
+    fn find_met_achievements(&self, namespace: &str, changes: &[StatChange]) -> Vec<(Uuid, &AchievementConfig)> {
+        let mut met = Vec::new();
+        for change in changes {
+            if let Some(player) = change.player {
+                let achievements = self.config.achievements.iter()
+                    .filter(|achievement| achievement.namespace == namespace && achievement.stat == change.key)
+                    .filter(|achievement| change.old_value < achievement.threshold && achievement.threshold <= change.new_value);
+                for achievement in achievements {
+                    met.push((player, achievement));
+                }
+            }
+        }
+        met
+    }
+
+    /// Records unlocks for any of the given achievements that the players don't already have,
+    /// returning only the newly unlocked ones.
+    async fn unlock_achievements<'a>(&self, met: Vec<(Uuid, &'a AchievementConfig)>) -> Result<Vec<(Uuid, &'a AchievementConfig)>, StatisticsDatabaseError> {
+        if met.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut handle = self.pool.get_handle().await?;
+
+        let players = met.iter()
+            .map(|(player, _)| format!("'{}'", player))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sql = format!("SELECT player_id, achievement FROM player_achievements WHERE player_id IN ({})", players);
+
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let mut unlocked: HashSet<(Uuid, String)> = HashSet::new();
+        for row in block.rows() {
+            unlocked.insert((row.get("player_id")?, row.get("achievement")?));
+        }
+
+        let unlocked_at = Utc::now().with_timezone(&Tz::GMT);
+
+        let mut block = Block::new();
+        let mut newly_unlocked = Vec::new();
+        for (player, achievement) in met {
+            // Also skips an achievement that's met twice within one batch.
+            if !unlocked.insert((player, achievement.id.clone())) {
+                continue;
+            }
+            block.push(row! {
+                player_id: player,
+                achievement: achievement.id.clone(),
+                unlocked_at: unlocked_at,
+            })?;
+            newly_unlocked.push((player, achievement));
+        }
+
+        if block.row_count() > 0 {
+            self.insert_block(&mut handle, "player_achievements", block).await?;
+        }
+
+        Ok(newly_unlocked)
+    }
+
+    async fn get_player_achievements(&self, player_id: &Uuid) -> Result<Vec<PlayerAchievement>, StatisticsDatabaseError> {
+        let mut handle = self.read_pool.get_handle().await?;
+
+        let sql = format!(r#"
+            SELECT achievement, min(unlocked_at) AS unlocked_at
+                FROM player_achievements
+                WHERE player_id = '{}'
+                GROUP BY achievement
+                ORDER BY unlocked_at ASC"#, player_id);
+
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let mut achievements = Vec::with_capacity(block.row_count());
+        for row in block.rows() {
+            let id: String = row.get("achievement")?;
+            let unlocked_at: DateTime<Tz> = row.get("unlocked_at")?;
+            let name = self.config.achievements.iter()
+                .find(|achievement| achievement.id == id)
+                .map(|achievement| achievement.name.clone());
+            achievements.push(PlayerAchievement {
+                id,
+                name,
+                unlocked_at: unlocked_at.with_timezone(&Utc),
+            });
+        }
+
+        Ok(achievements)
+    }
+
+    /// Bundles whose current values can't be read, or that the database won't take, are left out
+    /// and returned with the error, so that one bad bundle doesn't stop the rest of the batch being
+    /// written. Likewise, players whose current values can't be read are left out of their bundle.
+    ///
+    /// A bundle that failed after some of its stats were written is returned both ways: as
+    /// uploaded, with just the changes that were written, and as failed.
+    #[tracing::instrument(level = "debug", skip(self, uploads), fields(bundles = uploads.len()))]
+    async fn upload_stats_bundles(&self, server: &str, uploads: &[GameStatsUpload]) -> (Vec<UploadedBundle>, Vec<FailedBundle>) {
+        let date_played = Utc::now().with_timezone(&Tz::GMT);
+
+        // Bundles earlier in a batch change the values that later bundles see, but none of them are
+        // in the database yet when we read the current values.
+        let mut batch = HashMap::new();
+        // What was stored before the batch, for working its changes out again if some of it fails.
+        let mut stored_before: HashMap<String, HashMap<(Option<Uuid>, String), StoredStat>> = HashMap::new();
+
+        let mut pending = Vec::with_capacity(uploads.len());
+        let mut failed = Vec::new();
+
+        for GameStatsUpload { game_id, bundle } in uploads {
+            let game_id = *game_id;
+
+            // Inserts don't tell us anything about the values they affect, so read the current
+            // values of everything in the bundle up front, in a single grouped query per table.
+            let (stored, unreadable) = match self.read_stored_stats(bundle, &batch).await {
+                Ok(stored) => stored,
+                Err(e) => {
+                    failed.push(FailedBundle { game_id, error: e, written: Vec::new() });
+                    continue;
+                }
+            };
+            let (changes, failed_players) = apply_stored_stats(bundle, &stored, unreadable.clone(), &mut batch);
+            stored_before.entry(bundle.namespace.clone()).or_default().extend(stored);
+
+            let skipped = failed_players.iter().map(|(player, _)| *player).collect();
+            let rows = BundleRows::new(game_id, server, bundle, &skipped, date_played);
+            let uploaded = UploadedBundle { game_id, namespace: bundle.namespace.clone(), changes, failed_players };
+            pending.push((uploaded, rows, bundle, unreadable));
+        }
+
+        // Each table is written for every bundle at once. If the database won't take that, the
+        // bundles are written one at a time instead, so that only the ones it still won't take
+        // fail, and those aren't written to any later table. Any bundle with stats written is
+        // marked as applied, even if the rest of it failed, since sending it again would count
+        // those stats twice.
+        let mut errors: Vec<Option<StatisticsDatabaseError>> = pending.iter().map(|_| None).collect();
+        let mut written: Vec<Vec<BundleTable>> = pending.iter().map(|_| Vec::new()).collect();
+        for &table in &[BundleTable::Games, BundleTable::PlayerStatistics, BundleTable::GlobalStatistics, BundleTable::AppliedBundles] {
+            let remaining = (0..pending.len())
+                .filter(|&i| match table {
+                    BundleTable::AppliedBundles => wrote_stats(&written[i]),
+                    _ => errors[i].is_none(),
+                })
+                .collect::<Vec<_>>();
+            if remaining.is_empty() {
+                continue;
+            }
+
+            let bundles = remaining.iter().map(|&i| &pending[i].1).collect::<Vec<_>>();
+            let error = match self.insert_bundle_rows(table, &bundles).await {
+                Ok(()) => {
+                    for &i in &remaining {
+                        pending[i].1.record_written(table, &mut written[i]);
+                    }
+                    continue;
+                }
+                Err(e) => e,
+            };
+
+            // The first error a bundle runs into is the one that failed it.
+            if remaining.len() == 1 {
+                errors[remaining[0]].get_or_insert(error);
+                continue;
+            }
+
+            warn!("Failed to write {} bundles into {} together, so writing them one at a time: {}", remaining.len(), table.name(), error);
+            for i in remaining {
+                match self.insert_bundle_rows(table, &[&pending[i].1]).await {
+                    Ok(()) => pending[i].1.record_written(table, &mut written[i]),
+                    Err(e) => {
+                        errors[i].get_or_insert(e);
+                    }
+                }
+            }
+        }
+
+        // The changes of bundles after one that failed were worked out on top of the failed bundle's,
+        // which never made it in.
+        if errors.iter().any(Option::is_some) {
+            let bundles = pending.iter().zip(&written)
+                .map(|((_, _, bundle, unreadable), written)| (*bundle, unreadable, written.as_slice()))
+                .collect::<Vec<_>>();
+            let changes = written_stat_changes(&bundles, &stored_before);
+            for ((uploaded, ..), changes) in pending.iter_mut().zip(changes) {
+                uploaded.changes = changes;
+            }
+        }
+
+        let mut uploaded = Vec::with_capacity(pending.len());
+        for (((bundle, ..), error), written) in pending.into_iter().zip(errors).zip(written) {
+            let game_id = bundle.game_id;
+            if wrote_stats(&written) || error.is_none() {
+                uploaded.push(bundle);
+            }
+            if let Some(error) = error {
+                failed.push(FailedBundle { game_id, error, written });
+            }
+        }
+
+        (uploaded, failed)
+    }
+
+    async fn insert_bundle_rows(&self, table: BundleTable, bundles: &[&BundleRows]) -> Result<(), StatisticsDatabaseError> {
+        let block = bundle_rows_block(table, bundles)?;
+        let mut handle = self.pool.get_handle().await?;
+        self.insert_block(&mut handle, table.name(), block).await
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, bundle_ids), fields(table = "applied_bundles", bundles = bundle_ids.len()))]
+    async fn find_applied_bundles(&self, bundle_ids: &[Uuid]) -> Result<HashSet<Uuid>, StatisticsDatabaseError> {
+        if bundle_ids.is_empty() {
+            return Ok(HashSet::new());
+        }
+
+        let mut handle = self.pool.get_handle().await?;
+
+        let bundle_ids = bundle_ids.iter()
+            .map(|bundle_id| format!("'{}'", bundle_id))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sql = format!("SELECT bundle_id FROM applied_bundles WHERE bundle_id IN ({})", bundle_ids);
+
+        let block = self.fetch_all(&mut handle, sql).await?;
+
+        let mut applied = HashSet::new();
+        for row in block.rows() {
+            let bundle_id: Uuid = row.get("bundle_id")?;
+            applied.insert(bundle_id);
+        }
+
+        Ok(applied)
+    }
+
+    /// Each namespace has its own bucket, so a namespace uploading too often never holds up others.
+    async fn take_upload_token(&mut self, server: &str, correlation_id: &str, namespace: &str) -> bool {
+        let limit = self.config.namespace_upload_rate_limits.get(namespace)
+            .or_else(|| self.config.upload_rate_limit.as_ref());
+        let limit = match limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+
+        let bucket = self.upload_buckets.entry(namespace.to_owned())
+            .or_insert_with(|| TokenBucket::new(limit));
+        if bucket.try_take() {
+            self.throttled_namespaces.remove(namespace);
+            return true;
+        }
+
+        if self.throttled_namespaces.insert(namespace.to_owned()) {
+            warn!("[{}] Rate limiting stats uploads for {} from '{}'", correlation_id, namespace, server);
+
+            let mut fields = HashMap::new();
+            fields.insert("Server".to_owned(), server.to_owned());
+            fields.insert("Correlation ID".to_owned(), correlation_id.to_owned());
+            fields.insert("Limit".to_owned(), format!("{} per minute (burst of {})", limit.per_minute, limit.burst));
+            self.alerts.alert(BackendError {
+                title: format!("Rate limiting stats uploads for {}", namespace),
+                description: "Bundles over the limit are being rejected".to_owned(),
+                fields: Some(fields),
+            }).await;
+        }
+
+        false
+    }
+
+    async fn report_oversized_bundle(&self, server: &str, correlation_id: &str, upload: &GameStatsUpload, error: &str) {
+        warn!("[{}] Rejected stats bundle {} from '{}': {}", correlation_id, upload.game_id, server, error);
+
+        let mut fields = HashMap::new();
+        fields.insert("Server".to_owned(), server.to_owned());
+        fields.insert("Correlation ID".to_owned(), correlation_id.to_owned());
+        fields.insert("Namespace".to_owned(), upload.bundle.namespace.clone());
+        fields.insert("Game".to_owned(), upload.game_id.to_string());
+        self.alerts.alert(BackendError {
+            title: "Rejected an oversized stats bundle".to_owned(),
+            description: error.to_owned(),
+            fields: Some(fields),
+        }).await;
+    }
+
+    async fn report_lost_connection(&self, server: &str, correlation_id: &str, report: &BundleUploadReport) {
+        let mut fields = HashMap::new();
+        fields.insert("Server".to_owned(), server.to_owned());
+        fields.insert("Game".to_owned(), report.game_id.to_string());
+        fields.insert("Correlation ID".to_owned(), correlation_id.to_owned());
+
+        self.alerts.alert(BackendError {
+            title: "Lost connection to the statistics database".to_owned(),
+            description: report.error.clone().unwrap_or_default(),
+            fields: Some(fields),
+        }).await;
+    }
+
+    /// Moves every stat of a bundle the database couldn't take into the rejected stats, where it
+    /// can be replayed once whatever was wrong with it is fixed.
+    /// Takes the bundle as it was validated, so stats that were already rejected aren't recorded
+    /// a second time.
+    async fn quarantine_bundle(&self, correlation_id: &str, upload: &GameStatsUpload, reason: &str) {
+        let GameStatsUpload { game_id, bundle } = upload;
+
+        let mut rejected = Vec::new();
+        for (player, stats) in &bundle.stats.players {
+            for (key, stat) in stats {
+                rejected.push(RejectedStat { player: Some(*player), key: key.clone(), stat: stat.clone(), reason: reason.to_owned() });
+            }
+        }
+        for (key, stat) in bundle.stats.global.iter().flatten() {
+            rejected.push(RejectedStat { player: None, key: key.clone(), stat: stat.clone(), reason: reason.to_owned() });
+        }
+
+        if let Err(e) = self.record_rejected_stats(*game_id, &bundle.namespace, &rejected).await {
+            warn!("[{}] Failed to quarantine stats bundle {}: {}", correlation_id, game_id, e);
+        }
+    }
+
+    /// Deals with a bundle that got as far as being written but failed, depending on what sort of
+    /// error failed it.
+    async fn handle_failed_upload(&self, server: &str, correlation_id: &str, report: &BundleUploadReport, upload: &GameStatsUpload) {
+        match failed_upload_action(report) {
+            Some(FailedUploadAction::Retry) => {
+                warn!("[{}] Stats bundle {} from '{}' timed out and can be sent again: {:?}", correlation_id, report.game_id, server, report);
+            }
+            Some(FailedUploadAction::Alert) => {
+                warn!("[{}] Lost connection to the statistics database uploading bundle {} from '{}': {:?}", correlation_id, report.game_id, server, report);
+                self.report_lost_connection(server, correlation_id, report).await;
+            }
+            Some(FailedUploadAction::Quarantine) => {
+                warn!("[{}] Quarantining stats bundle {} from '{}': {:?}", correlation_id, report.game_id, server, report);
+                self.quarantine_bundle(correlation_id, upload, report.error.as_deref().unwrap_or_default()).await;
+            }
+            Some(FailedUploadAction::Log) => {
+                warn!("[{}] Failed to upload stats bundle {} from '{}': {:?}", correlation_id, report.game_id, server, report);
+            }
+            None => {}
+        }
+    }
+
+    async fn report_unsupported_bundle(&self, server: &str, correlation_id: &str, upload: &GameStatsUpload, error: &str) {
+        warn!("[{}] Rejected stats bundle {} from '{}': {}", correlation_id, upload.game_id, server, error);
+
+        let mut fields = HashMap::new();
+        fields.insert("Server".to_owned(), server.to_owned());
+        fields.insert("Correlation ID".to_owned(), correlation_id.to_owned());
+        fields.insert("Namespace".to_owned(), upload.bundle.namespace.clone());
+        fields.insert("Game".to_owned(), upload.game_id.to_string());
+        self.alerts.alert(BackendError {
+            title: "Rejected stats bundle with an unsupported version".to_owned(),
+            description: error.to_owned(),
+            fields: Some(fields),
+        }).await;
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, uploads), fields(bundles = uploads.len()))]
+    async fn process_uploads(&mut self, server: &str, correlation_id: &str, uploads: Vec<GameStatsUpload>) -> Vec<BundleUploadReport> {
+        let bundle_ids: Vec<Uuid> = uploads.iter()
+            .filter_map(|upload| upload.bundle.bundle_id)
+            .collect();
+
+        // If we can't tell whether a bundle was already applied, it's safer not to apply it at all
+        // than to risk counting it twice. The server is free to retry.
+        let mut applied = match self.find_applied_bundles(&bundle_ids).await {
+            Ok(applied) => applied,
+            Err(e) => {
+                warn!("[{}] Failed to check for already applied bundles from '{}': {}", correlation_id, server, e);
+                return uploads.iter()
+                    .map(|upload| {
+                        let mut report = BundleUploadReport::new(upload.game_id);
+                        fail_report(&mut report, &e);
+                        report
+                    })
+                    .collect();
+            }
+        };
+
+        let mut reports = Vec::with_capacity(uploads.len());
+        let mut accepted = Vec::with_capacity(uploads.len());
+        let mut rejected_counts = Vec::with_capacity(uploads.len());
+
+        for mut upload in uploads {
+            if let Some(bundle_id) = already_applied(&applied, &upload.bundle) {
+                info!("[{}] Skipping stats bundle {} from '{}' as it was already applied", correlation_id, bundle_id, server);
+                reports.push(BundleUploadReport { already_applied: true, ..BundleUploadReport::new(upload.game_id) });
+                continue;
+            }
+
+            if let Err(error) = upload.bundle.migrate() {
+                self.report_unsupported_bundle(server, correlation_id, &upload, &error).await;
+                reports.push(BundleUploadReport::failed(upload.game_id, error));
+                continue;
+            }
+
+            // Nothing from an oversized bundle is written, since it's probably not a real game.
+            if let Err(error) = check_bundle_size(&self.config, &upload.bundle) {
+                self.report_oversized_bundle(server, correlation_id, &upload, &error).await;
+                reports.push(BundleUploadReport::failed(upload.game_id, error));
+                continue;
+            }
+
+            if !self.take_upload_token(server, correlation_id, &upload.bundle.namespace).await {
+                reports.push(BundleUploadReport::failed(upload.game_id, "rate limited".to_owned()));
+                continue;
+            }
+
+            let rejected = reject_invalid_stats(&self.config, &mut upload.bundle);
+            log_rejected_stats(server, correlation_id, &upload.bundle.namespace, &rejected);
+
+            if let (Some(webhook), false) = (&self.webhook, rejected.is_empty()) {
+                webhook.emit(WebhookEvent::StatsRejected {
+                    server: server.to_owned(),
+                    namespace: upload.bundle.namespace.clone(),
+                    game_id: upload.game_id,
+                    stats: rejected.iter()
+                        .map(|stat| (stat.player, stat.key.clone(), stat.reason.clone()))
+                        .collect(),
+                });
+            }
+
+            if !rejected.is_empty() && self.config.record_rejected_stats {
+                if let Err(e) = self.record_rejected_stats(upload.game_id, &upload.bundle.namespace, &rejected).await {
+                    warn!("[{}] Failed to record rejected stats for bundle {}: {}", correlation_id, upload.game_id, e);
+                }
+            }
+
+            // Only now, so that a copy of a bundle that was turned away can still be applied.
+            mark_applied(&mut applied, &upload.bundle);
+            rejected_counts.push(rejected.len());
+            accepted.push(upload);
+        }
+
+        self.drop_already_set_stats(correlation_id, &mut accepted).await;
+
+        // Only counted now, so that set-once stats that were dropped aren't reported as applied.
+        let mut accepted_reports = accepted.iter().zip(rejected_counts)
+            .map(|(upload, rejected)| BundleUploadReport::accepted(upload.game_id, &upload.bundle, rejected))
+            .collect::<Vec<_>>();
+
+        let (uploaded, failed) = self.upload_stats_bundles(server, &accepted).await;
+        for bundle in failed {
+            let upload = accepted.iter().find(|upload| upload.game_id == bundle.game_id);
+            let report = accepted_reports.iter_mut().find(|report| report.game_id == bundle.game_id);
+            if let (Some(upload), Some(report)) = (upload, report) {
+                fail_written_report(report, &upload.bundle, &bundle);
+            }
+        }
+
+        for bundle in &uploaded {
+            if bundle.failed_players.is_empty() {
+                continue;
+            }
+            warn!("[{}] Left {} players out of stats bundle {} from '{}' as their stats couldn't be read, such as {}: {}",
+                correlation_id, bundle.failed_players.len(), bundle.game_id, server, bundle.failed_players[0].0, bundle.failed_players[0].1);
+
+            let upload = accepted.iter().find(|upload| upload.game_id == bundle.game_id);
+            let report = accepted_reports.iter_mut().find(|report| report.game_id == bundle.game_id);
+            if let (Some(upload), Some(report)) = (upload, report) {
+                record_player_errors(report, &upload.bundle, &bundle.failed_players);
+            }
+        }
+
+        let mut met_achievements = Vec::new();
+        let mut written = Vec::with_capacity(uploaded.len());
+        for bundle in uploaded {
+            let milestones = self.detect_milestones(server, &bundle.namespace, &bundle.changes);
+            for milestone in milestones {
+                if let Some(webhook) = &self.webhook {
+                    webhook.emit(WebhookEvent::MilestoneReached {
+                        server: server.to_owned(),
+                        player: milestone.player,
+                        namespace: milestone.namespace.clone(),
+                        stat: milestone.stat.clone(),
+                        milestone: milestone.milestone,
+                    });
+                }
+                let _ = self.controller.do_send_async(milestone).await;
+            }
+
+            met_achievements.extend(self.find_met_achievements(&bundle.namespace, &bundle.changes));
+            written.push((bundle.namespace, bundle.changes));
+        }
+
+        match self.unlock_achievements(met_achievements).await {
+            Ok(unlocked) => {
+                for (player, achievement) in unlocked {
+                    let _ = self.controller.do_send_async(AchievementUnlocked {
+                        channel: server.to_owned(),
+                        player,
+                        achievement: achievement.name.clone(),
+                    }).await;
+                }
+            }
+            Err(e) => warn!("[{}] Failed to unlock achievements from '{}': {}", correlation_id, server, e),
+        }
+
+        // Met achievements borrow from the config, so these have to wait until they're done with.
+        for (namespace, changes) in written {
+            self.invalidate_cached_results(&namespace);
+            self.publish_stat_changes(&namespace, changes);
+        }
+
+        for (report, upload) in accepted_reports.iter().zip(&accepted) {
+            self.handle_failed_upload(server, correlation_id, report, upload).await;
+        }
+
+        reports.extend(accepted_reports);
+
+        if let Some(webhook) = &self.webhook {
+            for report in &reports {
+                webhook.emit(WebhookEvent::BundleProcessed {
+                    server: server.to_owned(),
+                    report: report.clone(),
+                });
+            }
+        }
+
+        reports
+    }
+
+    /// Set-once stats keep the first value they were given, so any values for ones that are already
+    /// set, whether stored or earlier in the same batch, are dropped. If we can't tell which are
+    /// set, none of them are applied rather than risk overwriting one.
+    async fn drop_already_set_stats(&self, correlation_id: &str, uploads: &mut [GameStatsUpload]) {
+        let has_set_once = uploads.iter().any(|upload| {
+            let stats = &upload.bundle.stats;
+            stats.players.values().flat_map(|stats| stats.values())
+                .chain(stats.global.iter().flat_map(|stats| stats.values()))
+                .any(|stat| stat.is_set_once())
+        });
+        if !has_set_once {
+            return;
+        }
+
+        let (player_stats, global_stats) = match self.find_set_once_stats(uploads).await {
+            Ok(set) => set,
+            Err(e) => {
+                warn!("[{}] Failed to look up which set-once stats are already set, so dropping them: {}", correlation_id, e);
+                for upload in uploads.iter_mut() {
+                    let stats = &mut upload.bundle.stats;
+                    for stats in stats.players.values_mut().chain(stats.global.iter_mut()) {
+                        stats.retain(|_, stat| !stat.is_set_once());
+                    }
+                }
+                return;
+            }
+        };
+
+        drop_set_stats(uploads, player_stats, global_stats);
+    }
+
+    async fn find_set_once_stats(&self, uploads: &[GameStatsUpload]) -> Result<(HashSet<(Uuid, String, String)>, HashSet<(String, String)>), StatisticsDatabaseError> {
+        let mut namespaces = HashSet::new();
+        let mut players = HashSet::new();
+        for upload in uploads {
+            namespaces.insert(quote(&upload.bundle.namespace));
+            for player in upload.bundle.stats.players.keys() {
+                players.insert(format!("'{}'", player));
+            }
+        }
+        let namespaces = namespaces.into_iter().collect::<Vec<_>>().join(", ");
+
+        let mut handle = self.pool.get_handle().await?;
+
+        let mut player_stats = HashSet::new();
+        if !players.is_empty() {
+            let sql = format!(r#"
+                SELECT DISTINCT player_id, namespace, key
+                    FROM player_statistics
+                    WHERE endsWith(type, '_first') AND namespace IN ({}) AND player_id IN ({})"#,
+                namespaces, players.into_iter().collect::<Vec<_>>().join(", "));
+            let block = self.fetch_all(&mut handle, sql).await?;
+            for row in block.rows() {
+                player_stats.insert((row.get("player_id")?, row.get("namespace")?, row.get("key")?));
+            }
+        }
+
+        let sql = format!(r#"
+            SELECT DISTINCT namespace, key
+                FROM global_statistics
+                WHERE endsWith(type, '_first') AND namespace IN ({})"#, namespaces);
+        let block = self.fetch_all(&mut handle, sql).await?;
+        let mut global_stats = HashSet::new();
+        for row in block.rows() {
+            global_stats.insert((row.get("namespace")?, row.get("key")?));
+        }
+
+        Ok((player_stats, global_stats))
+    }
+
+    /// Checks a bundle in the same order as a real upload, other than not being rate limited.
+    async fn simulate_upload(&self, server: &str, correlation_id: &str, upload: GameStatsUpload) -> BundleUploadReport {
+        let GameStatsUpload { game_id, mut bundle } = upload;
+
+        if let Some(bundle_id) = bundle.bundle_id {
+            match self.find_applied_bundles(&[bundle_id]).await {
+                Ok(applied) if already_applied(&applied, &bundle).is_some() => {
+                    info!("[{}] dry run of stats bundle {} from '{}': already applied, would be skipped", correlation_id, bundle_id, server);
+                    return BundleUploadReport { already_applied: true, ..BundleUploadReport::new(game_id) };
+                }
+                Ok(_) => (),
+                Err(e) => warn!("[{}] Failed to check whether stats bundle {} was already applied: {}", correlation_id, bundle_id, e),
+            }
+        }
+
+        if let Err(error) = bundle.migrate() {
+            info!("[{}] dry run of stats bundle {} from '{}': {}", correlation_id, game_id, server, error);
+            return BundleUploadReport::failed(game_id, error);
+        }
+
+        if let Err(error) = check_bundle_size(&self.config, &bundle) {
+            info!("[{}] dry run of stats bundle {} from '{}': {}", correlation_id, game_id, server, error);
+            return BundleUploadReport::failed(game_id, error);
+        }
+
+        let rejected = reject_invalid_stats(&self.config, &mut bundle);
+        log_rejected_stats(server, correlation_id, &bundle.namespace, &rejected);
+
+        match self.compute_stat_changes(&bundle, &mut HashMap::new()).await {
+            Ok((changes, failed_players)) => {
+                info!("[{}] dry run of {} stats bundle {} from '{}' would make {} changes",
+                    correlation_id, bundle.namespace, game_id, server, changes.len());
+                for change in &changes {
+                    match change.player {
+                        Some(player) => info!("  {} {}: {} -> {}", player, change.key, change.old_value, change.new_value),
+                        None => info!("  global {}: {} -> {}", change.key, change.old_value, change.new_value),
+                    }
+                }
+                let mut report = BundleUploadReport {
+                    changes: Some(changes),
+                    ..BundleUploadReport::accepted(game_id, &bundle, rejected.len())
+                };
+                record_player_errors(&mut report, &bundle, &failed_players);
+                report
+            }
+            Err(e) => BundleUploadReport::failed(game_id, e.to_string()),
+        }
+    }
+}
+
+/// Looks up usernames for players who have stats but have never been seen in a status update, a
+/// batch at a time. Players the resolver doesn't know or fails on are recorded so that later
+/// backfills skip them until it's time to retry, while players left over from being rate limited
+/// are picked up by the next backfill. This waits between lookups, so it runs off the actor.
+async fn run_username_backfill(pool: Pool, resolver: Arc<UsernameResolver>) -> Result<UsernameBackfillReport, StatisticsDatabaseError> {
+    let mut handle = pool.get_handle().await?;
+
+    let sql = format!(r#"
+        SELECT DISTINCT player_id
+            FROM player_statistics
+            WHERE player_id NOT IN (SELECT player_id FROM players)
+                AND player_id NOT IN (
+                    SELECT player_id
+                        FROM username_lookups
+                        GROUP BY player_id
+                        HAVING max(lookup_failed_at) > now() - INTERVAL {} SECOND
+                )
+            LIMIT {}"#, resolver.retry_failed_after().as_secs(), resolver.batch_size());
+
+    let block = handle.query(&sql).fetch_all().await?;
+    let players = block.rows()
+        .map(|row| row.get::<Uuid, _>("player_id"))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let lookup = resolve_batch(resolver.as_ref(), &players, resolver.request_interval()).await;
+
+    // These profiles weren't really seen, so they get the oldest last seen time possible. That
+    // keeps them from looking active, and any real update replaces them.
+    let never_seen = Tz::GMT.timestamp(0, 0);
+    let mut resolved = Block::new();
+    for (player, username) in &lookup.resolved {
+        resolved.push(row! {
+            player_id: *player,
+            username: username.clone(),
+            last_seen: never_seen,
+            username_lower: username.to_lowercase(),
+        })?;
+    }
+    if resolved.row_count() > 0 {
+        handle.insert("players", resolved).await?;
+    }
+
+    let failed_at = Utc::now().with_timezone(&Tz::GMT);
+    let mut lookups = Block::new();
+    for player in lookup.unknown.iter().chain(&lookup.failed) {
+        lookups.push(row! {
+            player_id: *player,
+            lookup_failed_at: failed_at,
+        })?;
+    }
+    if lookups.row_count() > 0 {
+        handle.insert("username_lookups", lookups).await?;
+    }
+
+    Ok(UsernameBackfillReport {
+        resolved: lookup.resolved.len() as u64,
+        unknown: lookup.unknown.len() as u64,
+        failed: lookup.failed.len() as u64,
+        remaining: lookup.remaining.len() as u64,
+    })
+}
+
+/// Sends every player's totals in the namespace to `sink` in pages of at most `batch_size` players,
+/// without ever holding more than a page in memory. Stops early if the receiver is dropped.
+async fn stream_player_stats(
+    pool: Pool,
+    namespace: String,
+    batch_size: usize,
+    top_stats: Option<usize>,
+    pseudonym_salt: Option<String>,
+    sink: mpsc::Sender<Result<Vec<PlayerStatsDocument>, StatisticsDatabaseError>>,
+) {
+    let batch_size = batch_size.max(1);
+    if let Err(e) = try_stream_player_stats(pool, &namespace, batch_size, top_stats, pseudonym_salt.as_deref(), &sink).await {
+        let _ = sink.send(Err(e)).await;
+    }
+}
+
+async fn try_stream_player_stats(
+    pool: Pool,
+    namespace: &str,
+    batch_size: usize,
+    top_stats: Option<usize>,
+    pseudonym_salt: Option<&str>,
+    sink: &mpsc::Sender<Result<Vec<PlayerStatsDocument>, StatisticsDatabaseError>>,
+) -> Result<(), StatisticsDatabaseError> {
+    let mut handle = pool.get_handle().await?;
+
+    let (order, limit) = match top_stats {
+        Some(keep) => (", total DESC, key", format!("LIMIT {} BY player_id", keep)),
+        None => ("", String::new()),
+    };
+
+    // Ordering by player means each player's rows arrive together, so a document is complete as
+    // soon as we see the next player.
+    let sql = format!(r#"
+        SELECT player_id, key, SUM(value) AS total
+            FROM player_statistics
+            WHERE namespace = {}
+            GROUP BY player_id, key
+            ORDER BY player_id{}
+            {}
+            SETTINGS max_block_size = {}"#, quote(namespace), order, limit, batch_size);
+
+    let mut blocks = handle.query(sql).stream_blocks();
+
+    let mut page = Vec::with_capacity(batch_size);
+    let mut current: Option<PlayerStatsDocument> = None;
+
+    while let Some(block) = blocks.next().await {
+        let block = block?;
+        for row in block.rows() {
+            let mut player: Uuid = row.get("player_id")?;
+            if let Some(salt) = pseudonym_salt {
+                player = pseudonymize(salt, &player);
+            }
+            let key: String = row.get("key")?;
+            let total: f64 = row.get("total")?;
+
+            match &mut current {
+                Some(document) if document.player == player => {
+                    document.stats.insert(key, total);
+                    continue;
+                }
+                _ => (),
+            }
+
+            let mut stats = HashMap::new();
+            stats.insert(key, total);
+            if let Some(document) = current.replace(PlayerStatsDocument { player, stats }) {
+                page.push(document);
+            }
+
+            if page.len() >= batch_size {
+                let full_page = std::mem::replace(&mut page, Vec::with_capacity(batch_size));
+                if sink.send(Ok(full_page)).await.is_err() {
+                    // Dropping the stream here cancels the rest of the query.
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    page.extend(current);
+    if !page.is_empty() {
+        let _ = sink.send(Ok(page)).await;
+    }
+
+    Ok(())
+}
+
+// Rows ordered by player are gathered into one document per player.
+fn push_player_stat(documents: &mut Vec<PlayerStatsDocument>, player: Uuid, key: String, total: f64) {
+    match documents.last_mut() {
+        Some(document) if document.player == player => {
+            document.stats.insert(key, total);
+        }
+        _ => {
+            let mut stats = HashMap::new();
+            stats.insert(key, total);
+            documents.push(PlayerStatsDocument { player, stats });
+        }
+    }
+}
+
+fn check_bundle_size(config: &StatisticsConfig, bundle: &GameStatsBundle) -> Result<(), String> {
+    let players = bundle.stats.players.len();
+    if players > config.max_players_per_bundle {
+        return Err(format!("bundle has {} players, more than the limit of {}", players, config.max_players_per_bundle));
+    }
+
+    let stats = bundle.stats.players.values()
+        .chain(bundle.stats.global.iter())
+        .map(|stats| stats.len())
+        .max()
+        .unwrap_or(0);
+    if stats > config.max_stats_per_player {
+        return Err(format!("bundle has {} stats for one player, more than the limit of {}", stats, config.max_stats_per_player));
+    }
+
+    Ok(())
+}
+
+/// Removes any stats from the bundle that this namespace isn't allowed to upload, or that would
+/// poison totals by not being a finite number.
+fn reject_invalid_stats(config: &StatisticsConfig, bundle: &mut GameStatsBundle) -> Vec<RejectedStat> {
+    let namespace = bundle.namespace.clone();
+
+    let mut rejected = Vec::new();
+    for (player, stats) in bundle.stats.players.iter_mut() {
+        rejected.extend(take_invalid_stats(config, &namespace, Some(*player), stats));
+    }
+
+    if let Some(global) = &mut bundle.stats.global {
+        rejected.extend(take_invalid_stats(config, &namespace, None, global));
+    }
+
+    rejected
+}
+
+fn take_invalid_stats(config: &StatisticsConfig, namespace: &str, player: Option<Uuid>, stats: &mut HashMap<String, UploadStat>) -> Vec<RejectedStat> {
+    let invalid: Vec<(String, String)> = stats.iter()
+        .filter_map(|(key, stat)| check_stat(config, namespace, key, stat).map(|reason| (key.clone(), reason)))
+        .collect();
+
+    invalid.into_iter()
+        .filter_map(|(key, reason)| {
+            stats.remove(&key).map(|stat| RejectedStat { player, key, stat, reason })
+        })
+        .collect()
+}
+
+fn check_stat(config: &StatisticsConfig, namespace: &str, key: &str, stat: &UploadStat) -> Option<String> {
+    // Nothing stops a deserializer from producing these, and a single one makes every total
+    // it's summed into meaningless.
+    let value: f64 = stat.clone().into();
+    if !value.is_finite() {
+        return Some(format!("'{}' has a value of {}, which isn't a finite number", key, value));
+    }
+    // Stats are stored under their full name, so structured stats like `wins.nether` need
+    // nothing special; they just have to name every part.
+    if key.split('.').any(|part| part.is_empty()) {
+        return Some(format!("'{}' has an empty part in its name", key));
+    }
+    if let Some(allowlist) = config.stat_allowlists.get(namespace) {
+        if !allowlist.contains(key) {
+            return Some(format!("'{}' is not on the allowlist for {}", key, namespace));
+        }
+    }
+    None
+}
+
+// Drops set-once stats that are already set, given the (player, namespace, stat) and (namespace,
+// stat) pairs that are. Only the first of the uploads to set a stat keeps it.
+fn drop_set_stats(uploads: &mut [GameStatsUpload], mut player_stats: HashSet<(Uuid, String, String)>, mut global_stats: HashSet<(String, String)>) {
+    for upload in uploads.iter_mut() {
+        let namespace = &upload.bundle.namespace;
+        for (player, stats) in upload.bundle.stats.players.iter_mut() {
+            stats.retain(|key, stat| !stat.is_set_once() || player_stats.insert((*player, namespace.clone(), key.clone())));
+        }
+        if let Some(stats) = &mut upload.bundle.stats.global {
+            stats.retain(|key, stat| !stat.is_set_once() || global_stats.insert((namespace.clone(), key.clone())));
+        }
+    }
+}
+
+// Gives each player's change the next id. Global changes aren't sent to subscribers, so they don't
+// use one up.
+fn number_stat_changes(namespace: &str, changes: Vec<StatChange>, next_id: &mut u64) -> Vec<StatChanged> {
+    changes.into_iter()
+        .filter_map(|change| {
+            let uuid = change.player?;
+            let changed = StatChanged {
+                id: *next_id,
+                uuid,
+                namespace: namespace.to_owned(),
+                stat: change.key,
+                old_value: change.old_value,
+                new_value: change.new_value,
+            };
+            *next_id += 1;
+            Some(changed)
+        })
+        .collect()
+}
+
+// Whether some of the changes after `after` have already dropped out of the recent changes, or were
+// sent before a restart, which leaves none of them. An id that was never handed out, as after a
+// restart with fewer rows than before, can't be caught up from either.
+fn missed_unbuffered_changes(recent: &VecDeque<StatChanged>, next_id: u64, after: u64) -> bool {
+    let oldest = recent.front().map(|change| change.id).unwrap_or(next_id);
+    after.saturating_add(1) < oldest || after >= next_id
+}
+
+// Makes room among the players whose profiles are known to be up to date. Those last written longer
+// ago than the refresh interval would be written again anyway, so they go first. If that isn't
+// enough, everyone is forgotten, which at worst rewrites a few profiles early.
+fn forget_known_players(known_players: &mut HashMap<Uuid, (String, Instant)>, now: Instant) {
+    known_players.retain(|_, (_, updated)| now.saturating_duration_since(*updated) < PLAYER_PROFILE_REFRESH_INTERVAL);
+    if known_players.len() >= MAX_KNOWN_PLAYERS {
+        known_players.clear();
+    }
+}
+
+fn new_correlation_id() -> String {
+    Uuid::new_v4().to_simple().to_string()
+}
+
+// A stable stand-in for a player's uuid that can't be turned back into it without the salt. The
+// same salt always gives the same pseudonym, so players can still be followed across exports.
+fn pseudonymize(salt: &str, player: &Uuid) -> Uuid {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(player.as_bytes());
+    let hash = hasher.finalize();
+
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&hash[..16]);
+    Uuid::from_bytes(bytes)
+}
+
+fn log_rejected_stats(server: &str, correlation_id: &str, namespace: &str, rejected: &[RejectedStat]) {
+    for stat in rejected {
+        match stat.player {
+            Some(player) => tracing::warn!(correlation_id, server, %player, namespace, key = %stat.key, reason = %stat.reason, "Rejected stat"),
+            None => tracing::warn!(correlation_id, server, namespace, key = %stat.key, reason = %stat.reason, "Rejected global stat"),
+        }
+    }
+}
+
+impl Actor for StatisticDatabaseController {}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct GetPlayerStats {
+    pub uuid: Uuid,
+    // Only stats in these namespaces are returned, or every namespace if it's empty.
+    pub namespaces: Vec<String>,
+    // Includes stats from sub-namespaces (like `bedwars:solo` for `bedwars`) in their parent.
+    pub rollup: bool,
+    // Leaves out totals that are zero.
+    pub omit_zero: bool,
+}
+
+impl Message for GetPlayerStats {
+    type Result = Result<Option<PlayerStatsResponse>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<GetPlayerStats> for StatisticDatabaseController {
+    async fn handle(&mut self, message: GetPlayerStats, _ctx: &mut Context<Self>) -> <GetPlayerStats as Message>::Result {
+        if let Some(stats) = self.player_stats_cache.as_mut().and_then(|cache| cache.get(&message)) {
+            return Ok(stats);
+        }
+
+        let stats = self.get_player_stats(&message.uuid, &message.namespaces, message.rollup, message.omit_zero).await?;
+        if let Some(cache) = &mut self.player_stats_cache {
+            cache.insert(message.clone(), message.namespaces.clone(), stats.clone());
+        }
+        Ok(stats)
+    }
+}
+
+/// How often cached results have been reused, by the kind of query.
+pub struct GetCacheStats;
+
+impl Message for GetCacheStats {
+    type Result = HashMap<String, CacheStats>;
+}
+
+#[async_trait]
+impl Handler<GetCacheStats> for StatisticDatabaseController {
+    async fn handle(&mut self, _message: GetCacheStats, _ctx: &mut Context<Self>) -> <GetCacheStats as Message>::Result {
+        let mut stats = HashMap::new();
+        if let Some(cache) = &self.leaderboard_cache {
+            stats.insert("leaderboards".to_owned(), cache.stats());
+        }
+        if let Some(cache) = &self.player_stats_cache {
+            stats.insert("player_stats".to_owned(), cache.stats());
+        }
+        stats
+    }
+}
+
+pub struct GetTypedPlayerStats {
+    pub uuid: Uuid,
+    pub namespaces: Vec<String>,
+    pub rollup: bool,
+    pub omit_zero: bool,
+}
+
+impl Message for GetTypedPlayerStats {
+    type Result = Result<Option<TypedPlayerStatsResponse>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<GetTypedPlayerStats> for StatisticDatabaseController {
+    async fn handle(&mut self, message: GetTypedPlayerStats, _ctx: &mut Context<Self>) -> <GetTypedPlayerStats as Message>::Result {
+        self.get_typed_player_stats(&message.uuid, &message.namespaces, message.rollup, message.omit_zero).await
+    }
+}
+
+pub struct GetGlobalStats {
+    pub namespace: String,
+}
+
+impl Message for GetGlobalStats {
+    type Result = Result<Option<HashMap<String, f64>>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<GetGlobalStats> for StatisticDatabaseController {
+    async fn handle(&mut self, message: GetGlobalStats, _ctx: &mut Context<Self>) -> <GetGlobalStats as Message>::Result {
+        self.get_global_stats(&message.namespace).await
+    }
+}
+
+pub struct GetPlayerAchievements {
+    pub uuid: Uuid,
+}
+
+impl Message for GetPlayerAchievements {
+    type Result = Result<Vec<PlayerAchievement>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<GetPlayerAchievements> for StatisticDatabaseController {
+    async fn handle(&mut self, message: GetPlayerAchievements, _ctx: &mut Context<Self>) -> <GetPlayerAchievements as Message>::Result {
+        self.get_player_achievements(&message.uuid).await
+    }
+}
+
+pub struct ComparePlayers {
+    pub uuids: Vec<Uuid>,
+    pub namespace: String,
+    pub stats: Vec<String>,
+}
+
+impl Message for ComparePlayers {
+    type Result = Result<HashMap<Uuid, HashMap<String, f64>>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<ComparePlayers> for StatisticDatabaseController {
+    async fn handle(&mut self, message: ComparePlayers, _ctx: &mut Context<Self>) -> <ComparePlayers as Message>::Result {
+        self.compare_players(&message.uuids, &message.namespace, &message.stats).await
+    }
+}
+
+/// Use `CURRENT_SEASON` for the player's stats as they are now.
+pub struct ComparePlayerAcrossSeasons {
+    pub uuid: Uuid,
+    pub namespace: String,
+    pub seasons: Vec<String>,
+}
+
+impl Message for ComparePlayerAcrossSeasons {
+    type Result = Result<HashMap<String, HashMap<String, f64>>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<ComparePlayerAcrossSeasons> for StatisticDatabaseController {
+    async fn handle(&mut self, message: ComparePlayerAcrossSeasons, _ctx: &mut Context<Self>) -> <ComparePlayerAcrossSeasons as Message>::Result {
+        self.compare_player_across_seasons(&message.uuid, &message.namespace, &message.seasons).await
+    }
+}
+
+pub struct GetPersonalBests {
+    pub uuid: Uuid,
+    pub namespace: String,
+}
+
+impl Message for GetPersonalBests {
+    type Result = Result<HashMap<String, PersonalBest>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<GetPersonalBests> for StatisticDatabaseController {
+    async fn handle(&mut self, message: GetPersonalBests, _ctx: &mut Context<Self>) -> <GetPersonalBests as Message>::Result {
+        self.get_personal_bests(&message.uuid, &message.namespace).await
+    }
+}
+
+pub struct UpdatePlayerProfiles {
+    pub players: Vec<Player>,
+}
+
+impl Message for UpdatePlayerProfiles {
+    type Result = ();
+}
+
+#[async_trait]
+impl Handler<UpdatePlayerProfiles> for StatisticDatabaseController {
+    async fn handle(&mut self, message: UpdatePlayerProfiles, _ctx: &mut Context<Self>) {
+        if let Err(e) = self.update_player_profiles(message.players).await {
+            warn!("Failed to update player profiles: {}", e);
+        }
+    }
+}
+
+pub struct SearchPlayers {
+    pub prefix: String,
+    pub limit: u32,
+}
+
+impl Message for SearchPlayers {
+    type Result = Result<Vec<PlayerProfile>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<SearchPlayers> for StatisticDatabaseController {
+    async fn handle(&mut self, message: SearchPlayers, _ctx: &mut Context<Self>) -> <SearchPlayers as Message>::Result {
+        self.search_players(&message.prefix, message.limit).await
+    }
+}
+
+pub struct LookupPlayer {
+    pub username: String,
+}
+
+impl Message for LookupPlayer {
+    type Result = Result<Option<PlayerProfile>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<LookupPlayer> for StatisticDatabaseController {
+    async fn handle(&mut self, message: LookupPlayer, _ctx: &mut Context<Self>) -> <LookupPlayer as Message>::Result {
+        self.lookup_player(&message.username).await
+    }
+}
+
+pub struct GetWeightedLeaderboard {
+    pub namespace: String,
+    pub weights: HashMap<String, f64>,
+    pub limit: u32,
+    pub rollup: bool,
+    // banned and hidden players are left off leaderboards unless this is set
+    pub include_flagged: bool,
+}
+
+impl Message for GetWeightedLeaderboard {
+    type Result = Result<Vec<LeaderboardEntry>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<GetWeightedLeaderboard> for StatisticDatabaseController {
+    async fn handle(&mut self, message: GetWeightedLeaderboard, _ctx: &mut Context<Self>) -> <GetWeightedLeaderboard as Message>::Result {
+        self.get_weighted_leaderboard(&message.namespace, &message.weights, message.limit, message.rollup, message.include_flagged).await
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct GetLeaderboard {
+    pub namespace: String,
+    pub stat: String,
+    pub limit: u32,
+    pub rollup: bool,
+    pub restrict_to: Option<Vec<Uuid>>,
+    pub include_flagged: bool,
+    // overrides the stat's metadata
+    pub higher_is_better: Option<bool>,
+}
+
+impl Message for GetLeaderboard {
+    type Result = Result<LeaderboardResponse, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<GetLeaderboard> for StatisticDatabaseController {
+    async fn handle(&mut self, message: GetLeaderboard, _ctx: &mut Context<Self>) -> <GetLeaderboard as Message>::Result {
+        if let Some(leaderboard) = self.leaderboard_cache.as_mut().and_then(|cache| cache.get(&message)) {
+            return Ok(leaderboard);
+        }
+
+        let leaderboard = self.get_leaderboard(&message).await?;
+        if let Some(cache) = &mut self.leaderboard_cache {
+            cache.insert(message.clone(), vec![message.namespace.clone()], leaderboard.clone());
+        }
+        Ok(leaderboard)
+    }
+}
+
+/// Where a player stands in each namespace they've played, going by the namespace's headline stat.
+pub struct CompareNamespaces {
+    pub uuid: Uuid,
+}
+
+impl Message for CompareNamespaces {
+    type Result = Result<Vec<NamespaceStanding>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<CompareNamespaces> for StatisticDatabaseController {
+    async fn handle(&mut self, message: CompareNamespaces, _ctx: &mut Context<Self>) -> <CompareNamespaces as Message>::Result {
+        self.compare_namespaces(&message.uuid).await
+    }
+}
+
+pub struct GetLeaderboardWindow {
+    pub namespace: String,
+    pub stat: String,
+    pub uuid: Uuid,
+    // how many places above and below the player to include
+    pub radius: u32,
+}
+
+impl Message for GetLeaderboardWindow {
+    type Result = Result<Option<LeaderboardWindow>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<GetLeaderboardWindow> for StatisticDatabaseController {
+    async fn handle(&mut self, message: GetLeaderboardWindow, _ctx: &mut Context<Self>) -> <GetLeaderboardWindow as Message>::Result {
+        self.get_leaderboard_window(&message.namespace, &message.stat, &message.uuid, message.radius).await
+    }
+}
+
+pub struct GetLeaderboards {
+    pub namespace: String,
+    pub stats: Vec<String>,
+    pub limit: u32,
+    pub rollup: bool,
+    pub include_flagged: bool,
+}
+
+impl Message for GetLeaderboards {
+    type Result = Result<HashMap<String, Vec<LeaderboardEntry>>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<GetLeaderboards> for StatisticDatabaseController {
+    async fn handle(&mut self, message: GetLeaderboards, _ctx: &mut Context<Self>) -> <GetLeaderboards as Message>::Result {
+        self.get_leaderboards(&message.namespace, &message.stats, message.limit, message.rollup, message.include_flagged).await
+    }
+}
+
+pub struct RefreshLeaderboardSnapshots;
+
+impl Message for RefreshLeaderboardSnapshots {
+    type Result = ();
+}
+
+#[async_trait]
+impl Handler<RefreshLeaderboardSnapshots> for StatisticDatabaseController {
+    async fn handle(&mut self, _message: RefreshLeaderboardSnapshots, _ctx: &mut Context<Self>) {
+        self.refresh_leaderboard_snapshots().await;
+    }
+}
+
+/// Starts looking up usernames for a batch of players that have stats but no profile. The lookups
+/// are throttled, so they happen in the background and the report is sent once they're done. Only
+/// one backfill runs at a time.
+pub struct BackfillUsernames;
+
+impl Message for BackfillUsernames {
+    type Result = Result<oneshot::Receiver<Result<UsernameBackfillReport, StatisticsDatabaseError>>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<BackfillUsernames> for StatisticDatabaseController {
+    async fn handle(&mut self, _message: BackfillUsernames, _ctx: &mut Context<Self>) -> <BackfillUsernames as Message>::Result {
+        let resolver = match &self.username_resolver {
+            Some(resolver) => resolver.clone(),
+            None => return Err(StatisticsDatabaseError::InvalidRequest("no username resolver is configured".to_owned())),
+        };
+
+        let running = match self.username_backfill.clone().try_lock_owned() {
+            Ok(running) => running,
+            Err(_) => return Err(StatisticsDatabaseError::InvalidRequest("a username backfill is already running".to_owned())),
+        };
+
+        let (sender, receiver) = oneshot::channel();
+        let pool = self.pool.clone();
+        tokio::spawn(async move {
+            let result = run_username_backfill(pool, resolver).await;
+            match &result {
+                Ok(report) => info!(
+                    "Backfilled {} usernames ({} unknown, {} failed, {} left for later)",
+                    report.resolved, report.unknown, report.failed, report.remaining,
+                ),
+                Err(e) => warn!("Failed to backfill usernames: {}", e),
+            }
+            drop(running);
+            let _ = sender.send(result);
+        });
+
+        Ok(receiver)
+    }
+}
+
+/// Optimizes every statistics table, reporting their sizes before and after.
+pub struct RunMaintenance;
+
+impl Message for RunMaintenance {
+    type Result = Result<HashMap<String, TableMaintenance>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<RunMaintenance> for StatisticDatabaseController {
+    async fn handle(&mut self, _message: RunMaintenance, _ctx: &mut Context<Self>) -> <RunMaintenance as Message>::Result {
+        self.run_maintenance().await
+    }
+}
+
+pub struct DecayInactiveStats;
+
+impl Message for DecayInactiveStats {
+    type Result = ();
+}
+
+#[async_trait]
+impl Handler<DecayInactiveStats> for StatisticDatabaseController {
+    async fn handle(&mut self, _message: DecayInactiveStats, _ctx: &mut Context<Self>) {
+        self.decay_inactive_stats().await;
+    }
+}
+
+pub struct FlagPlayer {
+    pub uuid: Uuid,
+    pub flag: String,
+    pub value: bool,
+}
+
+impl Message for FlagPlayer {
+    type Result = Result<(), StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<FlagPlayer> for StatisticDatabaseController {
+    async fn handle(&mut self, message: FlagPlayer, _ctx: &mut Context<Self>) -> <FlagPlayer as Message>::Result {
+        self.flag_player(message.uuid, &message.flag, message.value).await
+    }
+}
+
+/// The flags that are currently set on a player.
+pub struct GetFlags {
+    pub uuid: Uuid,
+}
+
+impl Message for GetFlags {
+    type Result = Result<Vec<String>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<GetFlags> for StatisticDatabaseController {
+    async fn handle(&mut self, message: GetFlags, _ctx: &mut Context<Self>) -> <GetFlags as Message>::Result {
+        self.get_flags(&message.uuid).await
+    }
+}
+
+pub struct ResolveNames {
+    pub uuids: Vec<Uuid>,
+}
+
+impl Message for ResolveNames {
+    type Result = Result<HashMap<Uuid, String>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<ResolveNames> for StatisticDatabaseController {
+    async fn handle(&mut self, message: ResolveNames, _ctx: &mut Context<Self>) -> <ResolveNames as Message>::Result {
+        self.resolve_names(&message.uuids).await
+    }
+}
+
+pub struct ResolveName {
+    pub uuid: Uuid,
+}
+
+impl Message for ResolveName {
+    type Result = Result<Option<String>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<ResolveName> for StatisticDatabaseController {
+    async fn handle(&mut self, message: ResolveName, _ctx: &mut Context<Self>) -> <ResolveName as Message>::Result {
+        self.resolve_name(&message.uuid).await
+    }
+}
+
+pub struct GetPlayerOverview {
+    pub uuid: Uuid,
+    // with none given, stats from every namespace are included
+    pub namespaces: Option<Vec<String>>,
+}
+
+impl Message for GetPlayerOverview {
+    type Result = PlayerOverview;
+}
+
+#[async_trait]
+impl Handler<GetPlayerOverview> for StatisticDatabaseController {
+    async fn handle(&mut self, message: GetPlayerOverview, _ctx: &mut Context<Self>) -> <GetPlayerOverview as Message>::Result {
+        self.get_player_overview(&message.uuid, message.namespaces.as_deref().unwrap_or(&[])).await
+    }
+}
+
+pub struct GetPlayerPercentile {
+    pub uuid: Uuid,
+    pub namespace: String,
+    pub stat: String,
+}
+
+impl Message for GetPlayerPercentile {
+    type Result = Result<Option<f64>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<GetPlayerPercentile> for StatisticDatabaseController {
+    async fn handle(&mut self, message: GetPlayerPercentile, _ctx: &mut Context<Self>) -> <GetPlayerPercentile as Message>::Result {
+        self.get_player_percentile(&message.uuid, &message.namespace, &message.stat).await
+    }
+}
+
+pub struct FindPlayersByStatThreshold {
+    pub namespace: String,
+    pub stat: String,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub limit: u32,
+}
+
+impl Message for FindPlayersByStatThreshold {
+    type Result = Result<Vec<StatThresholdMatch>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<FindPlayersByStatThreshold> for StatisticDatabaseController {
+    async fn handle(&mut self, message: FindPlayersByStatThreshold, _ctx: &mut Context<Self>) -> <FindPlayersByStatThreshold as Message>::Result {
+        self.find_players_by_stat_threshold(&message.namespace, &message.stat, message.min, message.max, message.limit).await
+    }
+}
+
+pub struct GetNetworkLeaderboard {
+    pub stat: String,
+    pub limit: u32,
+}
+
+impl Message for GetNetworkLeaderboard {
+    type Result = Result<Vec<LeaderboardEntry>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<GetNetworkLeaderboard> for StatisticDatabaseController {
+    async fn handle(&mut self, message: GetNetworkLeaderboard, _ctx: &mut Context<Self>) -> <GetNetworkLeaderboard as Message>::Result {
+        self.get_network_leaderboard(&message.stat, message.limit).await
+    }
+}
+
+pub struct GetNetworkStats {
+    pub uuid: Uuid,
+}
+
+impl Message for GetNetworkStats {
+    type Result = Result<HashMap<String, f64>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<GetNetworkStats> for StatisticDatabaseController {
+    async fn handle(&mut self, message: GetNetworkStats, _ctx: &mut Context<Self>) -> <GetNetworkStats as Message>::Result {
+        self.get_network_stats(&message.uuid).await
+    }
+}
+
+pub struct GetTrending {
+    pub namespace: String,
+    pub stat: String,
+    pub window: Duration,
+    pub limit: u32,
+}
+
+impl Message for GetTrending {
+    type Result = Result<Vec<TrendingEntry>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<GetTrending> for StatisticDatabaseController {
+    async fn handle(&mut self, message: GetTrending, _ctx: &mut Context<Self>) -> <GetTrending as Message>::Result {
+        self.get_trending(&message.namespace, &message.stat, message.window, message.limit).await
+    }
+}
+
+pub struct GetNamespaceTrend {
+    pub namespace: String,
+    pub stat: String,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub granularity: TrendGranularity,
+}
+
+impl Message for GetNamespaceTrend {
+    type Result = Result<Vec<TrendPoint>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<GetNamespaceTrend> for StatisticDatabaseController {
+    async fn handle(&mut self, message: GetNamespaceTrend, _ctx: &mut Context<Self>) -> <GetNamespaceTrend as Message>::Result {
+        self.get_namespace_trend(&message.namespace, &message.stat, message.from, message.to, message.granularity).await
+    }
+}
+
+pub struct GetNamespaceActivity {
+    pub window: Duration,
+    pub limit: u32,
+}
+
+impl Message for GetNamespaceActivity {
+    type Result = Result<Vec<NamespaceActivity>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<GetNamespaceActivity> for StatisticDatabaseController {
+    async fn handle(&mut self, message: GetNamespaceActivity, _ctx: &mut Context<Self>) -> <GetNamespaceActivity as Message>::Result {
+        self.get_namespace_activity(message.window, message.limit).await
+    }
+}
+
+pub struct GetPlaytimeLeaderboard {
+    pub limit: u32,
+    // banned and hidden players are left off leaderboards unless this is set
+    pub include_flagged: bool,
+}
+
+impl Message for GetPlaytimeLeaderboard {
+    type Result = Result<Vec<PlaytimeEntry>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<GetPlaytimeLeaderboard> for StatisticDatabaseController {
+    async fn handle(&mut self, message: GetPlaytimeLeaderboard, _ctx: &mut Context<Self>) -> <GetPlaytimeLeaderboard as Message>::Result {
+        self.get_playtime_leaderboard(message.limit, message.include_flagged).await
+    }
+}
+
+pub struct GetStatMetadata {
+    pub namespace: String,
+}
+
+impl Message for GetStatMetadata {
+    type Result = Result<HashMap<String, StatMetadata>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<GetStatMetadata> for StatisticDatabaseController {
+    async fn handle(&mut self, message: GetStatMetadata, _ctx: &mut Context<Self>) -> <GetStatMetadata as Message>::Result {
+        self.get_stat_metadata(&message.namespace).await
+    }
+}
+
+/// Streams every player's stats in a namespace to `sink`. The handler returns immediately and the
+/// pages are sent from a separate task, so a long export doesn't hold up other requests. Exports
+/// never include usernames, and anonymized ones swap uuids for pseudonyms that can't be reversed.
+pub struct StreamPlayerStats {
+    pub namespace: String,
+    // Replaces each player's uuid with a pseudonym, so should be set whenever stats leave our hands.
+    pub anonymize: bool,
+    pub sink: mpsc::Sender<Result<Vec<PlayerStatsDocument>, StatisticsDatabaseError>>,
+}
+
+impl Message for StreamPlayerStats {
+    type Result = ();
+}
+
+#[async_trait]
+impl Handler<StreamPlayerStats> for StatisticDatabaseController {
+    async fn handle(&mut self, message: StreamPlayerStats, _ctx: &mut Context<Self>) {
+        let pseudonym_salt = if message.anonymize {
+            match &self.config.export_anonymization_salt {
+                Some(salt) => Some(salt.clone()),
+                None => {
+                    let error = StatisticsDatabaseError::InvalidRequest("anonymized exports need a salt to be configured".to_owned());
+                    let _ = message.sink.send(Err(error)).await;
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+
+        let top_stats = self.config.top_stats_per_player.get(&message.namespace).copied();
+        tokio::spawn(stream_player_stats(
+            self.read_pool.clone(),
+            message.namespace,
+            self.config.stream_batch_size,
+            top_stats,
+            pseudonym_salt,
+            message.sink,
+        ));
+    }
+}
+
+/// Reports the types each of a namespace's stats has been uploaded with, based on a sample of its
+/// stats so that it stays cheap for large namespaces.
+pub struct GetNamespaceSchema {
+    pub namespace: String,
+}
+
+impl Message for GetNamespaceSchema {
+    type Result = Result<NamespaceSchema, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<GetNamespaceSchema> for StatisticDatabaseController {
+    async fn handle(&mut self, message: GetNamespaceSchema, _ctx: &mut Context<Self>) -> <GetNamespaceSchema as Message>::Result {
+        self.get_namespace_schema(&message.namespace).await
+    }
+}
+
+/// Lists every stat name used in a namespace, sorted by name.
+pub struct ListStats {
+    pub namespace: String,
+}
+
+impl Message for ListStats {
+    type Result = Result<Vec<NamespaceStat>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<ListStats> for StatisticDatabaseController {
+    async fn handle(&mut self, message: ListStats, _ctx: &mut Context<Self>) -> <ListStats as Message>::Result {
+        self.list_stats(&message.namespace).await
+    }
+}
+
+pub struct GetStatBySource {
+    pub namespace: String,
+    pub stat: String,
+}
+
+impl Message for GetStatBySource {
+    type Result = Result<HashMap<String, f64>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<GetStatBySource> for StatisticDatabaseController {
+    async fn handle(&mut self, message: GetStatBySource, _ctx: &mut Context<Self>) -> <GetStatBySource as Message>::Result {
+        self.get_stat_by_source(&message.namespace, &message.stat).await
+    }
+}
+
+/// Reports global totals in the namespace that don't match the sum of their player stats, without
+/// changing anything. [`RecomputeGlobalStats`] can then be used to fix them.
+pub struct VerifyGlobalConsistency {
+    pub namespace: String,
+}
+
+impl Message for VerifyGlobalConsistency {
+    type Result = Result<Vec<GlobalStatMismatch>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<VerifyGlobalConsistency> for StatisticDatabaseController {
+    async fn handle(&mut self, message: VerifyGlobalConsistency, _ctx: &mut Context<Self>) -> <VerifyGlobalConsistency as Message>::Result {
+        self.verify_global_consistency(&message.namespace).await
+    }
+}
+
+/// Overwrites the namespace's global totals with the sums of its player stats, returning each
+/// stat's old (`global_total`) and new (`player_total`) value.
+pub struct RecomputeGlobalStats {
+    pub namespace: String,
+}
+
+impl Message for RecomputeGlobalStats {
+    type Result = Result<Vec<GlobalStatComparison>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<RecomputeGlobalStats> for StatisticDatabaseController {
+    async fn handle(&mut self, message: RecomputeGlobalStats, _ctx: &mut Context<Self>) -> <RecomputeGlobalStats as Message>::Result {
+        self.recompute_global_stats(&message.namespace).await
+    }
+}
+
+/// Fixes up a rejected stat before it's validated again, returning its possibly renamed key.
+pub type StatTransform = Box<dyn Fn(String, UploadStat) -> (String, UploadStat) + Send + Sync>;
+
+pub struct ReplayRejectedStats {
+    pub namespace: String,
+    pub transform: Option<StatTransform>,
+}
+
+impl Message for ReplayRejectedStats {
+    type Result = Result<ReplayReport, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<ReplayRejectedStats> for StatisticDatabaseController {
+    async fn handle(&mut self, message: ReplayRejectedStats, _ctx: &mut Context<Self>) -> <ReplayRejectedStats as Message>::Result {
+        self.replay_rejected_stats(&message.namespace, message.transform).await
+    }
+}
+
+/// Only for admins: this bypasses all the checks bundle uploads go through.
+pub struct AdjustPlayerStat {
+    pub uuid: Uuid,
+    pub namespace: String,
+    pub stat: String,
+    pub delta: f64,
+    // who is making the adjustment and why, for the audit trail
+    pub actor: String,
+    pub reason: String,
+}
+
+impl Message for AdjustPlayerStat {
+    type Result = Result<f64, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<AdjustPlayerStat> for StatisticDatabaseController {
+    async fn handle(&mut self, message: AdjustPlayerStat, _ctx: &mut Context<Self>) -> <AdjustPlayerStat as Message>::Result {
+        self.adjust_player_stat(message).await
+    }
+}
+
+pub struct ExportChangesSince {
+    pub namespace: String,
+    pub since: DateTime<Utc>,
+}
+
+impl Message for ExportChangesSince {
+    type Result = Result<ChangedStats, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<ExportChangesSince> for StatisticDatabaseController {
+    async fn handle(&mut self, message: ExportChangesSince, _ctx: &mut Context<Self>) -> <ExportChangesSince as Message>::Result {
+        self.export_changes_since(&message.namespace, message.since).await
+    }
+}
+
+pub struct GetStorageStats;
+
+impl Message for GetStorageStats {
+    type Result = Result<StorageStats, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<GetStorageStats> for StatisticDatabaseController {
+    async fn handle(&mut self, _message: GetStorageStats, _ctx: &mut Context<Self>) -> <GetStorageStats as Message>::Result {
+        self.get_storage_stats().await
+    }
+}
+
+pub struct RenameStat {
+    pub namespace: String,
+    pub old_name: String,
+    pub new_name: String,
+    // whether to combine with the new stat if it already exists, rather than refusing
+    pub merge: bool,
+}
+
+impl Message for RenameStat {
+    type Result = Result<RenamedStat, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<RenameStat> for StatisticDatabaseController {
+    async fn handle(&mut self, message: RenameStat, _ctx: &mut Context<Self>) -> <RenameStat as Message>::Result {
+        self.rename_stat(&message.namespace, &message.old_name, &message.new_name, message.merge).await
+    }
+}
+
+pub struct ResetStats {
+    pub namespace: String,
+    pub stats: Vec<String>,
+    // whether the namespace's global stats are reset as well as its players'
+    pub global: bool,
+}
+
+impl Message for ResetStats {
+    type Result = Result<ResetStatsReport, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<ResetStats> for StatisticDatabaseController {
+    async fn handle(&mut self, message: ResetStats, _ctx: &mut Context<Self>) -> <ResetStats as Message>::Result {
+        self.reset_stats(&message.namespace, &message.stats, message.global).await
+    }
+}
+
+pub struct GetGameStats(pub Uuid);
+
+impl Message for GetGameStats {
+    type Result = Result<Option<HashMap<Uuid, PlayerStatsResponse>>, StatisticsDatabaseError>;
+}
+
+#[async_trait]
+impl Handler<GetGameStats> for StatisticDatabaseController {
+    async fn handle(&mut self, message: GetGameStats, _ctx: &mut Context<Self>) -> <GetGameStats as Message>::Result {
+        self.get_game_stats(&message.0).await
+    }
+}
+
+#[derive(Debug)]
+pub struct UploadStatsBundle {
+    pub game_id: Uuid,
+    pub server: String,
+    pub bundle: GameStatsBundle,
+    // Logs the changes this bundle would make and returns them in the report, instead of writing it.
+    pub dry_run: bool,
+    // Tags every log line and alert about this upload. One is made up if the server didn't send one.
+    pub correlation_id: Option<String>,
+}
+
+impl Message for UploadStatsBundle {
+    type Result = BundleUploadReport;
+}
+
+#[async_trait]
+impl Handler<UploadStatsBundle> for StatisticDatabaseController {
+    async fn handle(&mut self, message: UploadStatsBundle, _ctx: &mut Context<Self>) -> <UploadStatsBundle as Message>::Result {
+        let correlation_id = message.correlation_id.unwrap_or_else(new_correlation_id);
+        let upload = GameStatsUpload { game_id: message.game_id, bundle: message.bundle };
+
+        if message.dry_run {
+            return self.simulate_upload(&message.server, &correlation_id, upload).await;
+        }
+
+        let report = self.process_uploads(&message.server, &correlation_id, vec![upload]).await.remove(0);
+
+        // Failed writes were already dealt with by kind, so this is only bundles turned away
+        // before then.
+        if report.error.is_some() && report.error_kind.is_none() {
+            warn!("[{}] Failed to upload stats bundle from '{}': {:?}", correlation_id, message.server, report);
+        }
+
+        report
+    }
+}
+
+/// Uploads several bundles from the same server at once, such as when a server flushes bundles it
+/// queued up while the backend was unreachable. The writes for all bundles are batched together.
+#[derive(Debug)]
+pub struct UploadStatsBundles {
+    pub server: String,
+    pub uploads: Vec<GameStatsUpload>,
+    pub correlation_id: Option<String>,
+}
+
+impl Message for UploadStatsBundles {
+    type Result = Vec<BundleUploadReport>;
+}
+
+#[async_trait]
+impl Handler<UploadStatsBundles> for StatisticDatabaseController {
+    async fn handle(&mut self, message: UploadStatsBundles, _ctx: &mut Context<Self>) -> <UploadStatsBundles as Message>::Result {
+        let correlation_id = message.correlation_id.unwrap_or_else(new_correlation_id);
+        let reports = self.process_uploads(&message.server, &correlation_id, message.uploads).await;
+
+        let failed: Vec<&BundleUploadReport> = reports.iter().filter(|report| report.error.is_some()).collect();
+        for report in failed.iter().filter(|report| report.error_kind.is_none()) {
+            warn!("[{}] Failed to upload stats bundle from '{}': {:?}", correlation_id, message.server, report);
+        }
+        info!("[{}] uploaded {} of {} stats bundles from '{}'", correlation_id, reports.len() - failed.len(), reports.len(), message.server);
+
+        reports
+    }
+}
+
+/// Drops the whole statistics database, for cleaning up after tests that ran against a throwaway
+/// database.
+#[cfg(feature = "test-utils")]
+pub struct DropDatabase;
+
+#[cfg(feature = "test-utils")]
+impl Message for DropDatabase {
+    type Result = Result<(), StatisticsDatabaseError>;
+}
+
+#[cfg(feature = "test-utils")]
+#[async_trait]
+impl Handler<DropDatabase> for StatisticDatabaseController {
+    async fn handle(&mut self, _message: DropDatabase, _ctx: &mut Context<Self>) -> <DropDatabase as Message>::Result {
+        let mut handle = self.pool.get_handle().await?;
+        handle.execute(format!("DROP DATABASE IF EXISTS `{}`", self.config.database_name)).await?;
+        Ok(())
+    }
+}
+
+pub struct SubscribeStatChanges {
+    // Only changes in this namespace or its sub-namespaces are received, or every change if unset.
+    pub namespace: Option<String>,
+    // The id of the last change the subscriber saw before reconnecting, so that the changes they
+    // missed in between are sent first.
+    pub after: Option<u64>,
+}
+
+impl Message for SubscribeStatChanges {
+    type Result = Option<StatChangeSubscription>;
+}
+
+#[async_trait]
+impl Handler<SubscribeStatChanges> for StatisticDatabaseController {
+    async fn handle(&mut self, message: SubscribeStatChanges, _ctx: &mut Context<Self>) -> <SubscribeStatChanges as Message>::Result {
+        let mut subscription = StatChangeSubscription {
+            namespace: message.namespace,
+            gap: false,
+            missed: VecDeque::new(),
+            receiver: self.stat_changes.subscribe(),
+        };
+
+        if let Some(after) = message.after {
+            subscription.gap = missed_unbuffered_changes(&self.recent_stat_changes, self.next_stat_change_id, after);
+            let missed = self.recent_stat_changes.iter()
+                .filter(|change| change.id > after && subscription.wants(change))
+                .cloned()
+                .collect();
+            subscription.missed = missed;
+        }
+
+        Some(subscription)
+    }
+}
+
+/// What a stat change subscriber receives.
+#[derive(Clone, Debug)]
+pub enum StatChangeEvent {
+    Changed(StatChanged),
+    // Some changes were missed and can't be sent, so whatever the subscriber is showing should be
+    // fetched again.
+    Gap,
+}
+
+pub struct StatChangeSubscription {
+    namespace: Option<String>,
+    // whether the changes missed before subscribing were no longer all around to be sent
+    gap: bool,
+    missed: VecDeque<StatChanged>,
+    receiver: broadcast::Receiver<StatChanged>,
+}
+
+impl StatChangeSubscription {
+    /// The next change, or `None` once there won't be any more. Changes sent while the subscriber
+    /// was too far behind to keep up are replaced with a gap.
+    pub async fn recv(&mut self) -> Option<StatChangeEvent> {
+        if self.gap {
+            self.gap = false;
+            return Some(StatChangeEvent::Gap);
+        }
+        if let Some(change) = self.missed.pop_front() {
+            return Some(StatChangeEvent::Changed(change));
+        }
+
+        loop {
+            match self.receiver.recv().await {
+                Ok(change) if self.wants(&change) => return Some(StatChangeEvent::Changed(change)),
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(_)) => return Some(StatChangeEvent::Gap),
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    fn wants(&self, change: &StatChanged) -> bool {
+        match &self.namespace {
+            Some(namespace) => is_in_namespace(&change.namespace, namespace),
+            None => true,
+        }
+    }
+}
+
+// Both the primary and read databases share the configured database name and pool sizes.
+fn pool_options(database_url: &str, config: &StatisticsConfig) -> Result<Options, StatisticsDatabaseError> {
+    let mut options: Options = database_url.parse()
+        .map_err(|e| StatisticsDatabaseError::InvalidConfig(format!("the database url couldn't be parsed: {}", e)))?
+        .database(&config.database_name);
+    if let Some(min_pool_size) = config.min_pool_size {
+        options = options.pool_min(min_pool_size);
+    }
+    if let Some(max_pool_size) = config.max_pool_size {
+        options = options.pool_max(max_pool_size);
+    }
+    Ok(options)
+}
+
+// Keeps only the `keep` largest stats, going by key between equal values so that which are kept
+// doesn't change from one read to the next.
+fn keep_top_stats(stats: &mut HashMap<String, StatValue>, keep: usize) {
+    if stats.len() <= keep {
+        return;
+    }
+
+    let mut ranked: Vec<(String, StatValue)> = stats.drain().collect();
+    ranked.sort_by(|(a_key, a), (b_key, b)| {
+        b.as_f64().partial_cmp(&a.as_f64())
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| a_key.cmp(b_key))
+    });
+    ranked.truncate(keep);
+    stats.extend(ranked);
+}
+
+/// Applies a bundle's stats on top of what's stored for them, or what earlier bundles in the same
+/// batch left them at. Players in `unreadable` are left out and returned with their errors.
+fn apply_stored_stats(
+    bundle: &GameStatsBundle,
+    stored: &HashMap<(Option<Uuid>, String), StoredStat>,
+    mut unreadable: HashMap<Uuid, String>,
+    batch: &mut HashMap<(String, Option<Uuid>, String), StoredStat>,
+) -> (Vec<StatChange>, Vec<(Uuid, String)>) {
+    let namespace = &bundle.namespace;
+
+    let player_stats = bundle.stats.players.iter()
+        .filter(|(player, _)| !unreadable.contains_key(*player))
+        .flat_map(|(player, stats)| stats.iter().map(move |(key, stat)| (Some(*player), key, stat)));
+    let global_stats = bundle.stats.global.iter()
+        .flat_map(|stats| stats.iter().map(|(key, stat)| (None, key, stat)));
+
+    let mut changes = Vec::new();
+    for (player, key, stat) in player_stats.chain(global_stats) {
+        let batch_key = (namespace.clone(), player, key.clone());
+        let before = batch.get(&batch_key).copied()
+            .or_else(|| stored.get(&(player, key.clone())).copied());
+        let (old_value, new_value, after) = StoredStat::apply(before, stat);
+        batch.insert(batch_key, after);
+        changes.push(StatChange { player, key: key.clone(), old_value, new_value });
+    }
+
+    (changes, unreadable.drain().collect())
+}
+
+/// The rows a bundle adds to each table, kept apart from other bundles' rows until they're written
+/// so that a batch can fall back to writing its bundles one at a time.
+struct BundleRows {
+    game_id: Uuid,
+    namespace: String,
+    player_count: u32,
+    server: String,
+    source_server: String,
+    date_played: DateTime<Tz>,
+    // (player, key, value, type)
+    player_stats: Vec<(Uuid, String, f64, String)>,
+    // (key, value, type)
+    global_stats: Vec<(String, f64, String)>,
+    bundle_id: Option<Uuid>,
+}
+
+impl BundleRows {
+    fn new(game_id: Uuid, server: &str, bundle: &GameStatsBundle, skipped: &HashSet<Uuid>, date_played: DateTime<Tz>) -> BundleRows {
+        let player_stats = bundle.stats.players.iter()
+            .filter(|(player, _)| !skipped.contains(*player))
+            .flat_map(|(player, stats)| stats.iter().map(move |(key, stat)| {
+                let value: f64 = stat.clone().into();
+                (*player, key.clone(), value, stat.get_type().to_owned())
+            }))
+            .collect();
+        let global_stats = bundle.stats.global.iter()
+            .flat_map(|stats| stats.iter())
+            .map(|(key, stat)| {
+                let value: f64 = stat.clone().into();
+                (key.clone(), value, stat.get_type().to_owned())
+            })
+            .collect();
+
+        BundleRows {
+            game_id,
+            namespace: bundle.namespace.clone(),
+            player_count: bundle.stats.players.len() as u32,
+            server: server.to_owned(),
+            source_server: bundle.source_server.clone().unwrap_or_else(|| server.to_owned()),
+            date_played,
+            player_stats,
+            global_stats,
+            bundle_id: bundle.bundle_id,
+        }
+    }
+
+    // Tables the bundle has no rows for aren't counted as written.
+    fn record_written(&self, table: BundleTable, written: &mut Vec<BundleTable>) {
+        let has_rows = match table {
+            BundleTable::Games => true,
+            BundleTable::PlayerStatistics => !self.player_stats.is_empty(),
+            BundleTable::GlobalStatistics => !self.global_stats.is_empty(),
+            BundleTable::AppliedBundles => self.bundle_id.is_some(),
+        };
+        if has_rows {
+            written.push(table);
+        }
+    }
+}
+
+// The id of a bundle that was already applied, whether by an earlier upload or earlier in the
+// same batch. Bundles without an id can't be told apart, so they're never skipped.
+fn already_applied(applied: &HashSet<Uuid>, bundle: &GameStatsBundle) -> Option<Uuid> {
+    bundle.bundle_id.filter(|bundle_id| applied.contains(bundle_id))
+}
+
+fn mark_applied(applied: &mut HashSet<Uuid>, bundle: &GameStatsBundle) {
+    if let Some(bundle_id) = bundle.bundle_id {
+        applied.insert(bundle_id);
+    }
+}
+
+/// A bundle that failed to be written, with the tables it had already been written to.
+struct FailedBundle {
+    game_id: Uuid,
+    error: StatisticsDatabaseError,
+    written: Vec<BundleTable>,
+}
+
+fn wrote_stats(written: &[BundleTable]) -> bool {
+    written.iter().any(|table| matches!(table, BundleTable::PlayerStatistics | BundleTable::GlobalStatistics))
+}
+
+/// Works out each bundle's changes again from what was stored before the batch, counting only the
+/// stats that were written, so that a bundle that failed doesn't show up in the old values of the
+/// bundles after it.
+fn written_stat_changes(
+    bundles: &[(&GameStatsBundle, &HashMap<Uuid, String>, &[BundleTable])],
+    stored_before: &HashMap<String, HashMap<(Option<Uuid>, String), StoredStat>>,
+) -> Vec<Vec<StatChange>> {
+    let nothing_stored = HashMap::new();
+    let mut batch = HashMap::new();
+    bundles.iter()
+        .map(|(bundle, unreadable, written)| {
+            let mut bundle = (*bundle).clone();
+            if !written.contains(&BundleTable::PlayerStatistics) {
+                bundle.stats.players.clear();
+            }
+            if !written.contains(&BundleTable::GlobalStatistics) {
+                bundle.stats.global = None;
+            }
+            let stored = stored_before.get(&bundle.namespace).unwrap_or(&nothing_stored);
+            apply_stored_stats(&bundle, stored, (*unreadable).clone(), &mut batch).0
+        })
+        .collect()
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum BundleTable {
+    Games,
+    PlayerStatistics,
+    GlobalStatistics,
+    AppliedBundles,
+}
+
+impl BundleTable {
+    fn name(self) -> &'static str {
+        match self {
+            BundleTable::Games => "games",
+            BundleTable::PlayerStatistics => "player_statistics",
+            BundleTable::GlobalStatistics => "global_statistics",
+            BundleTable::AppliedBundles => "applied_bundles",
+        }
+    }
+}
+
+fn bundle_rows_block(table: BundleTable, bundles: &[&BundleRows]) -> Result<Block, StatisticsDatabaseError> {
+    let mut block = Block::new();
+    for bundle in bundles {
+        match table {
+            BundleTable::Games => block.push(row! {
+                game_id: bundle.game_id,
+                namespace: bundle.namespace.clone(),
+                player_count: bundle.player_count,
+                server: bundle.server.clone(),
+                date_played: bundle.date_played,
+                source_server: bundle.source_server.clone(),
+            })?,
+            BundleTable::PlayerStatistics => {
+                for (player, key, value, ty) in &bundle.player_stats {
+                    block.push(row! {
+                        game_id: bundle.game_id,
+                        player_id: *player,
+                        namespace: bundle.namespace.clone(),
+                        key: key.clone(),
+                        value: *value,
+                        type: ty.clone(),
+                    })?;
+                }
+            }
+            BundleTable::GlobalStatistics => {
+                for (key, value, ty) in &bundle.global_stats {
+                    block.push(row! {
+                        game_id: bundle.game_id,
+                        namespace: bundle.namespace.clone(),
+                        key: key.clone(),
+                        value: *value,
+                        type: ty.clone(),
+                    })?;
+                }
+            }
+            BundleTable::AppliedBundles => {
+                if let Some(bundle_id) = bundle.bundle_id {
+                    block.push(row! {
+                        bundle_id: bundle_id,
+                        applied_at: bundle.date_played,
+                    })?;
+                }
+            }
+        }
+    }
+    Ok(block)
+}
+
+// Picks out a single rejected stat row, matching `rejected_row`.
+const REJECTED_ROW_COLUMNS: &str = "toUnixTimestamp(rejected_at), game_id, player_id, key, value";
+
+fn rejected_row(rejected_at: DateTime<Tz>, game_id: Uuid, player_id: Uuid, key: &str, value: f64) -> String {
+    format!("({}, toUUID('{}'), toUUID('{}'), {}, {})", rejected_at.timestamp(), game_id, player_id, quote(key), value)
+}
+
+// What's read for each stat to build a `StoredStat`.
+const STORED_STAT_COLUMNS: &str = "SUM(value) AS stored_total, MIN(value) AS stored_min, MAX(value) AS stored_max, COUNT() AS stored_count, argMin(value, recorded_at) AS stored_first";
+
+fn stored_stat(row: &Row<'_, Complex>) -> Result<StoredStat, StatisticsDatabaseError> {
+    Ok(StoredStat {
+        total: row.get("stored_total")?,
+        min: row.get("stored_min")?,
+        max: row.get("stored_max")?,
+        count: row.get("stored_count")?,
+        first: row.get("stored_first")?,
+    })
+}
+
+// Adds each player's latest username to a query ranking players by `value_column`. The join leaves
+// username empty for players we have no profile for.
+fn join_usernames(totals_sql: &str, value_column: &str) -> String {
+    format!(r#"
+        SELECT totals.player_id AS player_id, totals.{value} AS {value}, profiles.name AS username
+            FROM ({totals}) AS totals
+            LEFT JOIN (
+                SELECT player_id, argMax(username, last_seen) AS name
+                    FROM players
+                    GROUP BY player_id
+            ) AS profiles ON totals.player_id = profiles.player_id
+            ORDER BY {value} DESC"#, value = value_column, totals = totals_sql)
+}
+
+// Minigames with several modes can record each one as a sub-namespace, like `bedwars:solo`.
+const SUB_NAMESPACE_SEPARATOR: char = ':';
+
+fn root_namespace(namespace: &str) -> &str {
+    namespace.split(SUB_NAMESPACE_SEPARATOR).next().unwrap_or(namespace)
+}
+
+// Whether a row's namespace is the given namespace or one of its sub-namespaces.
+fn is_in_namespace(row_namespace: &str, namespace: &str) -> bool {
+    match row_namespace.strip_prefix(namespace) {
+        Some(rest) => rest.is_empty() || rest.starts_with(SUB_NAMESPACE_SEPARATOR),
+        None => false,
+    }
+}
+
+// Players left out of a bundle didn't have any of their stats applied. Only the first few errors are
+// kept, so that a bundle full of bad players doesn't make for a huge report.
+fn record_player_errors(report: &mut BundleUploadReport, bundle: &GameStatsBundle, failed_players: &[(Uuid, String)]) {
+    for (player, error) in failed_players {
+        let stats = bundle.stats.players.get(player).map(|stats| stats.len()).unwrap_or(0);
+        report.stats_applied = report.stats_applied.saturating_sub(stats);
+        report.players_failed += 1;
+        if report.player_errors.len() < MAX_REPORTED_PLAYER_ERRORS {
+            report.player_errors.push(format!("{}: {}", player, error));
+        }
+    }
+}
+
+// Nothing from a failed bundle was written, whatever it was going to apply.
+fn fail_report(report: &mut BundleUploadReport, error: &StatisticsDatabaseError) {
+    report.players_processed = 0;
+    report.stats_applied = 0;
+    report.error = Some(error.to_string());
+    report.error_kind = Some(error.upload_error_kind());
+}
+
+// A bundle that failed partway through only applied the stats in the tables it was written to.
+fn fail_written_report(report: &mut BundleUploadReport, bundle: &GameStatsBundle, failed: &FailedBundle) {
+    // Even just its game being written is enough for sending it again to count that game twice.
+    report.partly_written = !failed.written.is_empty();
+    if !wrote_stats(&failed.written) {
+        fail_report(report, &failed.error);
+        return;
+    }
+
+    if !failed.written.contains(&BundleTable::PlayerStatistics) {
+        let player_stats: usize = bundle.stats.players.values().map(|stats| stats.len()).sum();
+        report.stats_applied = report.stats_applied.saturating_sub(player_stats);
+        report.players_processed = 0;
+    }
+    if !failed.written.contains(&BundleTable::GlobalStatistics) {
+        let global_stats = bundle.stats.global.as_ref().map(|global| global.len()).unwrap_or(0);
+        report.stats_applied = report.stats_applied.saturating_sub(global_stats);
+    }
+    report.error = Some(failed.error.to_string());
+    report.error_kind = Some(failed.error.upload_error_kind());
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum FailedUploadAction {
+    // the server can send it again
+    Retry,
+    // someone needs to look at the database
+    Alert,
+    // set the bundle aside as rejected stats, since sending it again won't help
+    Quarantine,
+    Log,
+}
+
+// Both upload handlers go through this, so a bundle is dealt with the same however it was sent.
+// Retrying or quarantining a bundle that was partly written would count what was written twice, so
+// those only happen when none of it was.
+fn failed_upload_action(report: &BundleUploadReport) -> Option<FailedUploadAction> {
+    report.error.as_ref()?;
+    Some(match report.error_kind {
+        Some(UploadErrorKind::Connection) => FailedUploadAction::Alert,
+        _ if report.partly_written => FailedUploadAction::Log,
+        Some(UploadErrorKind::Retryable) => FailedUploadAction::Retry,
+        Some(UploadErrorKind::Invalid) => FailedUploadAction::Quarantine,
+        Some(UploadErrorKind::Other) | None => FailedUploadAction::Log,
+    })
+}
+
+// Manual adjustments, resets and decay are recorded under the nil game id, since they didn't come
+// from a game. Anything that looks at stats game by game should leave them out.
+const NOT_A_CORRECTION: &str = "game_id != toUUID('00000000-0000-0000-0000-000000000000')";
+
+// Players with any of these flags set are left off leaderboards and out of rankings.
+const HIDING_FLAGS: &[&str] = &["banned", "hidden"];
+
+// Leaves out totals that come to zero. Stats of other kinds are kept whatever their value.
+fn omit_zero_having_sql(omit_zero: bool) -> String {
+    if !omit_zero {
+        return String::new();
+    }
+
+    format!(r#"
+            HAVING
+                NOT endsWith(any(type), '_total')
+                OR multiIf(kind = 'int', int_total != 0, kind = 'decimal', {} != 0, float_total != 0)"#, decimal_total_sql())
+}
+
+// Sums decimal stats exactly, from the shortest string that gives back each stored value. A value
+// that doesn't convert fails the query rather than quietly counting as zero, and rows of other
+// kinds are never converted, so they can't fail it.
+fn decimal_total_sql() -> String {
+    format!(
+        "SUM(if(kind = 'decimal', toDecimal128(toString(value), {places}), toDecimal128(0, {places})))",
+        places = DECIMAL_PLACES,
+    )
+}
+
+// Keeps ints whole and decimals to their places, so that corrections leave their totals exact.
+fn round_to_type(ty: &str, value: f64) -> f64 {
+    if ty.starts_with("int_") {
+        value.round()
+    } else if ty.starts_with("decimal_") {
+        let scale = 10f64.powi(DECIMAL_PLACES as i32);
+        (value * scale).round() / scale
+    } else {
+        value
+    }
+}
+
+// Adjustments are rounded to what the stat's type can hold, like any other correction, but one that
+// rounds away to nothing is more likely a mistake than something to record.
+fn adjustment_delta(ty: &str, delta: f64) -> Result<f64, StatisticsDatabaseError> {
+    let rounded = round_to_type(ty, delta);
+    if rounded == 0.0 && delta != 0.0 {
+        return Err(StatisticsDatabaseError::InvalidRequest(format!("{} is too small an adjustment for a {} stat", delta, ty)));
+    }
+    Ok(rounded)
+}
+
+// Each row is relabelled with the network-wide stat it counts towards before summing, so that the
+// different names namespaces give a stat add up together. `None` without any aliases configured.
+fn network_stats_sql(player_id: &Uuid, stat_aliases: &HashMap<String, HashMap<String, String>>) -> Option<String> {
+    let mut branches = Vec::new();
+    let mut conditions = Vec::new();
+    for (stat, aliases) in stat_aliases {
+        if aliases.is_empty() {
+            continue;
+        }
+        let condition = aliased_stat_condition(aliases);
+        branches.push(format!("{}, {}", condition, quote(stat)));
+        conditions.push(condition);
+    }
+    if conditions.is_empty() {
+        return None;
+    }
+
+    Some(format!(r#"
+        SELECT multiIf({}, '') AS stat, SUM(value) AS total
+            FROM player_statistics
+            WHERE player_id = '{}' AND ({})
+            GROUP BY stat"#, branches.join(", "), player_id, conditions.join(" OR ")))
+}
+
+// Matches the rows of each namespace's own name for a stat, given as namespace -> stat.
+fn aliased_stat_condition(stats: &HashMap<String, String>) -> String {
+    stats.iter()
+        .map(|(namespace, stat)| format!("(namespace = {} AND key = {})", quote(namespace), quote(stat)))
+        .collect::<Vec<_>>()
+        .join(" OR ")
+}
+
+fn hidden_players_condition(include_flagged: bool) -> String {
+    if include_flagged {
+        return String::new();
+    }
+
+    let flags = HIDING_FLAGS.iter()
+        .map(|flag| quote(flag))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(r#" AND player_id NOT IN (
+        SELECT player_id
+            FROM player_flags
+            WHERE flag IN ({})
+            GROUP BY player_id, flag
+            HAVING argMax(value, updated_at) = 1
+    )"#, flags)
+}
+
+// Matches the namespace itself and, when rolling up, any of its sub-namespaces.
+fn namespace_condition(namespace: &str, rollup: bool) -> String {
+    if rollup {
+        let prefix = format!("{}{}", namespace, SUB_NAMESPACE_SEPARATOR);
+        format!("(namespace = {} OR startsWith(namespace, {}))", quote(namespace), quote(&prefix))
+    } else {
+        format!("namespace = {}", quote(namespace))
+    }
+}
+
+fn stat_metadata(config: &StatisticsConfig, namespace: &str, key: &str) -> StatMetadata {
+    let config = config.stat_metadata.get(namespace)
+        .and_then(|stats| stats.get(key));
+    StatMetadata::resolve(key, config)
+}
+
+// Which way a leaderboard sorts, when the request doesn't say, comes from the stat's metadata.
+fn higher_is_better(config: &StatisticsConfig, request: &GetLeaderboard) -> bool {
+    request.higher_is_better
+        .unwrap_or_else(|| stat_metadata(config, &request.namespace, &request.stat).higher_is_better)
+}
+
+// Limits stats to those from games played during the season, or nothing for the current season.
+fn season_games_condition(namespace: &str, configured: &[SeasonConfig], name: &str) -> Result<String, StatisticsDatabaseError> {
+    if name == CURRENT_SEASON {
+        return Ok(String::new());
+    }
+
+    let season = match configured.iter().find(|season| season.name == name) {
+        Some(season) => season,
+        None => return Err(StatisticsDatabaseError::InvalidRequest(format!("{} has no season called '{}'", namespace, name))),
+    };
+    Ok(format!(r#" AND game_id IN (
+        SELECT game_id
+            FROM games
+            WHERE namespace = {} AND date_played >= toDateTime({}) AND date_played < toDateTime({})
+    )"#, quote(namespace), season.start.timestamp(), season.end.timestamp()))
+}
+
+// With `restrict_to`, only those players are summed and sorted, so that their positions are their
+// ranks among each other rather than among everyone.
+fn leaderboard_sql(request: &GetLeaderboard, higher_is_better: bool) -> String {
+    let players = match &request.restrict_to {
+        Some(players) => {
+            let players = players.iter()
+                .map(|player| format!("'{}'", player))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!(" AND player_id IN ({})", players)
+        }
+        None => String::new(),
+    };
+
+    let order = if higher_is_better { "DESC" } else { "ASC" };
+    format!(r#"
+        SELECT player_id, SUM(value) AS total
+            FROM player_statistics
+            WHERE {} AND key = {}{}{}
+            GROUP BY player_id
+            ORDER BY total {}
+            LIMIT {}"#,
+        namespace_condition(&request.namespace, request.rollup), quote(&request.stat), players,
+        hidden_players_condition(request.include_flagged), order, request.limit.min(MAX_LEADERBOARD_SIZE))
+}
+
+fn check_cohort(players: &[Uuid]) -> Result<(), StatisticsDatabaseError> {
+    if players.len() > MAX_LEADERBOARD_COHORT {
+        return Err(StatisticsDatabaseError::InvalidRequest(format!("at most {} players can be ranked together", MAX_LEADERBOARD_COHORT)));
+    }
+    Ok(())
+}
+
+// Like personal bests, negating the stats where lower is better lets them all sort the same way, so
+// that one query can sort and limit every leaderboard at once.
+fn leaderboards_sql(condition: &str, stats: &[String], lower_is_better: &[&String], limit: u32) -> String {
+    let keys = stats.iter()
+        .map(|stat| quote(stat))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let lower_is_better = lower_is_better.iter()
+        .map(|stat| quote(stat))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!(r#"
+        SELECT key, player_id, SUM(value) AS total
+            FROM player_statistics
+            WHERE {} AND key IN ({})
+            GROUP BY key, player_id
+            ORDER BY key, if(has([{}], key), -total, total) DESC
+            LIMIT {} BY key"#, condition, keys, lower_is_better, limit.min(MAX_LEADERBOARD_SIZE))
+}
+
+// Matches only the listed stats of a namespace, so that nothing else is touched.
+fn stats_condition(namespace: &str, stats: &[String]) -> String {
+    let keys = stats.iter()
+        .map(|stat| quote(stat))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("namespace = {} AND key IN ({})", quote(namespace), keys)
+}
+
+// Matches a player's stats in any of the namespaces, or in every namespace if there are none.
+fn player_stats_condition(player_id: &Uuid, namespaces: &[String], rollup: bool) -> String {
+    if namespaces.is_empty() {
+        format!("player_id = '{}'", player_id)
+    } else {
+        let namespaces = namespaces.iter()
+            .map(|namespace| namespace_condition(namespace, rollup))
+            .collect::<Vec<_>>()
+            .join(" OR ");
+        format!("player_id = '{}' AND ({})", player_id, namespaces)
+    }
+}
+
+// Quotes a string for use as a literal in a query. Most values we interpolate are validated or
+// strictly formed (like uuids), but namespaces and keys in bundles come straight from game servers.
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+// The code ClickHouse reports when a query runs past its max_execution_time.
+const TIMEOUT_EXCEEDED: u32 = 159;
+
+#[derive(thiserror::Error, Debug)]
+pub enum StatisticsDatabaseError {
+    #[error("a database error occurred: {0}")]
+    ClickHouseError(clickhouse_rs::errors::Error),
+    #[error("couldn't reach the database: {0}")]
+    ConnectionError(clickhouse_rs::errors::Error),
+    // The database returned something we couldn't make sense of, like a column of the wrong type.
+    #[error("unexpected data from the database: {0}")]
+    CorruptData(clickhouse_rs::errors::Error),
+    #[error("the query took too long")]
+    Timeout,
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("invalid statistics config: {0}")]
+    InvalidConfig(String),
+    #[error("unknown error")]
+    UnknownError,
+}
+
+impl StatisticsDatabaseError {
+    pub fn upload_error_kind(&self) -> UploadErrorKind {
+        match self {
+            StatisticsDatabaseError::Timeout => UploadErrorKind::Retryable,
+            StatisticsDatabaseError::ConnectionError(_) => UploadErrorKind::Connection,
+            // Corrupt data is something wrong with what the database sent back, not with the bundle.
+            StatisticsDatabaseError::InvalidRequest(_) => UploadErrorKind::Invalid,
+            _ => UploadErrorKind::Other,
+        }
+    }
+}
+
+impl From<clickhouse_rs::errors::Error> for StatisticsDatabaseError {
+    fn from(error: clickhouse_rs::errors::Error) -> Self {
+        match error {
+            clickhouse_rs::errors::Error::Server(ref server) if server.code == TIMEOUT_EXCEEDED => StatisticsDatabaseError::Timeout,
+            error @ clickhouse_rs::errors::Error::Io(_) |
+            error @ clickhouse_rs::errors::Error::Connection(_) => StatisticsDatabaseError::ConnectionError(error),
+            error @ clickhouse_rs::errors::Error::FromSql(_) => StatisticsDatabaseError::CorruptData(error),
+            error => StatisticsDatabaseError::ClickHouseError(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::statistics::model::StatsBundle;
+
+    use super::*;
+
+    fn stats(values: &[(&str, i64)]) -> HashMap<String, StatValue> {
+        values.iter()
+            .map(|(key, value)| (key.to_string(), StatValue::Int(*value)))
+            .collect()
+    }
+
+    #[test]
+    fn changed_stats_are_gathered_per_player() {
+        let first = Uuid::new_v4();
+        let second = Uuid::new_v4();
+
+        let mut documents = Vec::new();
+        push_player_stat(&mut documents, first, "wins".to_owned(), 3.0);
+        push_player_stat(&mut documents, first, "kills".to_owned(), 10.0);
+        push_player_stat(&mut documents, second, "wins".to_owned(), 1.0);
+
+        assert_eq!(documents.len(), 2);
+        assert_eq!(documents[0].player, first);
+        assert_eq!(documents[0].stats.len(), 2);
+        assert_eq!(documents[0].stats["kills"], 10.0);
+        assert_eq!(documents[1].player, second);
+        assert_eq!(documents[1].stats["wins"], 1.0);
+    }
+
+    #[test]
+    fn forgetting_known_players_drops_the_stale_ones_first() {
+        let now = Instant::now() + PLAYER_PROFILE_REFRESH_INTERVAL * 2;
+        let stale = now - PLAYER_PROFILE_REFRESH_INTERVAL;
+        let mut known_players = HashMap::new();
+        for i in 0..MAX_KNOWN_PLAYERS {
+            let updated = if i % 2 == 0 { stale } else { now };
+            known_players.insert(Uuid::new_v4(), ("Gegy".to_owned(), updated));
+        }
+
+        forget_known_players(&mut known_players, now);
+        assert_eq!(known_players.len(), MAX_KNOWN_PLAYERS / 2);
+        assert!(known_players.values().all(|(_, updated)| *updated == now));
+
+        let fresh = (0..MAX_KNOWN_PLAYERS).map(|_| (Uuid::new_v4(), ("Gegy".to_owned(), now))).collect::<HashMap<_, _>>();
+        let mut known_players = fresh;
+        forget_known_players(&mut known_players, now);
+        assert!(known_players.is_empty());
+    }
+
+    #[test]
+    fn decimal_totals_never_fall_back_to_zero() {
+        let sql = decimal_total_sql();
+        assert!(sql.contains(&format!("toDecimal128(toString(value), {})", DECIMAL_PLACES)));
+        assert!(!sql.contains("OrZero"));
+    }
+
+    #[test]
+    fn keep_top_stats_keeps_exactly_the_largest() {
+        let mut stats = stats(&[("a", 3), ("b", 10), ("c", 1), ("d", 7), ("e", 5)]);
+        keep_top_stats(&mut stats, 3);
+
+        let mut kept: Vec<_> = stats.keys().cloned().collect();
+        kept.sort();
+        assert_eq!(kept, vec!["b", "d", "e"]);
+        assert_eq!(stats["b"], StatValue::Int(10));
+    }
+
+    #[test]
+    fn keep_top_stats_breaks_ties_by_key() {
+        let mut stats = stats(&[("z", 4), ("y", 4), ("x", 4)]);
+        keep_top_stats(&mut stats, 2);
+
+        let mut kept: Vec<_> = stats.keys().cloned().collect();
+        kept.sort();
+        assert_eq!(kept, vec!["x", "y"]);
+    }
+
+    fn failed_report(error: StatisticsDatabaseError) -> BundleUploadReport {
+        let mut report = BundleUploadReport::new(Uuid::new_v4());
+        fail_report(&mut report, &error);
+        report
+    }
+
+    fn bundle(players: usize, global: bool, bundle_id: Option<Uuid>) -> GameStatsBundle {
+        let mut stats = HashMap::new();
+        stats.insert("wins".to_owned(), UploadStat::IntTotal(1));
+        stats.insert("kills".to_owned(), UploadStat::IntTotal(4));
+        let players = (0..players).map(|_| (Uuid::new_v4(), stats.clone())).collect();
+        GameStatsBundle {
+            namespace: "bedwars".to_owned(),
+            stats: StatsBundle { global: if global { Some(stats) } else { None }, players },
+            bundle_id,
+            source_server: None,
+            version: 1,
+        }
+    }
+
+    #[test]
+    fn bundle_rows_are_written_together_or_on_their_own() {
+        let date_played = Utc::now().with_timezone(&Tz::GMT);
+        let first = BundleRows::new(Uuid::new_v4(), "bedwars-1", &bundle(3, true, Some(Uuid::new_v4())), &HashSet::new(), date_played);
+        let second = BundleRows::new(Uuid::new_v4(), "bedwars-2", &bundle(1, false, None), &HashSet::new(), date_played);
+
+        let rows = |table, bundles: &[&BundleRows]| bundle_rows_block(table, bundles).unwrap().row_count();
+        assert_eq!(rows(BundleTable::Games, &[&first, &second]), 2);
+        assert_eq!(rows(BundleTable::PlayerStatistics, &[&first, &second]), 8);
+        assert_eq!(rows(BundleTable::PlayerStatistics, &[&first]), 6);
+        assert_eq!(rows(BundleTable::PlayerStatistics, &[&second]), 2);
+        assert_eq!(rows(BundleTable::GlobalStatistics, &[&first, &second]), 2);
+        assert_eq!(rows(BundleTable::GlobalStatistics, &[&second]), 0);
+        assert_eq!(rows(BundleTable::AppliedBundles, &[&first, &second]), 1);
+    }
+
+    #[test]
+    fn replayed_rows_are_picked_out_exactly() {
+        let rejected_at = Tz::GMT.timestamp(1_600_000_000, 0);
+        let game_id = Uuid::nil();
+        let player_id = Uuid::nil();
+
+        let row = rejected_row(rejected_at, game_id, player_id, "wins", 2.5);
+        assert_eq!(row, format!("(1600000000, toUUID('{}'), toUUID('{}'), 'wins', 2.5)", game_id, player_id));
+    }
+
+    #[test]
+    fn bundles_sent_twice_are_applied_once() {
+        let first = bundle(1, false, Some(Uuid::new_v4()));
+        let player = *first.stats.players.keys().next().unwrap();
+        let uploads = vec![first.clone(), first];
+
+        let mut applied = HashSet::new();
+        let mut batch = HashMap::new();
+        let mut changes = Vec::new();
+        for bundle in &uploads {
+            if already_applied(&applied, bundle).is_some() {
+                continue;
+            }
+            mark_applied(&mut applied, bundle);
+            changes.extend(apply_stored_stats(bundle, &HashMap::new(), HashMap::new(), &mut batch).0);
+        }
+
+        let wins = changes.iter().filter(|change| change.key == "wins").collect::<Vec<_>>();
+        assert_eq!(wins.len(), 1);
+        assert_eq!((wins[0].old_value, wins[0].new_value), (0.0, 1.0));
+        assert_eq!(batch[&("bedwars".to_owned(), Some(player), "wins".to_owned())].total, 1.0);
+    }
+
+    #[test]
+    fn bundles_are_only_marked_applied_when_told_to() {
+        let with_id = bundle(1, false, Some(Uuid::new_v4()));
+        let mut applied = HashSet::new();
+        assert_eq!(already_applied(&applied, &with_id), None);
+
+        mark_applied(&mut applied, &with_id);
+        assert_eq!(already_applied(&applied, &with_id), with_id.bundle_id);
+
+        let without_id = bundle(1, false, None);
+        mark_applied(&mut applied, &without_id);
+        assert_eq!(already_applied(&applied, &without_id), None);
+        assert_eq!(applied.len(), 1);
+    }
+
+    #[test]
+    fn only_tables_with_rows_count_as_written() {
+        let date_played = Utc::now().with_timezone(&Tz::GMT);
+        let rows = BundleRows::new(Uuid::new_v4(), "bedwars-1", &bundle(1, false, None), &HashSet::new(), date_played);
+
+        let mut written = Vec::new();
+        for &table in &[BundleTable::Games, BundleTable::PlayerStatistics, BundleTable::GlobalStatistics, BundleTable::AppliedBundles] {
+            rows.record_written(table, &mut written);
+        }
+        assert_eq!(written, vec![BundleTable::Games, BundleTable::PlayerStatistics]);
+        assert!(wrote_stats(&written));
+        assert!(!wrote_stats(&[BundleTable::Games]));
+    }
+
+    #[test]
+    fn failed_bundles_are_left_out_of_later_changes() {
+        let first = bundle(1, true, None);
+        let second = first.clone();
+        let player = *first.stats.players.keys().next().unwrap();
+
+        let mut stored = HashMap::new();
+        stored.insert((Some(player), "wins".to_owned()), StoredStat { total: 10.0, min: 1.0, max: 1.0, count: 10, first: 1.0 });
+        let mut stored_before = HashMap::new();
+        stored_before.insert("bedwars".to_owned(), stored);
+
+        let unreadable = HashMap::new();
+        let all = [BundleTable::Games, BundleTable::PlayerStatistics, BundleTable::GlobalStatistics];
+        let change = |changes: &[StatChange], player: Option<Uuid>| {
+            let change = changes.iter().find(|change| change.player == player && change.key == "wins").unwrap();
+            (change.old_value, change.new_value)
+        };
+
+        // the first bundle wasn't written at all
+        let changes = written_stat_changes(&[(&first, &unreadable, &all[..0]), (&second, &unreadable, &all[..])], &stored_before);
+        assert!(changes[0].is_empty());
+        assert_eq!(change(&changes[1], Some(player)), (10.0, 11.0));
+        assert_eq!(change(&changes[1], None), (0.0, 1.0));
+
+        // only the first bundle's player stats were written
+        let changes = written_stat_changes(&[(&first, &unreadable, &all[..2]), (&second, &unreadable, &all[..])], &stored_before);
+        assert_eq!(changes[0].len(), 2);
+        assert_eq!(change(&changes[1], Some(player)), (11.0, 12.0));
+        assert_eq!(change(&changes[1], None), (0.0, 1.0));
+    }
+
+    #[test]
+    fn partly_written_bundles_report_what_was_applied() {
+        let bundle = bundle(2, true, None);
+        let game_id = Uuid::new_v4();
+
+        let mut report = BundleUploadReport::accepted(game_id, &bundle, 0);
+        let failed = FailedBundle {
+            game_id,
+            error: StatisticsDatabaseError::Timeout,
+            written: vec![BundleTable::Games, BundleTable::PlayerStatistics],
+        };
+        fail_written_report(&mut report, &bundle, &failed);
+        assert_eq!((report.players_processed, report.stats_applied), (2, 4));
+        assert!(report.error.is_some());
+
+        let mut report = BundleUploadReport::accepted(game_id, &bundle, 0);
+        let failed = FailedBundle { written: vec![BundleTable::Games], ..failed };
+        fail_written_report(&mut report, &bundle, &failed);
+        assert_eq!((report.players_processed, report.stats_applied), (0, 0));
+    }
+
+    #[test]
+    fn unreadable_players_are_left_out_of_the_bundle() {
+        let bundle = bundle(3, true, None);
+        let mut players = bundle.stats.players.keys().copied().collect::<Vec<_>>();
+        players.sort();
+        let broken = players[1];
+
+        let mut stored = HashMap::new();
+        stored.insert((Some(players[0]), "wins".to_owned()), StoredStat { total: 10.0, min: 1.0, max: 1.0, count: 10, first: 1.0 });
+        let mut unreadable = HashMap::new();
+        unreadable.insert(broken, "corrupt value".to_owned());
+
+        let mut batch = HashMap::new();
+        let (changes, failed) = apply_stored_stats(&bundle, &stored, unreadable, &mut batch);
+
+        assert_eq!(failed, vec![(broken, "corrupt value".to_owned())]);
+        assert!(changes.iter().all(|change| change.player != Some(broken)));
+        assert_eq!(changes.iter().filter(|change| change.player.is_some()).count(), 4);
+        assert_eq!(changes.iter().filter(|change| change.player.is_none()).count(), 2);
+
+        let wins = changes.iter()
+            .find(|change| change.player == Some(players[0]) && change.key == "wins")
+            .unwrap();
+        assert_eq!((wins.old_value, wins.new_value), (10.0, 11.0));
+        assert!(!batch.contains_key(&("bedwars".to_owned(), Some(broken), "wins".to_owned())));
+    }
+
+    #[test]
+    fn reports_count_every_failed_player_but_keep_only_a_few_errors() {
+        let bundle = bundle(MAX_REPORTED_PLAYER_ERRORS + 2, false, None);
+        let mut report = BundleUploadReport::accepted(Uuid::new_v4(), &bundle, 0);
+        let failed = bundle.stats.players.keys()
+            .take(MAX_REPORTED_PLAYER_ERRORS + 1)
+            .map(|player| (*player, "corrupt value".to_owned()))
+            .collect::<Vec<_>>();
+
+        record_player_errors(&mut report, &bundle, &failed);
+
+        assert_eq!(report.players_failed, MAX_REPORTED_PLAYER_ERRORS + 1);
+        assert_eq!(report.player_errors.len(), MAX_REPORTED_PLAYER_ERRORS);
+        assert_eq!(report.stats_applied, 2);
+        assert!(report.player_errors[0].ends_with(": corrupt value"));
+    }
+
+    #[test]
+    fn failed_reports_apply_nothing() {
+        let bundle = bundle(1, false, None);
+
+        let mut report = BundleUploadReport::accepted(Uuid::new_v4(), &bundle, 0);
+        assert_eq!(report.players_processed, 1);
+        assert_eq!(report.stats_applied, 2);
+
+        fail_report(&mut report, &StatisticsDatabaseError::Timeout);
+        assert_eq!(report.players_processed, 0);
+        assert_eq!(report.stats_applied, 0);
+        assert!(report.error.is_some());
+    }
+
+    #[test]
+    fn timed_out_uploads_can_be_retried() {
+        let report = failed_report(StatisticsDatabaseError::Timeout);
+        assert_eq!(report.error_kind, Some(UploadErrorKind::Retryable));
+        assert_eq!(failed_upload_action(&report), Some(FailedUploadAction::Retry));
+    }
+
+    #[test]
+    fn lost_connections_raise_an_alert() {
+        let report = BundleUploadReport {
+            error_kind: Some(UploadErrorKind::Connection),
+            ..BundleUploadReport::failed(Uuid::new_v4(), "connection reset".to_owned())
+        };
+        assert_eq!(failed_upload_action(&report), Some(FailedUploadAction::Alert));
+    }
+
+    #[test]
+    fn invalid_uploads_are_quarantined() {
+        let report = failed_report(StatisticsDatabaseError::InvalidRequest("bad stat".to_owned()));
+        assert_eq!(report.error_kind, Some(UploadErrorKind::Invalid));
+        assert_eq!(failed_upload_action(&report), Some(FailedUploadAction::Quarantine));
+    }
+
+    #[test]
+    fn other_failures_are_only_logged() {
+        let report = failed_report(StatisticsDatabaseError::UnknownError);
+        assert_eq!(report.error_kind, Some(UploadErrorKind::Other));
+        assert_eq!(failed_upload_action(&report), Some(FailedUploadAction::Log));
+
+        // Bundles turned away before being written have no kind.
+        let report = BundleUploadReport::failed(Uuid::new_v4(), "rate limited".to_owned());
+        assert_eq!(failed_upload_action(&report), Some(FailedUploadAction::Log));
+    }
+
+    #[test]
+    fn partly_written_uploads_are_neither_retried_nor_quarantined() {
+        let bundle = bundle(1, true, None);
+        let game_id = Uuid::new_v4();
+        for error in vec![StatisticsDatabaseError::Timeout, StatisticsDatabaseError::InvalidRequest("bad stat".to_owned())] {
+            let mut report = BundleUploadReport::accepted(game_id, &bundle, 0);
+            let failed = FailedBundle { game_id, error, written: vec![BundleTable::Games] };
+            fail_written_report(&mut report, &bundle, &failed);
+            assert!(report.partly_written);
+            assert_eq!(failed_upload_action(&report), Some(FailedUploadAction::Log));
+        }
+
+        let mut report = BundleUploadReport::accepted(game_id, &bundle, 0);
+        let failed = FailedBundle { game_id, error: StatisticsDatabaseError::Timeout, written: Vec::new() };
+        fail_written_report(&mut report, &bundle, &failed);
+        assert!(!report.partly_written);
+        assert_eq!(failed_upload_action(&report), Some(FailedUploadAction::Retry));
+    }
+
+    #[test]
+    fn successful_uploads_need_nothing_done() {
+        let report = BundleUploadReport::new(Uuid::new_v4());
+        assert_eq!(failed_upload_action(&report), None);
+    }
+
+    #[test]
+    fn keep_top_stats_leaves_small_documents_alone() {
+        let mut stats = stats(&[("a", 1), ("b", 2)]);
+        keep_top_stats(&mut stats, 5);
+        assert_eq!(stats.len(), 2);
+    }
+
+    #[test]
+    fn stat_change_ids_carry_on_from_the_seed() {
+        let player = Uuid::new_v4();
+        let change = |player, key: &str| StatChange { player, key: key.to_owned(), old_value: 0.0, new_value: 1.0 };
+
+        // as if there were already 41 player stat rows from before a restart
+        let mut next_id = 42;
+        let changed = number_stat_changes("bedwars", vec![change(Some(player), "kills"), change(None, "games_played"), change(Some(player), "wins")], &mut next_id);
+
+        assert_eq!(changed.iter().map(|changed| (changed.id, changed.stat.as_str())).collect::<Vec<_>>(), vec![(42, "kills"), (43, "wins")]);
+        assert!(changed.iter().all(|changed| changed.uuid == player && changed.namespace == "bedwars"));
+        assert_eq!(next_id, 44);
+
+        let changed = number_stat_changes("bedwars", vec![change(Some(player), "kills")], &mut next_id);
+        assert_eq!(changed[0].id, 44);
+    }
+
+    fn statistics_config(extra: serde_json::Value) -> StatisticsConfig {
+        let mut config = serde_json::json!({
+            "database_url": "tcp://localhost:9000",
+            "database_name": "nucleoid_statistics",
+        });
+        if let (Some(config), Some(extra)) = (config.as_object_mut(), extra.as_object()) {
+            config.extend(extra.clone());
+        }
+        serde_json::from_value(config).unwrap()
+    }
+
+    fn rejected_keys(rejected: &[RejectedStat]) -> Vec<&str> {
+        let mut keys = rejected.iter().map(|rejected| rejected.key.as_str()).collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys
+    }
+
+    #[test]
+    fn stats_off_the_allowlist_are_rejected() {
+        let config = statistics_config(serde_json::json!({ "stat_allowlists": { "bedwars": ["wins"] } }));
+        let mut bundle = bundle(2, true, None);
+
+        let rejected = reject_invalid_stats(&config, &mut bundle);
+
+        // kills for both players and the global stats
+        assert_eq!(rejected_keys(&rejected), vec!["kills", "kills", "kills"]);
+        assert!(rejected.iter().all(|rejected| rejected.reason == "'kills' is not on the allowlist for bedwars"));
+        for stats in bundle.stats.players.values().chain(bundle.stats.global.iter()) {
+            assert_eq!(stats.keys().collect::<Vec<_>>(), vec!["wins"]);
+        }
+    }
+
+    #[test]
+    fn allowlisted_stats_are_kept() {
+        let config = statistics_config(serde_json::json!({ "stat_allowlists": { "bedwars": ["wins", "kills"] } }));
+        let mut bundle = bundle(2, true, None);
+
+        assert!(reject_invalid_stats(&config, &mut bundle).is_empty());
+        assert!(bundle.stats.players.values().all(|stats| stats.len() == 2));
+    }
+
+    #[test]
+    fn namespaces_without_an_allowlist_accept_anything() {
+        let config = statistics_config(serde_json::json!({ "stat_allowlists": { "skywars": ["wins"] } }));
+        let mut bundle = bundle(2, true, None);
+
+        assert!(reject_invalid_stats(&config, &mut bundle).is_empty());
+        assert!(bundle.stats.players.values().all(|stats| stats.len() == 2));
+        assert_eq!(bundle.stats.global.map(|global| global.len()), Some(2));
+    }
+
+    #[test]
+    fn player_stats_can_be_read_from_several_namespaces() {
+        let player = Uuid::new_v4();
+        let namespaces = vec!["bedwars".to_owned(), "skywars".to_owned()];
+
+        assert_eq!(
+            player_stats_condition(&player, &namespaces, false),
+            format!("player_id = '{}' AND (namespace = 'bedwars' OR namespace = 'skywars')", player),
+        );
+        assert_eq!(
+            player_stats_condition(&player, &namespaces[..1], true),
+            format!("player_id = '{}' AND ((namespace = 'bedwars' OR startsWith(namespace, 'bedwars{}')))", player, SUB_NAMESPACE_SEPARATOR),
+        );
+        assert_eq!(player_stats_condition(&player, &[], false), format!("player_id = '{}'", player));
+    }
+
+    #[test]
+    fn non_finite_stats_are_rejected_and_the_rest_kept() {
+        let config = statistics_config(serde_json::json!({}));
+        let mut bundle = bundle(1, true, None);
+        let player = *bundle.stats.players.keys().next().unwrap();
+        let stats = bundle.stats.players.get_mut(&player).unwrap();
+        stats.insert("nan".to_owned(), UploadStat::FloatTotal(f64::NAN));
+        stats.insert("infinity".to_owned(), UploadStat::FloatMax(f64::INFINITY));
+        if let Some(global) = &mut bundle.stats.global {
+            global.insert("negative_infinity".to_owned(), UploadStat::FloatMin(f64::NEG_INFINITY));
+        }
+
+        let rejected = reject_invalid_stats(&config, &mut bundle);
+
+        assert_eq!(rejected_keys(&rejected), vec!["infinity", "nan", "negative_infinity"]);
+        let reason = |key: &str| rejected.iter().find(|rejected| rejected.key == key).map(|rejected| rejected.reason.as_str());
+        assert_eq!(reason("nan"), Some("'nan' has a value of NaN, which isn't a finite number"));
+        assert_eq!(reason("infinity"), Some("'infinity' has a value of inf, which isn't a finite number"));
+        assert_eq!(reason("negative_infinity"), Some("'negative_infinity' has a value of -inf, which isn't a finite number"));
+        assert!(rejected.iter().find(|rejected| rejected.key == "nan").unwrap().player == Some(player));
+
+        // the clean stats are still there to be applied
+        let mut kept = bundle.stats.players[&player].keys().collect::<Vec<_>>();
+        kept.sort_unstable();
+        assert_eq!(kept, vec!["kills", "wins"]);
+        assert_eq!(bundle.stats.global.map(|global| global.len()), Some(2));
+    }
+
+    fn first_win_upload(player: Uuid, first_win: i32) -> GameStatsUpload {
+        let mut stats = HashMap::new();
+        stats.insert("first_win".to_owned(), UploadStat::IntFirst(first_win));
+        stats.insert("wins".to_owned(), UploadStat::IntTotal(1));
+        let mut players = HashMap::new();
+        players.insert(player, stats);
+
+        let mut bundle = bundle(0, false, None);
+        bundle.stats.players = players;
+        GameStatsUpload { game_id: Uuid::new_v4(), bundle }
+    }
+
+    #[test]
+    fn set_once_stats_already_set_are_dropped() {
+        let player = Uuid::new_v4();
+        let mut uploads = vec![first_win_upload(player, 200)];
+
+        let mut already_set = HashSet::new();
+        already_set.insert((player, "bedwars".to_owned(), "first_win".to_owned()));
+        drop_set_stats(&mut uploads, already_set, HashSet::new());
+
+        // the second bundle leaves the first win alone but still counts the win
+        let stats = &uploads[0].bundle.stats.players[&player];
+        assert!(!stats.contains_key("first_win"));
+        assert!(matches!(stats["wins"], UploadStat::IntTotal(1)));
+    }
+
+    #[test]
+    fn only_the_first_upload_in_a_batch_sets_a_stat() {
+        let player = Uuid::new_v4();
+        let mut uploads = vec![first_win_upload(player, 100), first_win_upload(player, 200)];
+
+        drop_set_stats(&mut uploads, HashSet::new(), HashSet::new());
+
+        assert!(matches!(uploads[0].bundle.stats.players[&player]["first_win"], UploadStat::IntFirst(100)));
+        assert!(!uploads[1].bundle.stats.players[&player].contains_key("first_win"));
+    }
+
+    #[test]
+    fn every_leaderboard_is_sorted_and_limited_on_its_own() {
+        let stats = vec!["wins".to_owned(), "fastest_win".to_owned()];
+        let sql = leaderboards_sql("namespace = 'bedwars'", &stats, &[&stats[1]], 10);
+        let sql = sql.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        assert!(sql.contains("WHERE namespace = 'bedwars' AND key IN ('wins', 'fastest_win')"));
+        // the fastest win board sorts lowest first, like its own leaderboard would
+        assert!(sql.contains("ORDER BY key, if(has(['fastest_win'], key), -total, total) DESC"));
+        assert!(sql.ends_with("LIMIT 10 BY key"));
+    }
+
+    #[test]
+    fn leaderboards_are_capped_like_a_single_leaderboard() {
+        let stats = vec!["wins".to_owned()];
+        let sql = leaderboards_sql("namespace = 'bedwars'", &stats, &[], MAX_LEADERBOARD_SIZE + 1);
+        assert!(sql.contains("has([], key)"));
+        assert!(sql.ends_with(&format!("LIMIT {} BY key", MAX_LEADERBOARD_SIZE)));
+    }
+
+    #[test]
+    fn pseudonyms_are_stable_for_a_salt() {
+        let player = Uuid::new_v4();
+        let pseudonym = pseudonymize("secret", &player);
+
+        assert_ne!(pseudonym, player);
+        assert_eq!(pseudonymize("secret", &player), pseudonym);
+        assert_ne!(pseudonymize("other secret", &player), pseudonym);
+        assert_ne!(pseudonymize("secret", &Uuid::new_v4()), pseudonym);
+    }
+
+    #[test]
+    fn anonymized_documents_carry_no_username_or_uuid() {
+        let player = Uuid::new_v4();
+        let mut stats = HashMap::new();
+        stats.insert("wins".to_owned(), 12.0);
+        let document = PlayerStatsDocument { player: pseudonymize("secret", &player), stats };
+
+        let json = serde_json::to_value(&document).unwrap();
+        let fields = json.as_object().unwrap();
+        assert_eq!(fields.keys().collect::<Vec<_>>(), vec!["player", "stats"]);
+        assert_eq!(json["player"], pseudonymize("secret", &player).to_string());
+        assert!(!json.to_string().contains(&player.to_string()));
+        // the stats themselves are left as they were
+        assert_eq!(json["stats"]["wins"], 12.0);
+    }
+
+    #[test]
+    fn bundles_over_the_size_limits_are_refused() {
+        let config = statistics_config(serde_json::json!({ "max_players_per_bundle": 2, "max_stats_per_player": 2 }));
+
+        assert_eq!(check_bundle_size(&config, &bundle(2, true, None)), Ok(()));
+        assert_eq!(check_bundle_size(&config, &bundle(3, false, None)), Err("bundle has 3 players, more than the limit of 2".to_owned()));
+
+        let mut too_many_stats = bundle(1, false, None);
+        for stats in too_many_stats.stats.players.values_mut() {
+            stats.insert("deaths".to_owned(), UploadStat::IntTotal(2));
+        }
+        assert_eq!(check_bundle_size(&config, &too_many_stats), Err("bundle has 3 stats for one player, more than the limit of 2".to_owned()));
+
+        // global stats count as one more player
+        let mut too_many_global = bundle(1, true, None);
+        if let Some(global) = &mut too_many_global.stats.global {
+            global.insert("deaths".to_owned(), UploadStat::IntTotal(2));
+        }
+        assert!(check_bundle_size(&config, &too_many_global).is_err());
+    }
+
+    #[test]
+    fn the_default_size_limits_are_generous() {
+        assert_eq!(check_bundle_size(&statistics_config(serde_json::json!({})), &bundle(100, true, None)), Ok(()));
+    }
+
+    fn leaderboard_request(stat: &str, restrict_to: Option<Vec<Uuid>>) -> GetLeaderboard {
+        GetLeaderboard {
+            namespace: "bedwars".to_owned(),
+            stat: stat.to_owned(),
+            limit: 10,
+            rollup: false,
+            restrict_to,
+            include_flagged: true,
+            higher_is_better: None,
+        }
+    }
+
+    #[test]
+    fn friends_leaderboards_rank_only_the_given_players() {
+        let friends = vec![Uuid::new_v4(), Uuid::new_v4()];
+        let sql = leaderboard_sql(&leaderboard_request("wins", Some(friends.clone())), true);
+        let sql = sql.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        let cohort = format!("player_id IN ('{}', '{}')", friends[0], friends[1]);
+        assert!(sql.contains(&format!("WHERE namespace = 'bedwars' AND key = 'wins' AND {} GROUP BY player_id", cohort)));
+        assert!(sql.ends_with("ORDER BY total DESC LIMIT 10"));
+
+        let everyone = leaderboard_sql(&leaderboard_request("wins", None), true);
+        assert!(!everyone.contains("player_id IN"));
+    }
+
+    #[test]
+    fn friends_leaderboards_are_capped() {
+        let cohort = (0..MAX_LEADERBOARD_COHORT).map(|_| Uuid::new_v4()).collect::<Vec<_>>();
+        assert!(check_cohort(&cohort).is_ok());
+
+        let too_many = (0..=MAX_LEADERBOARD_COHORT).map(|_| Uuid::new_v4()).collect::<Vec<_>>();
+        assert!(matches!(check_cohort(&too_many), Err(StatisticsDatabaseError::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn structured_stat_keys_are_accepted_whole() {
+        let config = statistics_config(serde_json::json!({}));
+        assert_eq!(check_stat(&config, "bedwars", "wins.nether", &UploadStat::IntTotal(1)), None);
+        assert_eq!(check_stat(&config, "bedwars", "kills.bow.long_range", &UploadStat::IntTotal(1)), None);
+    }
+
+    #[test]
+    fn structured_stat_keys_must_name_every_part() {
+        let config = statistics_config(serde_json::json!({}));
+        for key in &["wins.", ".wins", "wins..nether", "."] {
+            assert_eq!(
+                check_stat(&config, "bedwars", key, &UploadStat::IntTotal(1)),
+                Some(format!("'{}' has an empty part in its name", key)),
+            );
+        }
+    }
+
+    #[test]
+    fn structured_stat_keys_are_checked_against_the_allowlist_whole() {
+        let config = statistics_config(serde_json::json!({ "stat_allowlists": { "bedwars": ["wins.nether"] } }));
+        assert_eq!(check_stat(&config, "bedwars", "wins.nether", &UploadStat::IntTotal(1)), None);
+        assert!(check_stat(&config, "bedwars", "wins", &UploadStat::IntTotal(1)).is_some());
+    }
+
+    fn seasons() -> Vec<SeasonConfig> {
+        serde_json::from_value(serde_json::json!([
+            { "name": "spring", "start": "2021-03-01T00:00:00Z", "end": "2021-06-01T00:00:00Z" },
+            { "name": "summer", "start": "2021-06-01T00:00:00Z", "end": "2021-09-01T00:00:00Z" },
+        ])).unwrap()
+    }
+
+    #[test]
+    fn past_seasons_only_count_games_played_during_them() {
+        let seasons = seasons();
+        let condition = |name| season_games_condition("bedwars", &seasons, name).unwrap().split_whitespace().collect::<Vec<_>>().join(" ");
+
+        let spring = condition("spring");
+        assert!(spring.contains(&format!("date_played >= toDateTime({}) AND date_played < toDateTime({})", seasons[0].start.timestamp(), seasons[0].end.timestamp())));
+        assert!(spring.contains("WHERE namespace = 'bedwars'"));
+
+        let summer = condition("summer");
+        assert!(summer.contains(&format!("date_played >= toDateTime({}) AND date_played < toDateTime({})", seasons[1].start.timestamp(), seasons[1].end.timestamp())));
+
+        // the current season is everything, corrections included
+        assert_eq!(condition(CURRENT_SEASON), "");
+    }
+
+    #[test]
+    fn unknown_seasons_are_refused() {
+        match season_games_condition("bedwars", &seasons(), "winter") {
+            Err(StatisticsDatabaseError::InvalidRequest(message)) => assert_eq!(message, "bedwars has no season called 'winter'"),
+            result => panic!("expected an invalid request, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn lower_is_better_stats_rank_lowest_first() {
+        let config = statistics_config(serde_json::json!({
+            "stat_metadata": { "bedwars": { "fastest_win": { "higher_is_better": false } } },
+        }));
+
+        let fastest_win = leaderboard_request("fastest_win", None);
+        assert!(!higher_is_better(&config, &fastest_win));
+        assert!(leaderboard_sql(&fastest_win, higher_is_better(&config, &fastest_win)).contains("ORDER BY total ASC"));
+
+        let wins = leaderboard_request("wins", None);
+        assert!(higher_is_better(&config, &wins));
+        assert!(leaderboard_sql(&wins, higher_is_better(&config, &wins)).contains("ORDER BY total DESC"));
+    }
+
+    #[test]
+    fn callers_can_override_the_sort_direction() {
+        let config = statistics_config(serde_json::json!({
+            "stat_metadata": { "bedwars": { "fastest_win": { "higher_is_better": false } } },
+        }));
+
+        let slowest_win = GetLeaderboard { higher_is_better: Some(true), ..leaderboard_request("fastest_win", None) };
+        assert!(higher_is_better(&config, &slowest_win));
+
+        let fewest_wins = GetLeaderboard { higher_is_better: Some(false), ..leaderboard_request("wins", None) };
+        assert!(!higher_is_better(&config, &fewest_wins));
+    }
+
+    #[test]
+    fn resets_only_touch_the_listed_stats() {
+        let stats = vec!["wins".to_owned(), "it's".to_owned()];
+        assert_eq!(stats_condition("bedwars", &stats), "namespace = 'bedwars' AND key IN ('wins', 'it\\'s')");
+
+        let condition = stats_condition("bedwars", &stats[..1]);
+        assert!(condition.ends_with("key IN ('wins')"));
+        assert!(!condition.contains("kills"));
+    }
+
+    #[test]
+    fn reset_rows_cancel_the_total_exactly() {
+        // sums of float rows can drift, but the reset row is rounded back to what the type can hold
+        let total = 0.1 + 0.2;
+        assert_eq!(round_to_type("int_total", 2.9999999), 3.0);
+        assert_eq!(round_to_type("decimal_total", total), 0.3);
+        assert_eq!(round_to_type("float_total", total), total);
+    }
+
+    #[test]
+    fn adjustments_are_rounded_to_the_stat_type() {
+        assert_eq!(adjustment_delta("int_total", 2.6).unwrap(), 3.0);
+        assert_eq!(adjustment_delta("int_total", -1.2).unwrap(), -1.0);
+        assert_eq!(adjustment_delta("decimal_total", 0.1 + 0.2).unwrap(), 0.3);
+        assert_eq!(adjustment_delta("float_total", 0.25).unwrap(), 0.25);
+        assert!(adjustment_delta("int_total", 0.4).is_err());
+    }
+
+    #[test]
+    fn zero_totals_are_only_left_out_when_asked() {
+        assert_eq!(omit_zero_having_sql(false), "");
+
+        let having = omit_zero_having_sql(true).split_whitespace().collect::<Vec<_>>().join(" ");
+        assert!(having.starts_with("HAVING NOT endsWith(any(type), '_total') OR"));
+        // each kind of total is compared as the type it's summed as
+        assert!(having.contains("kind = 'int', int_total != 0"));
+        assert!(having.contains(&format!("kind = 'decimal', {} != 0", decimal_total_sql())));
+        assert!(having.ends_with("float_total != 0)"));
+    }
+
+    #[test]
+    fn aliased_stats_are_summed_across_namespaces() {
+        let mut kills = HashMap::new();
+        kills.insert("bedwars".to_owned(), "kills".to_owned());
+        kills.insert("skywars".to_owned(), "eliminations".to_owned());
+        let mut aliases = HashMap::new();
+        aliases.insert("kills".to_owned(), kills);
+
+        let player = Uuid::new_v4();
+        let sql = network_stats_sql(&player, &aliases).unwrap();
+        let sql = sql.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        // both namespaces' names count towards the one network-wide stat
+        let bedwars = "(namespace = 'bedwars' AND key = 'kills')";
+        let skywars = "(namespace = 'skywars' AND key = 'eliminations')";
+        let condition = aliased_stat_condition(&aliases["kills"]);
+        assert!(condition == format!("{} OR {}", bedwars, skywars) || condition == format!("{} OR {}", skywars, bedwars));
+        assert!(sql.contains(&format!("SELECT multiIf({}, 'kills', '') AS stat, SUM(value) AS total", condition)));
+        assert!(sql.contains(&format!("WHERE player_id = '{}' AND ({}) GROUP BY stat", player, condition)));
+    }
+
+    #[test]
+    fn network_stats_need_aliases() {
+        let mut aliases = HashMap::new();
+        assert_eq!(network_stats_sql(&Uuid::new_v4(), &aliases), None);
+
+        aliases.insert("kills".to_owned(), HashMap::new());
+        assert_eq!(network_stats_sql(&Uuid::new_v4(), &aliases), None);
+    }
+
+    fn stat_changed(id: u64) -> StatChanged {
+        StatChanged { id, uuid: Uuid::new_v4(), namespace: "bedwars".to_owned(), stat: "wins".to_owned(), old_value: 0.0, new_value: 1.0 }
+    }
+
+    #[test]
+    fn subscribers_are_told_when_missed_changes_are_gone() {
+        let recent = (5..10).map(stat_changed).collect::<VecDeque<_>>();
+        assert!(!missed_unbuffered_changes(&recent, 10, 4));
+        assert!(!missed_unbuffered_changes(&recent, 10, 9));
+        assert!(missed_unbuffered_changes(&recent, 10, 3));
+        assert!(missed_unbuffered_changes(&recent, 10, 10));
+
+        // after a restart nothing is buffered, so only a subscriber that saw everything is caught up
+        let restarted = VecDeque::new();
+        assert!(!missed_unbuffered_changes(&restarted, 10, 9));
+        assert!(missed_unbuffered_changes(&restarted, 10, 8));
+    }
+
+    #[tokio::test]
+    async fn lagging_subscribers_get_a_gap() {
+        let (sender, receiver) = broadcast::channel(2);
+        let mut subscription = StatChangeSubscription { namespace: None, gap: false, missed: VecDeque::new(), receiver };
+        for id in 1..=3 {
+            sender.send(stat_changed(id)).unwrap();
+        }
+
+        assert!(matches!(subscription.recv().await, Some(StatChangeEvent::Gap)));
+        assert!(matches!(subscription.recv().await, Some(StatChangeEvent::Changed(change)) if change.id == 2));
+    }
+
+    #[tokio::test]
+    async fn subscribers_get_a_gap_before_what_could_be_caught_up() {
+        let (_sender, receiver) = broadcast::channel(2);
+        let missed = vec![stat_changed(5)].into_iter().collect();
+        let mut subscription = StatChangeSubscription { namespace: None, gap: true, missed, receiver };
+
+        assert!(matches!(subscription.recv().await, Some(StatChangeEvent::Gap)));
+        assert!(matches!(subscription.recv().await, Some(StatChangeEvent::Changed(change)) if change.id == 5));
+    }
 }
@@ -1,17 +1,54 @@
+use std::time::Duration;
+
 use xtra::{Actor, Address};
 
 use crate::{Controller, RegisterStatisticsDatabaseController, StatisticsConfig, TokioGlobal};
-use crate::statistics::database::StatisticDatabaseController;
+use crate::statistics::database::{DecayInactiveStats, RefreshLeaderboardSnapshots, StatisticDatabaseController};
 
+pub mod alert;
+pub mod cache;
 pub mod model;
+pub mod names;
 pub mod database;
+pub mod rate_limit;
+pub mod username;
+pub mod webhook;
 
 pub async fn run(controller: Address<Controller>, config: StatisticsConfig) {
-    let statistics_database = StatisticDatabaseController::connect(&controller, &config).await
+    let statistics_database = StatisticDatabaseController::connect(&controller, Box::new(controller.clone()), &config).await
         .expect("failed to connect to statistics database")
-        .create(None)
+        .create(config.mailbox_capacity)
         .spawn(&mut TokioGlobal);
 
-    controller.do_send_async(RegisterStatisticsDatabaseController { controller: statistics_database })
+    controller.do_send_async(RegisterStatisticsDatabaseController { controller: statistics_database.clone() })
         .await.expect("controller disconnected");
+
+    if !config.stat_decay.is_empty() {
+        let statistics_database = statistics_database.clone();
+        let decay_interval = Duration::from_secs(config.stat_decay_interval_seconds.max(1));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(decay_interval);
+            // The first tick is immediate, but there's no need to decay as soon as we start.
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if statistics_database.do_send_async(DecayInactiveStats).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    if !config.leaderboard_snapshots.is_empty() {
+        let refresh_interval = Duration::from_secs(config.leaderboard_refresh_interval_seconds.max(1));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(refresh_interval);
+            loop {
+                interval.tick().await;
+                if statistics_database.do_send_async(RefreshLeaderboardSnapshots).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
 }
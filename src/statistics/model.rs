@@ -1,21 +1,32 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 
-use chrono::DateTime;
+use chrono::{Datelike, DateTime};
 use chrono::Utc;
-use clickhouse_rs::Pool;
-use serde::{Deserialize, Serialize};
+use clickhouse_rs::{ClientHandle, Pool};
+use log::warn;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use uuid::Uuid;
 
+use crate::{StatisticsConfig, StatMetadataConfig, StatUnit};
+
 pub const CREATE_GAMES_TABLE: &str = r#"
 CREATE TABLE IF NOT EXISTS games(
     game_id         UUID DEFAULT generateUUIDv4(),
     namespace       String,
     player_count    UInt32,
     server          String,
-    date_played     DateTime
+    date_played     DateTime,
+    source_server   String DEFAULT ''
 ) Engine=MergeTree() PRIMARY KEY game_id
 "#;
 
+// The games table predates source_server, so it needs adding to existing databases.
+pub const ADD_GAMES_SOURCE_SERVER_COLUMN: &str = r#"
+ALTER TABLE games ADD COLUMN IF NOT EXISTS source_server String DEFAULT ''
+"#;
+
 pub const CREATE_PLAYER_STATS_TABLE: &str = r#"
 CREATE TABLE IF NOT EXISTS player_statistics(
     statistic_id    UUID DEFAULT generateUUIDv4(),
@@ -24,7 +35,8 @@ CREATE TABLE IF NOT EXISTS player_statistics(
     namespace       String,
     key             String,
     value           Float64,
-    type            String
+    type            String,
+    recorded_at     DateTime DEFAULT now()
 ) Engine=MergeTree() PRIMARY KEY statistic_id
 "#;
 
@@ -35,25 +47,454 @@ CREATE TABLE IF NOT EXISTS global_statistics(
     namespace       String,
     key             String,
     value           Float64,
-    type            String
+    type            String,
+    recorded_at     DateTime DEFAULT now()
 ) Engine=MergeTree() PRIMARY KEY statistic_id
 "#;
 
-pub async fn initialise_database(db: &Pool) -> Result<(), clickhouse_rs::errors::Error> {
+// When stat rows were written, for exporting only what's changed. Rows from before this existed
+// are stored as written at the epoch, so they're only picked up by a full export. The column is
+// added with that as its default and filled in before the default becomes the time of writing,
+// otherwise the old rows would take the new default and read as written whenever they're read.
+async fn add_recorded_at_column(client: &mut ClientHandle, table: &str) -> Result<(), clickhouse_rs::errors::Error> {
+    let sql = format!(r#"
+        SELECT default_expression
+            FROM system.columns
+            WHERE database = currentDatabase() AND table = '{}' AND name = 'recorded_at'"#, table);
+    let block = client.query(sql).fetch_all().await?;
+    let default_expression: Option<String> = match block.rows().next() {
+        Some(row) => Some(row.get("default_expression")?),
+        None => None,
+    };
+
+    for sql in recorded_at_migration(table, default_expression.as_deref()) {
+        client.execute(sql).await?;
+    }
+
+    Ok(())
+}
+
+// Adding the column can be cut short before its default becomes the time of writing, so until it
+// has, the rows are filled in again and the default changed. Filling them in again is harmless,
+// since none of them can have been written with anything but the epoch.
+fn recorded_at_migration(table: &str, default_expression: Option<&str>) -> Vec<String> {
+    let mut steps = Vec::new();
+    match default_expression {
+        Some("now()") => return steps,
+        Some(_) => (),
+        None => steps.push(format!("ALTER TABLE {} ADD COLUMN IF NOT EXISTS recorded_at DateTime DEFAULT toDateTime(0)", table)),
+    }
+    steps.push(format!("ALTER TABLE {} MATERIALIZE COLUMN recorded_at SETTINGS mutations_sync = 1", table));
+    steps.push(format!("ALTER TABLE {} MODIFY COLUMN recorded_at DateTime DEFAULT now()", table));
+    steps
+}
+
+pub const CREATE_REJECTED_STATS_TABLE: &str = r#"
+CREATE TABLE IF NOT EXISTS rejected_stats(
+    rejected_at     DateTime,
+    game_id         UUID,
+    player_id       UUID,
+    namespace       String,
+    key             String,
+    value           Float64,
+    type            String,
+    reason          String
+) Engine=MergeTree() ORDER BY rejected_at
+"#;
+
+// Rows are only ever appended, so there may be several per player until they get merged away.
+// Queries should take the latest username by last_seen. `username` keeps the casing the player
+// logged in with for display, while lookups match against `username_lower`.
+pub const CREATE_PLAYERS_TABLE: &str = r#"
+CREATE TABLE IF NOT EXISTS players(
+    player_id       UUID,
+    username        String,
+    last_seen       DateTime,
+    username_lower  String DEFAULT lowerUTF8(username)
+) Engine=ReplacingMergeTree(last_seen) ORDER BY player_id
+"#;
+
+// Profiles written before username_lower existed get it from their username until they're next
+// updated.
+pub const ADD_PLAYERS_USERNAME_LOWER_COLUMN: &str = r#"
+ALTER TABLE players ADD COLUMN IF NOT EXISTS username_lower String DEFAULT lowerUTF8(username)
+"#;
+
+// Bundle ids only need to be remembered for as long as a server might retry sending a bundle. The
+// TTL is replaced with the configured applied_bundle_retention_days on startup.
+pub const CREATE_APPLIED_BUNDLES_TABLE: &str = r#"
+CREATE TABLE IF NOT EXISTS applied_bundles(
+    bundle_id       UUID,
+    applied_at      DateTime
+) Engine=MergeTree() ORDER BY bundle_id TTL applied_at + INTERVAL 7 DAY
+"#;
+
+// Unlocks are only ever inserted for players who don't have them yet, but merging still cleans up
+// after any race between two uploads.
+pub const CREATE_PLAYER_ACHIEVEMENTS_TABLE: &str = r#"
+CREATE TABLE IF NOT EXISTS player_achievements(
+    player_id       UUID,
+    achievement     String,
+    unlocked_at     DateTime
+) Engine=ReplacingMergeTree() ORDER BY (player_id, achievement)
+"#;
+
+// Players the username resolver didn't know or failed on, so that backfills don't keep asking it
+// about the same players.
+pub const CREATE_USERNAME_LOOKUPS_TABLE: &str = r#"
+CREATE TABLE IF NOT EXISTS username_lookups(
+    player_id           UUID,
+    lookup_failed_at    DateTime
+) Engine=ReplacingMergeTree(lookup_failed_at) ORDER BY player_id
+"#;
+
+// Moderation flags like `banned`. Setting a flag again replaces its previous value.
+pub const CREATE_PLAYER_FLAGS_TABLE: &str = r#"
+CREATE TABLE IF NOT EXISTS player_flags(
+    player_id       UUID,
+    flag            String,
+    value           UInt8,
+    updated_at      DateTime
+) Engine=ReplacingMergeTree(updated_at) ORDER BY (player_id, flag)
+"#;
+
+fn applied_bundles_ttl_sql(days: u32) -> String {
+    format!("ALTER TABLE applied_bundles MODIFY TTL applied_at + INTERVAL {} DAY", days)
+}
+
+pub async fn initialise_database(db: &Pool, config: &StatisticsConfig) -> Result<(), clickhouse_rs::errors::Error> {
     let mut client = db.get_handle().await?;
 
     // See if we can connect
     client.ping().await?;
 
     client.execute(CREATE_GAMES_TABLE).await?;
+    client.execute(ADD_GAMES_SOURCE_SERVER_COLUMN).await?;
     client.execute(CREATE_PLAYER_STATS_TABLE).await?;
+    add_recorded_at_column(&mut client, "player_statistics").await?;
     client.execute(CREATE_GLOBAL_STATS_TABLE).await?;
+    add_recorded_at_column(&mut client, "global_statistics").await?;
+    client.execute(CREATE_REJECTED_STATS_TABLE).await?;
+    client.execute(CREATE_APPLIED_BUNDLES_TABLE).await?;
+    client.execute(CREATE_PLAYERS_TABLE).await?;
+    client.execute(ADD_PLAYERS_USERNAME_LOWER_COLUMN).await?;
+    client.execute(CREATE_PLAYER_ACHIEVEMENTS_TABLE).await?;
+    client.execute(CREATE_PLAYER_FLAGS_TABLE).await?;
+    client.execute(CREATE_USERNAME_LOOKUPS_TABLE).await?;
+
+    // The table may already exist with a different retention, so always (re)apply it.
+    let rejected_stats_ttl = match config.rejected_stats_retention_days {
+        Some(days) if days > 0 => format!("ALTER TABLE rejected_stats MODIFY TTL rejected_at + INTERVAL {} DAY", days),
+        _ => "ALTER TABLE rejected_stats REMOVE TTL".to_owned(),
+    };
+    if let Err(e) = client.execute(rejected_stats_ttl).await {
+        // Older ClickHouse versions can't remove a TTL that was never set; neither case should stop us starting.
+        warn!("Failed to apply rejected stats retention: {}", e);
+    }
+
+    client.execute(applied_bundles_ttl_sql(config.applied_bundle_retention_days)).await?;
+
     Ok(())
 }
 
 pub type PlayerStatsResponse = HashMap<String, HashMap<String, f64>>;
+pub type TypedPlayerStatsResponse = HashMap<String, HashMap<String, StatValue>>;
 pub type PlayerStatsBundle = HashMap<Uuid, HashMap<String, UploadStat>>;
 
+#[derive(Serialize, Clone, Debug)]
+pub struct PlayerProfile {
+    pub id: Uuid,
+    pub username: String,
+}
+
+/// Everything a profile page shows about a player. Parts that couldn't be loaded are left out.
+#[derive(Serialize, Clone, Debug)]
+pub struct PlayerOverview {
+    pub uuid: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<PlayerStatsResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub achievements: Option<Vec<PlayerAchievement>>,
+    // places on snapshotted leaderboards, which are the only ones cheap enough to check
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ranks: Vec<LeaderboardRank>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LeaderboardRank {
+    pub namespace: String,
+    pub stat: String,
+    // 1 is the top of the leaderboard
+    pub rank: usize,
+}
+
+/// Where a player stands in one namespace, going by its headline stat.
+#[derive(Serialize, Clone, Debug)]
+pub struct NamespaceStanding {
+    pub namespace: String,
+    pub stat: String,
+    pub value: f64,
+    // 1 is the top, and tied players share a rank
+    pub rank: usize,
+    // the fraction of players with a value for the stat who this player outranks
+    pub percentile: f64,
+}
+
+/// The part of a leaderboard around one player.
+#[derive(Serialize, Clone, Debug)]
+pub struct LeaderboardWindow {
+    // the player's own rank, 1 being the top of the leaderboard
+    pub rank: usize,
+    pub entries: Vec<RankedLeaderboardEntry>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct RankedLeaderboardEntry {
+    pub rank: usize,
+    pub player: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    pub value: f64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct PlayerAchievement {
+    pub id: String,
+    // Achievements that have since been removed from the config no longer have a name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub unlocked_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LeaderboardEntry {
+    pub player: Uuid,
+    pub value: f64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct StatMetadata {
+    pub label: String,
+    pub unit: StatUnit,
+    pub higher_is_better: bool,
+}
+
+impl StatMetadata {
+    /// Fills in anything left out of the config: stats are labelled by their key, and are counts
+    /// where higher is better unless told otherwise.
+    pub fn resolve(key: &str, config: Option<&StatMetadataConfig>) -> StatMetadata {
+        let default = StatMetadataConfig::default();
+        let config = config.unwrap_or(&default);
+        StatMetadata {
+            label: config.label.clone().unwrap_or_else(|| key.to_owned()),
+            unit: config.unit,
+            higher_is_better: config.higher_is_better.unwrap_or(true),
+        }
+    }
+}
+
+/// A global total along with the sum of the same stat across every player in the namespace.
+#[derive(Serialize, Clone, Debug)]
+pub struct GlobalStatComparison {
+    pub key: String,
+    pub global_total: f64,
+    pub player_total: f64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct GlobalStatMismatch {
+    pub key: String,
+    pub global_total: f64,
+    pub player_total: f64,
+    // how far the global total is above (positive) or below (negative) the sum over players
+    pub difference: f64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct StatThresholdMatch {
+    pub player: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    pub value: f64,
+}
+
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TrendGranularity {
+    Day,
+    // weeks start on Monday
+    Week,
+}
+
+impl TrendGranularity {
+    pub fn step(self) -> chrono::Duration {
+        match self {
+            TrendGranularity::Day => chrono::Duration::days(1),
+            TrendGranularity::Week => chrono::Duration::weeks(1),
+        }
+    }
+
+    /// The start of the bucket the time falls in, in UTC.
+    pub fn bucket_start(self, time: DateTime<Utc>) -> DateTime<Utc> {
+        let day = time.date().and_hms(0, 0, 0);
+        match self {
+            TrendGranularity::Day => day,
+            TrendGranularity::Week => day - chrono::Duration::days(i64::from(time.weekday().num_days_from_monday())),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TrendPoint {
+    pub start: DateTime<Utc>,
+    pub value: f64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TrendingEntry {
+    pub player: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    // how much the stat increased by within the window
+    pub gained: f64,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct StorageStats {
+    pub tables: HashMap<String, TableStorage>,
+    pub namespaces: HashMap<String, NamespaceStorage>,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct TableStorage {
+    pub rows: u64,
+    // compressed size on disk
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TableMaintenance {
+    pub before: TableStorage,
+    pub after: TableStorage,
+    // why the table couldn't be optimized, if it couldn't
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct NamespaceStorage {
+    pub player_rows: u64,
+    pub global_rows: u64,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ReplayReport {
+    pub recovered: u64,
+    pub still_rejected: u64,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct UsernameBackfillReport {
+    pub resolved: u64,
+    // players the resolver had no username for
+    pub unknown: u64,
+    // players the resolver failed on. these and unknown players are skipped by later backfills
+    // until it's time to retry them
+    pub failed: u64,
+    // players left for the next backfill because the resolver rate limited us
+    pub remaining: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct RenamedStat {
+    pub player_rows: u64,
+    pub global_rows: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ResetStatsReport {
+    // how many players had a non-zero value for at least one of the stats
+    pub players: usize,
+    // how many of the stats had a non-zero global value that was reset
+    pub global_stats: usize,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct NamespaceActivity {
+    pub namespace: String,
+    pub games: u64,
+    // how many player stat rows games in the namespace wrote within the window
+    pub stats_written: u64,
+}
+
+/// The totals of everything in a namespace that has changed since some time.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ChangedStats {
+    pub players: Vec<PlayerStatsDocument>,
+    // only the global stats that changed
+    pub global: HashMap<String, f64>,
+}
+
+/// One player's totals within a namespace, as sent when streaming a namespace's stats.
+#[derive(Serialize, Clone, Debug)]
+pub struct PlayerStatsDocument {
+    pub player: Uuid,
+    pub stats: HashMap<String, f64>,
+}
+
+/// The types a stat has been uploaded with. A stat with more than one type is probably being
+/// uploaded incorrectly by some server.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct StatSchema {
+    pub types: Vec<String>,
+    pub inconsistent: bool,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct NamespaceSchema {
+    pub players: HashMap<String, StatSchema>,
+    pub global: HashMap<String, StatSchema>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct NamespaceStat {
+    pub name: String,
+    pub scope: StatScope,
+}
+
+/// Whether a stat has been uploaded for players, globally, or both.
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StatScope {
+    Player,
+    Global,
+    Both,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct PersonalBest {
+    pub value: f64,
+    pub game_id: Uuid,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LeaderboardResponse {
+    pub entries: Vec<LeaderboardEntry>,
+    // How long ago the leaderboard was computed, if it was served from a snapshot.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age_seconds: Option<u64>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct PlaytimeEntry {
+    pub player: Uuid,
+    // Players who have never been seen in a status update have no known username.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    pub playtime: f64,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct GameStatsResponse {
     namespace: String,
@@ -62,10 +503,211 @@ pub struct GameStatsResponse {
     date_played: DateTime<Utc>,
 }
 
+/// The effect a bundle has (or would have) on a stat's value, as reported by the player and game
+/// stats queries. `player` is `None` for global statistics.
+#[derive(Serialize, Clone, Debug)]
+pub struct StatChange {
+    pub player: Option<Uuid>,
+    pub key: String,
+    pub old_value: f64,
+    pub new_value: f64,
+}
+
+/// Everything needed to work out a stat's current value whatever its type, from all the rows
+/// recorded for it so far.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StoredStat {
+    pub total: f64,
+    pub min: f64,
+    pub max: f64,
+    pub count: u64,
+    // the value of the earliest row, for set-once stats
+    pub first: f64,
+}
+
+impl StoredStat {
+    /// The stat's value as the type it's being uploaded as would have it.
+    pub fn value_for(&self, stat: &UploadStat) -> f64 {
+        match stat {
+            UploadStat::IntTotal(_) | UploadStat::FloatTotal(_) | UploadStat::DecimalTotal(_) => self.total,
+            UploadStat::IntMin(_) | UploadStat::FloatMin(_) => self.min,
+            UploadStat::IntMax(_) | UploadStat::FloatMax(_) => self.max,
+            UploadStat::IntRollingAverage(_) | UploadStat::FloatRollingAverage(_) => {
+                if self.count > 0 { self.total / self.count as f64 } else { 0.0 }
+            }
+            UploadStat::IntFirst(_) | UploadStat::FloatFirst(_) => self.first,
+        }
+    }
+
+    /// Applies an uploaded value to what was stored before, which is `None` if nothing was, and
+    /// returns the change it makes along with the new state.
+    pub fn apply(stored: Option<StoredStat>, stat: &UploadStat) -> (f64, f64, StoredStat) {
+        let value: f64 = stat.clone().into();
+        let updated = match stored {
+            Some(stored) => StoredStat {
+                total: stored.total + value,
+                min: stored.min.min(value),
+                max: stored.max.max(value),
+                count: stored.count + 1,
+                first: stored.first,
+            },
+            None => StoredStat { total: value, min: value, max: value, count: 1, first: value },
+        };
+        let old_value = stored.map(|stored| stored.value_for(stat)).unwrap_or(0.0);
+        (old_value, updated.value_for(stat), updated)
+    }
+}
+
+/// A stat that was removed from a bundle before upload. `player` is `None` for global statistics.
+#[derive(Clone, Debug)]
+pub struct RejectedStat {
+    pub player: Option<Uuid>,
+    pub key: String,
+    pub stat: UploadStat,
+    pub reason: String,
+}
+
+/// A bundle along with the id of the game it was recorded for.
+#[derive(Deserialize, Clone, Debug)]
+pub struct GameStatsUpload {
+    pub game_id: Uuid,
+    pub bundle: GameStatsBundle,
+}
+
+/// A bundle that has been written, including how it changed each stat's value.
+#[derive(Clone, Debug)]
+pub struct UploadedBundle {
+    pub game_id: Uuid,
+    pub namespace: String,
+    pub changes: Vec<StatChange>,
+    // players whose stats were left out because what's stored for them couldn't be read
+    pub failed_players: Vec<(Uuid, String)>,
+}
+
+/// What happened to an uploaded bundle. For a dry run, this is what would have happened.
+#[derive(Serialize, Clone, Debug)]
+pub struct BundleUploadReport {
+    pub game_id: Uuid,
+    pub players_processed: usize,
+    pub stats_applied: usize,
+    pub stats_rejected: usize,
+    // players left out of the bundle, and the errors for the first few of them
+    pub players_failed: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub player_errors: Vec<String>,
+    // The bundle was skipped because a bundle with the same id was already applied.
+    pub already_applied: bool,
+    pub error: Option<String>,
+    // Some of the bundle was written before it failed, so sending it again could count that twice.
+    pub partly_written: bool,
+    // what sort of database error failed the bundle, if that's what failed it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_kind: Option<UploadErrorKind>,
+    // for a dry run, the changes the bundle would have made
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub changes: Option<Vec<StatChange>>,
+}
+
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadErrorKind {
+    // the database was too slow; sending the bundle again later should work
+    Retryable,
+    // the database couldn't be reached at all
+    Connection,
+    // the bundle itself was the problem, so sending it again won't help
+    Invalid,
+    Other,
+}
+
+impl BundleUploadReport {
+    pub fn new(game_id: Uuid) -> BundleUploadReport {
+        BundleUploadReport {
+            game_id,
+            players_processed: 0,
+            stats_applied: 0,
+            stats_rejected: 0,
+            players_failed: 0,
+            player_errors: Vec::new(),
+            already_applied: false,
+            error: None,
+            partly_written: false,
+            error_kind: None,
+            changes: None,
+        }
+    }
+
+    /// The bundle wasn't looked at, but sending it again later should work.
+    pub fn retry_later(game_id: Uuid, error: String) -> BundleUploadReport {
+        BundleUploadReport {
+            error_kind: Some(UploadErrorKind::Retryable),
+            ..BundleUploadReport::failed(game_id, error)
+        }
+    }
+
+    pub fn failed(game_id: Uuid, error: String) -> BundleUploadReport {
+        BundleUploadReport { error: Some(error), ..BundleUploadReport::new(game_id) }
+    }
+
+    pub fn accepted(game_id: Uuid, bundle: &GameStatsBundle, stats_rejected: usize) -> BundleUploadReport {
+        let player_stats: usize = bundle.stats.players.values().map(|stats| stats.len()).sum();
+        let global_stats = bundle.stats.global.as_ref().map(|global| global.len()).unwrap_or(0);
+        BundleUploadReport {
+            players_processed: bundle.stats.players.len(),
+            stats_applied: player_stats + global_stats,
+            stats_rejected,
+            ..BundleUploadReport::new(game_id)
+        }
+    }
+}
+
+/// Published to subscribers after a bundle has been uploaded, once for every player stat it touched.
+#[derive(Serialize, Clone, Debug)]
+pub struct StatChanged {
+    // increases with each change, carrying on from where it was after a restart, so a subscriber
+    // can say which it saw last
+    pub id: u64,
+    pub uuid: Uuid,
+    pub namespace: String,
+    pub stat: String,
+    pub old_value: f64,
+    pub new_value: f64,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct GameStatsBundle {
     pub namespace: String,
     pub stats: StatsBundle,
+    // Lets servers safely retry sending a bundle: a bundle with an id that was already applied is skipped.
+    #[serde(default)]
+    pub bundle_id: Option<Uuid>,
+    // The specific server instance that produced the bundle, if the channel is shared by several.
+    #[serde(default)]
+    pub source_server: Option<String>,
+    #[serde(default = "default_bundle_version")]
+    pub version: u32,
+}
+
+pub const CURRENT_BUNDLE_VERSION: u32 = 1;
+
+// Bundles from servers that predate versioning are all version 1.
+fn default_bundle_version() -> u32 {
+    1
+}
+
+impl GameStatsBundle {
+    /// Upgrades a bundle sent by an older server to the current format, so that servers and the
+    /// backend don't need to be updated at the same time. Version 1 is still current, so there are
+    /// no steps yet: when the format changes, add one here that brings the previous version up to
+    /// date, and bump [`CURRENT_BUNDLE_VERSION`].
+    pub fn migrate(&mut self) -> Result<(), String> {
+        if self.version == 0 || self.version > CURRENT_BUNDLE_VERSION {
+            return Err(format!("unsupported bundle version {} (expected 1 to {})", self.version, CURRENT_BUNDLE_VERSION));
+        }
+
+        self.version = CURRENT_BUNDLE_VERSION;
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -85,6 +727,11 @@ pub enum UploadStat {
     FloatMin(f64),
     FloatMax(f64),
     FloatRollingAverage(f64),
+    // For currency and anything else that must add up exactly, like coins in economy minigames.
+    DecimalTotal(Decimal),
+    // Only the first value ever uploaded is kept, for things like the date of a player's first win.
+    IntFirst(i32),
+    FloatFirst(f64),
 }
 
 impl UploadStat {
@@ -98,8 +745,38 @@ impl UploadStat {
             UploadStat::FloatMin(_) => "float_min",
             UploadStat::FloatMax(_) => "float_max",
             UploadStat::FloatRollingAverage(_) => "float_rolling_average",
+            UploadStat::DecimalTotal(_) => "decimal_total",
+            UploadStat::IntFirst(_) => "int_first",
+            UploadStat::FloatFirst(_) => "float_first",
         }
     }
+
+    pub fn is_set_once(&self) -> bool {
+        matches!(self, UploadStat::IntFirst(_) | UploadStat::FloatFirst(_))
+    }
+
+    /// Rebuilds a stat from the type and value it was stored with.
+    pub fn from_stored(ty: &str, value: f64) -> Option<UploadStat> {
+        let int = || if value.fract() == 0.0 && value >= i32::MIN as f64 && value <= i32::MAX as f64 {
+            Some(value as i32)
+        } else {
+            None
+        };
+        Some(match ty {
+            "int_total" => UploadStat::IntTotal(int()?),
+            "int_min" => UploadStat::IntMin(int()?),
+            "int_max" => UploadStat::IntMax(int()?),
+            "int_rolling_average" => UploadStat::IntRollingAverage(int()?),
+            "float_total" => UploadStat::FloatTotal(value),
+            "float_min" => UploadStat::FloatMin(value),
+            "float_max" => UploadStat::FloatMax(value),
+            "float_rolling_average" => UploadStat::FloatRollingAverage(value),
+            "decimal_total" => UploadStat::DecimalTotal(value.to_string().parse().ok()?),
+            "int_first" => UploadStat::IntFirst(int()?),
+            "float_first" => UploadStat::FloatFirst(value),
+            _ => return None,
+        })
+    }
 }
 
 impl Into<f64> for UploadStat {
@@ -109,12 +786,274 @@ impl Into<f64> for UploadStat {
             UploadStat::FloatTotal(v) |
             UploadStat::FloatMin(v) |
             UploadStat::FloatMax(v) |
-            UploadStat::FloatRollingAverage(v) => v,
+            UploadStat::FloatRollingAverage(v) |
+            UploadStat::FloatFirst(v) => v,
 
             UploadStat::IntTotal(v) |
             UploadStat::IntMin(v) |
             UploadStat::IntMax(v) |
-            UploadStat::IntRollingAverage(v) => v as f64,
+            UploadStat::IntRollingAverage(v) |
+            UploadStat::IntFirst(v) => v as f64,
+
+            UploadStat::DecimalTotal(v) => v.as_f64(),
+        }
+    }
+}
+
+pub const DECIMAL_PLACES: usize = 4;
+const DECIMAL_SCALE: i128 = 10_000;
+
+/// A fixed-point number with `DECIMAL_PLACES` decimal places. These are sent as strings, like
+/// `"12.5"`, so that they don't pass through a float on the way.
+///
+/// Rows still store a decimal stat's value as a Float64, which holds any decimal with up to 15
+/// significant digits in a way that converts back exactly, so totals are summed as decimals from
+/// each row's shortest string form rather than by adding the floats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Decimal {
+    units: i128,
+}
+
+impl Decimal {
+    pub fn as_f64(&self) -> f64 {
+        self.units as f64 / DECIMAL_SCALE as f64
+    }
+
+    pub fn saturating_add(self, other: Decimal) -> Decimal {
+        Decimal { units: self.units.saturating_add(other.units) }
+    }
+}
+
+impl FromStr for Decimal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, s),
+        };
+        let (whole, fraction) = match digits.split_once('.') {
+            Some((whole, fraction)) => (whole, fraction),
+            None => (digits, ""),
+        };
+
+        if whole.is_empty() || !whole.chars().all(|c| c.is_ascii_digit()) || !fraction.chars().all(|c| c.is_ascii_digit()) {
+            return Err(format!("'{}' is not a decimal number", s));
+        }
+        if fraction.len() > DECIMAL_PLACES {
+            return Err(format!("'{}' has more than {} decimal places", s, DECIMAL_PLACES));
         }
+
+        let whole: i128 = whole.parse().map_err(|_| format!("'{}' is too large", s))?;
+        let fraction: i128 = format!("{:0<width$}", fraction, width = DECIMAL_PLACES).parse()
+            .map_err(|_| format!("'{}' is not a decimal number", s))?;
+        let units = whole.checked_mul(DECIMAL_SCALE)
+            .and_then(|units| units.checked_add(fraction))
+            .ok_or_else(|| format!("'{}' is too large", s))?;
+
+        Ok(Decimal { units: if negative { -units } else { units } })
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.units < 0 { "-" } else { "" };
+        let units = self.units.unsigned_abs();
+        let scale = DECIMAL_SCALE as u128;
+        write!(f, "{}{}.{:0width$}", sign, units / scale, units % scale, width = DECIMAL_PLACES)
+    }
+}
+
+impl Serialize for Decimal {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Decimal {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// A stat value that keeps the representation it was uploaded with, rather than flattening
+/// everything to an `f64`. Integer stats are summed as 64-bit integers so large counters stay exact.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "type", content = "value")]
+pub enum StatValue {
+    Int(i64),
+    Float(f64),
+    Decimal(Decimal),
+}
+
+impl StatValue {
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            StatValue::Int(v) => *v as f64,
+            StatValue::Float(v) => *v,
+            StatValue::Decimal(v) => v.as_f64(),
+        }
+    }
+
+    /// Combines two values for the same stat. If a stat has been uploaded as more than one kind of
+    /// value, the result falls back to a float.
+    pub fn combine(self, other: StatValue) -> StatValue {
+        match (self, other) {
+            (StatValue::Int(a), StatValue::Int(b)) => StatValue::Int(a.saturating_add(b)),
+            (StatValue::Decimal(a), StatValue::Decimal(b)) => StatValue::Decimal(a.saturating_add(b)),
+            (a, b) => StatValue::Float(a.as_f64() + b.as_f64()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply_all(stats: &[UploadStat]) -> Vec<(f64, f64)> {
+        let mut stored = None;
+        stats.iter()
+            .map(|stat| {
+                let (old_value, new_value, updated) = StoredStat::apply(stored, stat);
+                stored = Some(updated);
+                (old_value, new_value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn totals_add_up() {
+        let changes = apply_all(&[UploadStat::IntTotal(3), UploadStat::IntTotal(4)]);
+        assert_eq!(changes, vec![(0.0, 3.0), (3.0, 7.0)]);
+    }
+
+    #[test]
+    fn minimums_and_maximums_keep_the_best() {
+        let changes = apply_all(&[UploadStat::FloatMin(12.5), UploadStat::FloatMin(20.0), UploadStat::FloatMin(9.0)]);
+        assert_eq!(changes, vec![(0.0, 12.5), (12.5, 12.5), (12.5, 9.0)]);
+
+        let changes = apply_all(&[UploadStat::IntMax(5), UploadStat::IntMax(2), UploadStat::IntMax(8)]);
+        assert_eq!(changes, vec![(0.0, 5.0), (5.0, 5.0), (5.0, 8.0)]);
+    }
+
+    #[test]
+    fn rolling_averages_weigh_every_upload_equally() {
+        let changes = apply_all(&[
+            UploadStat::FloatRollingAverage(10.0),
+            UploadStat::FloatRollingAverage(20.0),
+            UploadStat::FloatRollingAverage(60.0),
+        ]);
+        assert_eq!(changes, vec![(0.0, 10.0), (10.0, 15.0), (15.0, 30.0)]);
+    }
+
+    #[test]
+    fn set_once_stats_keep_the_first_value() {
+        let changes = apply_all(&[UploadStat::IntFirst(100), UploadStat::IntFirst(200)]);
+        assert_eq!(changes, vec![(0.0, 100.0), (100.0, 100.0)]);
+    }
+
+    #[test]
+    fn stored_values_are_picked_by_type() {
+        let stored = StoredStat { total: 30.0, min: 5.0, max: 15.0, count: 3, first: 10.0 };
+        assert_eq!(stored.value_for(&UploadStat::IntTotal(0)), 30.0);
+        assert_eq!(stored.value_for(&UploadStat::IntMin(0)), 5.0);
+        assert_eq!(stored.value_for(&UploadStat::IntMax(0)), 15.0);
+        assert_eq!(stored.value_for(&UploadStat::IntRollingAverage(0)), 10.0);
+        assert_eq!(stored.value_for(&UploadStat::FloatFirst(0.0)), 10.0);
+    }
+
+    #[test]
+    fn only_dry_run_reports_include_changes() {
+        let game_id = Uuid::new_v4();
+        let report = serde_json::to_value(BundleUploadReport::new(game_id)).unwrap();
+        assert!(report.get("changes").is_none());
+
+        let player = Uuid::new_v4();
+        let dry_run = BundleUploadReport {
+            changes: Some(vec![StatChange { player: Some(player), key: "wins".to_owned(), old_value: 2.0, new_value: 3.0 }]),
+            ..BundleUploadReport::new(game_id)
+        };
+        let report = serde_json::to_value(dry_run).unwrap();
+        assert_eq!(report["changes"][0]["player"], player.to_string());
+        assert_eq!(report["changes"][0]["old_value"], 2.0);
+        assert_eq!(report["changes"][0]["new_value"], 3.0);
+    }
+
+    fn decimal(s: &str) -> Decimal {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn decimal_totals_add_up_exactly() {
+        let mut total = StatValue::Decimal(decimal("0.1"));
+        for _ in 0..9 {
+            total = total.combine(StatValue::Decimal(decimal("0.1")));
+        }
+        assert_eq!(total, StatValue::Decimal(decimal("1")));
+
+        let total = decimal("0.1").saturating_add(decimal("0.2"));
+        assert_eq!(total.to_string(), "0.3000");
+        assert_eq!(decimal("-1.25").saturating_add(decimal("0.0001")).to_string(), "-1.2499");
+    }
+
+    #[test]
+    fn decimals_keep_every_stored_place() {
+        // what the database sends back for a decimal_total
+        assert_eq!(decimal("123456789012.3456").to_string(), "123456789012.3456");
+        assert!("0.12345".parse::<Decimal>().is_err());
+    }
+
+    #[test]
+    fn applied_bundles_keep_the_configured_number_of_days() {
+        assert_eq!(applied_bundles_ttl_sql(30), "ALTER TABLE applied_bundles MODIFY TTL applied_at + INTERVAL 30 DAY");
+    }
+
+    fn versioned_bundle(version: Option<u32>) -> GameStatsBundle {
+        let mut bundle = serde_json::json!({
+            "namespace": "bedwars",
+            "stats": {
+                "global": null,
+                "players": { "c6c84a95-3bcb-4dd4-b2a6-0b4dd6ebd0a8": { "wins": { "type": "int_total", "value": 1 } } },
+            },
+        });
+        if let Some(version) = version {
+            bundle["version"] = version.into();
+        }
+        serde_json::from_value(bundle).unwrap()
+    }
+
+    #[test]
+    fn bundles_without_a_version_migrate_from_version_1() {
+        let mut bundle = versioned_bundle(None);
+        assert_eq!(bundle.version, 1);
+
+        assert_eq!(bundle.migrate(), Ok(()));
+        assert_eq!(bundle.version, CURRENT_BUNDLE_VERSION);
+        assert_eq!(bundle.namespace, "bedwars");
+        let stats = bundle.stats.players.values().next().unwrap();
+        assert!(matches!(stats["wins"], UploadStat::IntTotal(1)));
+    }
+
+    #[test]
+    fn unknown_bundle_versions_are_refused() {
+        assert_eq!(versioned_bundle(Some(0)).migrate(), Err(format!("unsupported bundle version 0 (expected 1 to {})", CURRENT_BUNDLE_VERSION)));
+        assert!(versioned_bundle(Some(CURRENT_BUNDLE_VERSION + 1)).migrate().is_err());
+        assert_eq!(versioned_bundle(Some(CURRENT_BUNDLE_VERSION)).migrate(), Ok(()));
+    }
+
+    #[test]
+    fn recorded_at_is_finished_off_until_it_defaults_to_now() {
+        assert!(recorded_at_migration("player_statistics", Some("now()")).is_empty());
+
+        let steps = recorded_at_migration("player_statistics", None);
+        assert_eq!(steps.len(), 3);
+        assert!(steps[0].contains("ADD COLUMN IF NOT EXISTS recorded_at"));
+
+        // added, but stopped before the default was changed
+        let steps = recorded_at_migration("player_statistics", Some("toDateTime(0)"));
+        assert_eq!(steps.len(), 2);
+        assert!(steps[0].contains("MATERIALIZE COLUMN recorded_at"));
+        assert_eq!(steps[1], "ALTER TABLE player_statistics MODIFY COLUMN recorded_at DateTime DEFAULT now()");
     }
 }
@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+// The most players kept at once. Expired entries are cleared out first, then the oldest.
+const CAPACITY: usize = 10_000;
+
+/// Recently resolved usernames, including players no username could be found for.
+pub struct NameCache {
+    ttl: Duration,
+    capacity: usize,
+    entries: HashMap<Uuid, (Option<String>, Instant)>,
+}
+
+impl NameCache {
+    pub fn new(ttl: Duration) -> NameCache {
+        NameCache::with_capacity(ttl, CAPACITY)
+    }
+
+    pub fn with_capacity(ttl: Duration, capacity: usize) -> NameCache {
+        NameCache { ttl, capacity: capacity.max(1), entries: HashMap::new() }
+    }
+
+    /// `None` if the player isn't cached, or `Some(None)` if they are but have no known username.
+    pub fn get(&self, player: &Uuid) -> Option<Option<&str>> {
+        match self.entries.get(player) {
+            Some((name, cached_at)) if cached_at.elapsed() < self.ttl => Some(name.as_deref()),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, player: Uuid, name: Option<String>) {
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&player) {
+            self.make_room();
+        }
+        self.entries.insert(player, (name, Instant::now()));
+    }
+
+    fn make_room(&mut self) {
+        let ttl = self.ttl;
+        self.entries.retain(|_, (_, cached_at)| cached_at.elapsed() < ttl);
+        if self.entries.len() < self.capacity {
+            return;
+        }
+
+        // Everything is still fresh, so drop the oldest tenth rather than doing this on every insert.
+        let mut by_age = self.entries.iter()
+            .map(|(player, (_, cached_at))| (*player, *cached_at))
+            .collect::<Vec<_>>();
+        by_age.sort_by_key(|(_, cached_at)| *cached_at);
+
+        let evict = (self.entries.len() + 1 - self.capacity).max(self.capacity / 10);
+        for (player, _) in by_age.into_iter().take(evict) {
+            self.entries.remove(&player);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remembers_players_without_a_username() {
+        let mut cache = NameCache::new(Duration::from_secs(60));
+        let known = Uuid::new_v4();
+        let unknown = Uuid::new_v4();
+        cache.insert(known, Some("Gegy".to_owned()));
+        cache.insert(unknown, None);
+
+        assert_eq!(cache.get(&known), Some(Some("Gegy")));
+        assert_eq!(cache.get(&unknown), Some(None));
+        assert_eq!(cache.get(&Uuid::new_v4()), None);
+    }
+
+    #[test]
+    fn expired_entries_are_not_returned() {
+        let mut cache = NameCache::new(Duration::from_secs(0));
+        let player = Uuid::new_v4();
+        cache.insert(player, Some("Gegy".to_owned()));
+
+        assert_eq!(cache.get(&player), None);
+    }
+
+    #[test]
+    fn never_grows_past_its_capacity() {
+        let mut cache = NameCache::with_capacity(Duration::from_secs(60 * 60), 20);
+        let mut last = Uuid::nil();
+        for _ in 0..100 {
+            last = Uuid::new_v4();
+            cache.insert(last, None);
+            assert!(cache.entries.len() <= 20);
+        }
+
+        assert_eq!(cache.get(&last), Some(None));
+    }
+
+    #[test]
+    fn updating_a_cached_player_evicts_nothing() {
+        let mut cache = NameCache::with_capacity(Duration::from_secs(60 * 60), 2);
+        let first = Uuid::new_v4();
+        let second = Uuid::new_v4();
+        cache.insert(first, None);
+        cache.insert(second, None);
+        cache.insert(first, Some("Gegy".to_owned()));
+
+        assert_eq!(cache.get(&first), Some(Some("Gegy")));
+        assert_eq!(cache.get(&second), Some(None));
+    }
+}
@@ -0,0 +1,96 @@
+use std::time::Instant;
+
+use crate::RateLimitConfig;
+
+/// Allows short bursts of up to `burst` events, refilling continuously at `per_minute` a minute.
+pub struct TokenBucket {
+    capacity: f64,
+    refill_per_second: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(config: &RateLimitConfig) -> TokenBucket {
+        TokenBucket::new_at(config, Instant::now())
+    }
+
+    /// A full bucket that starts refilling from `now`.
+    pub fn new_at(config: &RateLimitConfig, now: Instant) -> TokenBucket {
+        let capacity = config.burst.max(1) as f64;
+        TokenBucket {
+            capacity,
+            refill_per_second: config.per_minute.max(0.0) / 60.0,
+            tokens: capacity,
+            last_refill: now,
+        }
+    }
+
+    pub fn try_take(&mut self) -> bool {
+        self.take_at(Instant::now())
+    }
+
+    /// Takes a token at the time `now`, if one has refilled by then.
+    pub fn take_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_second).min(self.capacity);
+        self.last_refill = self.last_refill.max(now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn bucket(per_minute: f64, burst: u32) -> (TokenBucket, Instant) {
+        let start = Instant::now();
+        (TokenBucket::new_at(&RateLimitConfig { per_minute, burst }, start), start)
+    }
+
+    #[test]
+    fn allows_a_burst_then_stops() {
+        let (mut bucket, start) = bucket(6.0, 3);
+        assert!(bucket.take_at(start));
+        assert!(bucket.take_at(start));
+        assert!(bucket.take_at(start));
+        assert!(!bucket.take_at(start));
+    }
+
+    #[test]
+    fn refills_at_the_configured_rate() {
+        let (mut bucket, start) = bucket(6.0, 1);
+        assert!(bucket.take_at(start));
+
+        // 6 a minute is one every 10 seconds
+        assert!(!bucket.take_at(start + Duration::from_secs(9)));
+        assert!(bucket.take_at(start + Duration::from_secs(10)));
+        assert!(!bucket.take_at(start + Duration::from_secs(11)));
+    }
+
+    #[test]
+    fn never_holds_more_than_the_burst() {
+        let (mut bucket, start) = bucket(60.0, 2);
+        let later = start + Duration::from_secs(60 * 60);
+        assert!(bucket.take_at(later));
+        assert!(bucket.take_at(later));
+        assert!(!bucket.take_at(later));
+    }
+
+    #[test]
+    fn going_back_in_time_refills_nothing() {
+        let (mut bucket, start) = bucket(60.0, 1);
+        let later = start + Duration::from_secs(5);
+        assert!(bucket.take_at(later));
+        assert!(!bucket.take_at(start));
+        assert!(bucket.take_at(later + Duration::from_secs(1)));
+    }
+}
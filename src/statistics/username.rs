@@ -0,0 +1,208 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::warn;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::UsernameResolverConfig;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Somewhere to look up players' current usernames from their uuids.
+#[async_trait]
+pub trait UsernameSource: Send + Sync {
+    /// `Ok(None)` means the source doesn't know the player.
+    async fn resolve(&self, player: &Uuid) -> Result<Option<String>, ResolveError>;
+}
+
+/// Looks up players' current usernames from their uuids, by default through Mojang's session
+/// server.
+pub struct UsernameResolver {
+    client: reqwest::Client,
+    config: UsernameResolverConfig,
+}
+
+#[derive(Deserialize)]
+struct Profile {
+    name: String,
+}
+
+impl UsernameResolver {
+    pub fn new(config: &UsernameResolverConfig) -> UsernameResolver {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("failed to create username resolver client");
+
+        UsernameResolver { client, config: config.clone() }
+    }
+
+    pub fn batch_size(&self) -> u32 {
+        self.config.batch_size
+    }
+
+    pub fn request_interval(&self) -> Duration {
+        Duration::from_millis(self.config.request_interval_ms)
+    }
+
+    /// How long a player the resolver couldn't find a username for is left before trying again.
+    pub fn retry_failed_after(&self) -> Duration {
+        Duration::from_secs(self.config.retry_failed_after_hours * 60 * 60)
+    }
+}
+
+#[async_trait]
+impl UsernameSource for UsernameResolver {
+    async fn resolve(&self, player: &Uuid) -> Result<Option<String>, ResolveError> {
+        let url = self.config.url.replace("{uuid}", &player.to_simple().to_string());
+        let response = self.client.get(&url).send().await?;
+
+        match response.status() {
+            StatusCode::TOO_MANY_REQUESTS => Err(ResolveError::RateLimited),
+            StatusCode::NO_CONTENT | StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => {
+                let profile: Profile = response.json().await?;
+                Ok(Some(profile.name))
+            }
+            status => Err(ResolveError::Status(status)),
+        }
+    }
+}
+
+/// What came of looking up a batch of players.
+#[derive(Debug, Default)]
+pub struct BatchLookup {
+    pub resolved: Vec<(Uuid, String)>,
+    // players the source doesn't know
+    pub unknown: Vec<Uuid>,
+    // players the source failed on
+    pub failed: Vec<Uuid>,
+    // players that weren't looked up because the source rate limited us
+    pub remaining: Vec<Uuid>,
+}
+
+/// Looks up each player in turn, waiting `interval` between lookups. A player the source fails on
+/// doesn't stop the rest being looked up, but being rate limited does.
+pub async fn resolve_batch(source: &dyn UsernameSource, players: &[Uuid], interval: Duration) -> BatchLookup {
+    let mut lookup = BatchLookup::default();
+    for (i, player) in players.iter().enumerate() {
+        if i > 0 {
+            tokio::time::sleep(interval).await;
+        }
+
+        match source.resolve(player).await {
+            Ok(Some(username)) => lookup.resolved.push((*player, username)),
+            Ok(None) => lookup.unknown.push(*player),
+            Err(ResolveError::RateLimited) => {
+                warn!("Rate limited resolving usernames, leaving {} players for later", players.len() - i);
+                lookup.remaining = players[i..].to_vec();
+                break;
+            }
+            Err(e) => {
+                warn!("Failed to resolve the username of {}: {}", player, e);
+                lookup.failed.push(*player);
+            }
+        }
+    }
+
+    lookup
+}
+
+#[derive(Error, Debug)]
+pub enum ResolveError {
+    #[error("rate limited")]
+    RateLimited,
+    #[error("request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("unexpected status {0}")]
+    Status(StatusCode),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    enum Answer {
+        Name(&'static str),
+        Unknown,
+        Fail,
+        RateLimit,
+    }
+
+    struct MockSource {
+        answers: HashMap<Uuid, Answer>,
+        asked: Mutex<Vec<Uuid>>,
+    }
+
+    impl MockSource {
+        fn new(answers: Vec<(Uuid, Answer)>) -> MockSource {
+            MockSource { answers: answers.into_iter().collect(), asked: Mutex::new(Vec::new()) }
+        }
+    }
+
+    #[async_trait]
+    impl UsernameSource for MockSource {
+        async fn resolve(&self, player: &Uuid) -> Result<Option<String>, ResolveError> {
+            self.asked.lock().unwrap().push(*player);
+            match self.answers.get(player) {
+                Some(Answer::Name(name)) => Ok(Some((*name).to_owned())),
+                Some(Answer::Unknown) | None => Ok(None),
+                Some(Answer::Fail) => Err(ResolveError::Status(StatusCode::INTERNAL_SERVER_ERROR)),
+                Some(Answer::RateLimit) => Err(ResolveError::RateLimited),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn sorts_players_by_what_the_source_said() {
+        let players: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        let source = MockSource::new(vec![
+            (players[0], Answer::Name("Gegy")),
+            (players[1], Answer::Unknown),
+            (players[2], Answer::Fail),
+        ]);
+
+        let lookup = resolve_batch(&source, &players, Duration::from_millis(0)).await;
+
+        assert_eq!(lookup.resolved, vec![(players[0], "Gegy".to_owned())]);
+        assert_eq!(lookup.unknown, vec![players[1]]);
+        assert_eq!(lookup.failed, vec![players[2]]);
+        assert!(lookup.remaining.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_failed_player_does_not_stop_the_batch() {
+        let players: Vec<Uuid> = (0..2).map(|_| Uuid::new_v4()).collect();
+        let source = MockSource::new(vec![
+            (players[0], Answer::Fail),
+            (players[1], Answer::Name("Gegy")),
+        ]);
+
+        let lookup = resolve_batch(&source, &players, Duration::from_millis(0)).await;
+
+        assert_eq!(lookup.failed, vec![players[0]]);
+        assert_eq!(lookup.resolved, vec![(players[1], "Gegy".to_owned())]);
+    }
+
+    #[tokio::test]
+    async fn being_rate_limited_leaves_the_rest_for_later() {
+        let players: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        let source = MockSource::new(vec![
+            (players[0], Answer::Name("Gegy")),
+            (players[1], Answer::RateLimit),
+            (players[2], Answer::Name("Geek")),
+        ]);
+
+        let lookup = resolve_batch(&source, &players, Duration::from_millis(0)).await;
+
+        assert_eq!(lookup.resolved.len(), 1);
+        assert_eq!(lookup.remaining, vec![players[1], players[2]]);
+        assert_eq!(*source.asked.lock().unwrap(), vec![players[0], players[1]]);
+    }
+}
@@ -0,0 +1,194 @@
+use std::time::Duration;
+
+use hmac::{Hmac, Mac, NewMac};
+use log::warn;
+use serde::Serialize;
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::statistics::model::BundleUploadReport;
+use crate::WebhookConfig;
+
+const SIGNATURE_HEADER: &str = "X-Nucleoid-Signature";
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    StatsRejected {
+        server: String,
+        namespace: String,
+        game_id: Uuid,
+        // (player, stat, reason), with no player for global stats
+        stats: Vec<(Option<Uuid>, String, String)>,
+    },
+    MilestoneReached {
+        server: String,
+        player: Uuid,
+        namespace: String,
+        stat: String,
+        milestone: f64,
+    },
+    BundleProcessed {
+        server: String,
+        report: BundleUploadReport,
+    },
+}
+
+/// Posts stat events to an external service. Delivery is best-effort: each event is sent from its
+/// own task, so a slow or failing receiver never holds up stat processing.
+#[derive(Clone)]
+pub struct Webhook {
+    client: reqwest::Client,
+    config: WebhookConfig,
+}
+
+impl Webhook {
+    pub fn new(config: &WebhookConfig) -> Webhook {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_seconds))
+            .build()
+            .expect("failed to create webhook client");
+
+        Webhook { client, config: config.clone() }
+    }
+
+    pub fn emit(&self, event: WebhookEvent) {
+        let body = match serde_json::to_vec(&event) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Failed to serialize webhook event: {}", e);
+                return;
+            }
+        };
+
+        let webhook = self.clone();
+        tokio::spawn(async move { webhook.send(body).await });
+    }
+
+    async fn send(&self, body: Vec<u8>) {
+        let signature = self.config.secret.as_ref().map(|secret| sign(secret, &body));
+
+        let mut delay = Duration::from_secs(1);
+        for attempt in 0..=self.config.retries {
+            if attempt > 0 {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+
+            let mut request = self.client.post(&self.config.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            if let Some(signature) = &signature {
+                request = request.header(SIGNATURE_HEADER, signature.as_str());
+            }
+
+            match request.send().await {
+                Ok(response) if response.status().is_success() => return,
+                Ok(response) => warn!("Webhook returned {} (attempt {})", response.status(), attempt + 1),
+                Err(e) => warn!("Failed to send webhook (attempt {}): {}", attempt + 1, e),
+            }
+        }
+
+        warn!("Giving up on webhook event after {} attempts", self.config.retries + 1);
+    }
+}
+
+// The receiver can check events came from us by computing the same HMAC over the body.
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(body);
+    let signature = mac.finalize().into_bytes();
+
+    let hex: String = signature.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("sha256={}", hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use tokio::sync::mpsc;
+    use warp::Filter;
+    use warp::http::StatusCode;
+
+    use super::*;
+
+    // A receiver that fails the first `failures` requests, and passes on the signature and body of
+    // every request it gets.
+    fn mock_receiver(failures: usize) -> (SocketAddr, mpsc::UnboundedReceiver<(Option<String>, Bytes)>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let received = Arc::new(AtomicUsize::new(0));
+
+        let route = warp::post()
+            .and(warp::header::optional::<String>(SIGNATURE_HEADER))
+            .and(warp::body::bytes())
+            .map(move |signature, body| {
+                let _ = sender.send((signature, body));
+                if received.fetch_add(1, Ordering::SeqCst) < failures {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                } else {
+                    StatusCode::OK
+                }
+            });
+
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (address, receiver)
+    }
+
+    fn webhook(address: SocketAddr, secret: Option<&str>, retries: u32) -> Webhook {
+        Webhook::new(&WebhookConfig {
+            url: format!("http://{}/events", address),
+            secret: secret.map(str::to_owned),
+            timeout_seconds: 5,
+            retries,
+        })
+    }
+
+    #[test]
+    fn signatures_are_hmac_sha256_of_the_body() {
+        assert_eq!(
+            sign("key", b"The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8",
+        );
+    }
+
+    #[tokio::test]
+    async fn events_are_posted_with_their_signature() {
+        let (address, mut received) = mock_receiver(0);
+        let body = br#"{"event":"bundle_processed"}"#.to_vec();
+
+        webhook(address, Some("secret"), 0).send(body.clone()).await;
+
+        let (signature, received_body) = received.recv().await.unwrap();
+        assert_eq!(signature, Some(sign("secret", &body)));
+        assert_eq!(received_body, body);
+        assert!(received.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn events_are_unsigned_without_a_secret() {
+        let (address, mut received) = mock_receiver(0);
+
+        webhook(address, None, 0).send(b"{}".to_vec()).await;
+
+        let (signature, _) = received.recv().await.unwrap();
+        assert_eq!(signature, None);
+    }
+
+    #[tokio::test]
+    async fn failed_deliveries_are_retried() {
+        let (address, mut received) = mock_receiver(1);
+
+        webhook(address, Some("secret"), 2).send(b"{}".to_vec()).await;
+
+        // one failure, then a success that ends the retries
+        assert!(received.recv().await.is_some());
+        assert!(received.recv().await.is_some());
+        assert!(received.try_recv().is_err());
+    }
+}
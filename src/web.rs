@@ -1,38 +1,85 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use uuid::Uuid;
 use warp::Filter;
 use warp::http::StatusCode;
 use xtra::prelude::*;
 
 use crate::controller::*;
-use crate::statistics::database::{GetPlayerStats, StatisticsDatabaseError, GetGameStats};
-use crate::WebServerConfig;
+use crate::statistics::database::{GetPlayerStats, StatisticsDatabaseError, GetGameStats, GetGlobalStats, GetTypedPlayerStats, StatChangeEvent, SubscribeStatChanges, GetWeightedLeaderboard, GetLeaderboard, GetLeaderboardWindow, GetLeaderboards, GetNetworkLeaderboard, GetPlaytimeLeaderboard, GetStatMetadata, GetNamespaceSchema, ListStats, CompareNamespaces, ComparePlayers, GetPlayerOverview, GetPlayerPercentile, GetFlags, GetTrending, GetNamespaceActivity, GetNamespaceTrend, MAX_LEADERBOARD_SIZE, SearchPlayers, LookupPlayer, MAX_PLAYER_SEARCH_RESULTS};
+use crate::statistics::model::TrendGranularity;
+use crate::model::ServerStatus;
+use crate::{ResponseCompression, WebServerConfig};
 
 pub async fn run(controller: Address<Controller>, config: WebServerConfig) {
     let cors = warp::cors()
         .allow_any_origin();
 
+    let status_max_age = config.status_max_age_seconds.map(Duration::from_secs);
+
     let status = warp::path("status")
         .and(warp::path::param())
         .and_then({
             let controller = controller.clone();
-            move |channel| get_status(controller.clone(), channel)
+            move |channel| get_status(controller.clone(), channel, status_max_age)
         }).with(&cors);
 
     let player_game_stats = warp::path("stats")
         .and(warp::path("player"))
         .and(warp::path::param::<Uuid>())
         .and(warp::path::param::<String>())
+        .and(warp::query::<PlayerStatsQuery>())
         .and_then({
             let controller = controller.clone();
-            move |uuid, namespace| get_player_stats(controller.clone(), uuid, Some(namespace))
+            move |uuid, namespace, query| get_player_stats(controller.clone(), uuid, vec![namespace], query)
         }).with(&cors);
 
     let all_player_game_stats = warp::path("stats")
         .and(warp::path("player"))
         .and(warp::path::param::<Uuid>())
+        .and(warp::query::<PlayerStatsQuery>())
+        .and_then({
+            let controller = controller.clone();
+            move |uuid, query: PlayerStatsQuery| {
+                let namespaces = parse_namespaces(query.namespaces.as_deref());
+                get_player_stats(controller.clone(), uuid, namespaces, query)
+            }
+        }).with(&cors);
+
+    let global_stats = warp::path("stats")
+        .and(warp::path("global"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and_then({
+            let controller = controller.clone();
+            move |namespace| get_global_stats(controller.clone(), namespace)
+        }).with(&cors);
+
+    let compare_players = warp::path("stats")
+        .and(warp::path("compare"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::query::<ComparePlayersQuery>())
+        .and_then({
+            let controller = controller.clone();
+            move |namespace, query| compare_players(controller.clone(), namespace, query)
+        }).with(&cors);
+
+    let player_percentile = warp::path("stats")
+        .and(warp::path("percentile"))
+        .and(warp::path::param::<Uuid>())
+        .and(warp::path::param::<String>())
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
         .and_then({
             let controller = controller.clone();
-            move |uuid| get_player_stats(controller.clone(), uuid, None)
+            move |uuid, namespace, stat| get_player_percentile(controller.clone(), uuid, namespace, stat)
         }).with(&cors);
 
     let all_game_stats = warp::path("stats")
@@ -43,21 +90,239 @@ pub async fn run(controller: Address<Controller>, config: WebServerConfig) {
             move |uuid| get_game_stats(controller.clone(), uuid)
         }).with(&cors);
 
+    let network_leaderboard = network_leaderboard_path()
+        .and(warp::query::<LeaderboardQuery>())
+        .and_then({
+            let controller = controller.clone();
+            move |stat, query| get_network_leaderboard(controller.clone(), stat, query)
+        }).with(&cors);
+
+    let weighted_leaderboard = weighted_leaderboard_path()
+        .and(warp::query::<WeightedLeaderboardQuery>())
+        .and_then({
+            let controller = controller.clone();
+            move |namespace, query| get_weighted_leaderboard(controller.clone(), namespace, query)
+        }).with(&cors);
+
+    let leaderboard = leaderboard_path()
+        .and(warp::query::<StatLeaderboardQuery>())
+        .and_then({
+            let controller = controller.clone();
+            move |namespace, stat, query| get_leaderboard(controller.clone(), namespace, stat, query)
+        }).with(&cors);
+
+    let leaderboard_window = warp::path("stats")
+        .and(warp::path("leaderboard"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::param::<String>())
+        .and(warp::path("around"))
+        .and(warp::path::param::<Uuid>())
+        .and(warp::path::end())
+        .and(warp::query::<LeaderboardWindowQuery>())
+        .and_then({
+            let controller = controller.clone();
+            move |namespace, stat, uuid, query| get_leaderboard_window(controller.clone(), namespace, stat, uuid, query)
+        }).with(&cors);
+
+    let leaderboards = warp::path("stats")
+        .and(warp::path("leaderboards"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::query::<LeaderboardsQuery>())
+        .and_then({
+            let controller = controller.clone();
+            move |namespace, query| get_leaderboards(controller.clone(), namespace, query)
+        }).with(&cors);
+
+    let trending = warp::path("stats")
+        .and(warp::path("trending"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::query::<TrendingQuery>())
+        .and_then({
+            let controller = controller.clone();
+            move |namespace, stat, query| get_trending(controller.clone(), namespace, stat, query)
+        }).with(&cors);
+
+    let namespace_trend = warp::path("stats")
+        .and(warp::path("trend"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::query::<NamespaceTrendQuery>())
+        .and_then({
+            let controller = controller.clone();
+            move |namespace, stat, query| get_namespace_trend(controller.clone(), namespace, stat, query)
+        }).with(&cors);
+
+    let namespace_activity = warp::path("stats")
+        .and(warp::path("activity"))
+        .and(warp::path::end())
+        .and(warp::query::<ActivityQuery>())
+        .and_then({
+            let controller = controller.clone();
+            move |query| get_namespace_activity(controller.clone(), query)
+        }).with(&cors);
+
+    let playtime_leaderboard = warp::path("stats")
+        .and(warp::path("leaderboard"))
+        .and(warp::path("playtime"))
+        .and(warp::path::end())
+        .and(warp::query::<LeaderboardQuery>())
+        .and_then({
+            let controller = controller.clone();
+            move |query| get_playtime_leaderboard(controller.clone(), query)
+        }).with(&cors);
+
+    let stat_metadata = warp::path("stats")
+        .and(warp::path("metadata"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and_then({
+            let controller = controller.clone();
+            move |namespace| get_stat_metadata(controller.clone(), namespace)
+        }).with(&cors);
+
+    let namespace_schema = warp::path("stats")
+        .and(warp::path("schema"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and_then({
+            let controller = controller.clone();
+            move |namespace| get_namespace_schema(controller.clone(), namespace)
+        }).with(&cors);
+
+    let list_stats = warp::path("stats")
+        .and(warp::path("names"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and_then({
+            let controller = controller.clone();
+            move |namespace| list_stats(controller.clone(), namespace)
+        }).with(&cors);
+
+    let search_players = warp::path("players")
+        .and(warp::path("search"))
+        .and(warp::path::end())
+        .and(warp::query::<SearchPlayersQuery>())
+        .and_then({
+            let controller = controller.clone();
+            move |query| search_players(controller.clone(), query)
+        }).with(&cors);
+
+    let lookup_player = warp::path("players")
+        .and(warp::path("name"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and_then({
+            let controller = controller.clone();
+            move |username| lookup_player(controller.clone(), username)
+        }).with(&cors);
+
+    let player_overview = warp::path("players")
+        .and(warp::path::param::<Uuid>())
+        .and(warp::path("overview"))
+        .and(warp::path::end())
+        .and(warp::query::<PlayerOverviewQuery>())
+        .and_then({
+            let controller = controller.clone();
+            move |uuid, query| get_player_overview(controller.clone(), uuid, query)
+        }).with(&cors);
+
+    let player_standings = warp::path("players")
+        .and(warp::path::param::<Uuid>())
+        .and(warp::path("standings"))
+        .and(warp::path::end())
+        .and_then({
+            let controller = controller.clone();
+            move |uuid| get_player_standings(controller.clone(), uuid)
+        }).with(&cors);
+
+    let player_flags = warp::path("admin")
+        .and(admin_only(config.admin_token.clone()))
+        .and(warp::path("players"))
+        .and(warp::path::param::<Uuid>())
+        .and(warp::path("flags"))
+        .and(warp::path::end())
+        .and_then({
+            let controller = controller.clone();
+            move |uuid| get_player_flags(controller.clone(), uuid)
+        }).with(&cors);
+
+    // The stream carries every player's stat changes as they happen, so only the admin token may
+    // subscribe to it.
+    let stat_changes = warp::path("stats")
+        .and(warp::path("changes"))
+        .and(warp::path::end())
+        .and(admin_only(config.admin_token.clone()))
+        .and(warp::query::<StatChangesQuery>())
+        .and(warp::header::optional::<u64>("last-event-id"))
+        .and_then({
+            let controller = controller.clone();
+            move |query, last_event_id| get_stat_changes(controller.clone(), query, last_event_id)
+        }).with(&cors);
+
     let combined = status
         .or(player_game_stats)
         .or(all_player_game_stats)
-        .or(all_game_stats);
+        .or(global_stats)
+        .or(compare_players)
+        .or(player_percentile)
+        .or(all_game_stats)
+        .or(network_leaderboard)
+        .or(weighted_leaderboard)
+        .or(leaderboard)
+        .or(leaderboard_window)
+        .or(leaderboards)
+        .or(playtime_leaderboard)
+        .or(trending)
+        .or(namespace_trend)
+        .or(namespace_activity)
+        .or(stat_metadata)
+        .or(namespace_schema)
+        .or(list_stats)
+        .or(search_players)
+        .or(lookup_player)
+        .or(player_overview)
+        .or(player_standings)
+        .or(player_flags);
+
+    // Compressing the event stream would hold back events until enough had built up, so it's
+    // left out.
+    let address = ([127, 0, 0, 1], config.port);
+    match config.compression {
+        Some(ResponseCompression::Gzip) => warp::serve(combined.with(warp::compression::gzip()).or(stat_changes)).run(address).await,
+        Some(ResponseCompression::Brotli) => warp::serve(combined.with(warp::compression::brotli()).or(stat_changes)).run(address).await,
+        None => warp::serve(combined.or(stat_changes)).run(address).await,
+    }
+}
 
-    warp::serve(combined)
-        .run(([127, 0, 0, 1], config.port))
-        .await;
+#[derive(Serialize)]
+struct StatusResponse {
+    #[serde(flatten)]
+    status: ServerStatus,
+    updated_at: DateTime<Utc>,
+    // The server has disconnected or stopped sending updates, so this is only its last known status.
+    stale: bool,
 }
 
-async fn get_status(controller: Address<Controller>, channel: String) -> ApiResult {
+async fn get_status(controller: Address<Controller>, channel: String, max_age: Option<Duration>) -> ApiResult {
     match controller.send(GetStatus(channel)).await {
         Ok(status) => {
             Ok(match status {
-                Some(status) => Box::new(warp::reply::json(&status)),
+                Some(cached) => {
+                    let expired = match (max_age, SystemTime::now().duration_since(cached.updated_at)) {
+                        (Some(max_age), Ok(age)) => age > max_age,
+                        _ => false,
+                    };
+
+                    Box::new(warp::reply::json(&StatusResponse {
+                        status: cached.status,
+                        updated_at: DateTime::<Utc>::from(cached.updated_at),
+                        stale: !cached.connected || expired,
+                    }))
+                }
                 None => Box::new(warp::reply::with_status("Not found", StatusCode::NOT_FOUND)),
             })
         },
@@ -67,7 +332,24 @@ async fn get_status(controller: Address<Controller>, channel: String) -> ApiResu
 
 type ApiResult = Result<Box<dyn warp::Reply>, warp::Rejection>;
 
-async fn get_player_stats(controller: Address<Controller>, uuid: Uuid, namespace: Option<String>) -> ApiResult {
+#[derive(Deserialize)]
+struct PlayerStatsQuery {
+    // Returns stats with their stored int/float type rather than flattening them all to floats.
+    #[serde(default)]
+    typed: bool,
+    #[serde(default)]
+    rollup: bool,
+    // comma-separated namespaces to limit the stats to, when not asking for a single namespace
+    #[serde(default)]
+    namespaces: Option<String>,
+    #[serde(default)]
+    format: ResponseFormat,
+    // leaves out totals that are zero
+    #[serde(default)]
+    omit_zero: bool,
+}
+
+async fn get_player_stats(controller: Address<Controller>, uuid: Uuid, namespaces: Vec<String>, query: PlayerStatsQuery) -> ApiResult {
     let statistics = if let Some(statistics) = controller.send(GetStatisticsDatabaseController)
         .await.expect("controller disconnected") {
         statistics
@@ -75,30 +357,141 @@ async fn get_player_stats(controller: Address<Controller>, uuid: Uuid, namespace
         return Ok(send_http_status(StatusCode::NOT_FOUND));
     };
 
-    if let Some(namespace) = &namespace {
-        for c in namespace.chars() {
-            if !((c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || (c >= '0' && c <= '9') || c == '_') {
-                return Ok(send_http_status(StatusCode::BAD_REQUEST));
-            }
+    if !namespaces.iter().all(|namespace| is_valid_namespace(namespace)) {
+        return Ok(send_http_status(StatusCode::BAD_REQUEST));
+    }
+
+    if query.typed {
+        let res = statistics.send(GetTypedPlayerStats {
+            uuid,
+            namespaces,
+            rollup: query.rollup,
+            omit_zero: query.omit_zero,
+        }).await.unwrap();
+        Ok(query.format.send_stats_response(res))
+    } else {
+        let res = statistics.send(GetPlayerStats {
+            uuid,
+            namespaces,
+            rollup: query.rollup,
+            omit_zero: query.omit_zero,
+        }).await.unwrap();
+        Ok(query.format.send_stats_response(res))
+    }
+}
+
+#[derive(Deserialize, Copy, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+enum ResponseFormat {
+    Json,
+    // smaller and quicker to parse, for clients fetching a lot of stats
+    Msgpack,
+}
+
+impl Default for ResponseFormat {
+    fn default() -> Self {
+        ResponseFormat::Json
+    }
+}
+
+impl ResponseFormat {
+    fn send_stats_response<T: Serialize>(self, res: Result<Option<T>, StatisticsDatabaseError>) -> Box<dyn warp::Reply> {
+        match (self, res) {
+            (ResponseFormat::Msgpack, Ok(Some(stats))) => match rmp_serde::to_vec_named(&stats) {
+                Ok(bytes) => Box::new(warp::reply::with_header(bytes, "content-type", "application/msgpack")),
+                Err(e) => {
+                    log::warn!("failed to encode response as messagepack: {}", e);
+                    send_http_status(StatusCode::INTERNAL_SERVER_ERROR)
+                }
+            },
+            (_, res) => send_stats_response(res),
         }
     }
+}
 
-    let res = statistics.send(GetPlayerStats {
-        uuid,
-        namespace,
-    }).await.unwrap();
-    return match res {
-        Ok(stats) => {
-            Ok(if let Some(stats) = stats {
-                Box::new(warp::reply::json(&stats))
-            } else {
-                send_http_status(StatusCode::NOT_FOUND)
-            })
-        },
-        Err(e) => {
-            Ok(handle_server_error(&e))
+#[derive(Deserialize)]
+struct PlayerOverviewQuery {
+    // comma-separated namespaces to limit the stats to
+    #[serde(default)]
+    namespaces: Option<String>,
+}
+
+async fn get_player_standings(controller: Address<Controller>, uuid: Uuid) -> ApiResult {
+    let statistics = if let Some(statistics) = controller.send(GetStatisticsDatabaseController)
+        .await.expect("controller disconnected") {
+        statistics
+    } else {
+        return Ok(send_http_status(StatusCode::NOT_FOUND));
+    };
+
+    let res = statistics.send(CompareNamespaces { uuid }).await.unwrap();
+    Ok(send_stats_response(res.map(Some)))
+}
+
+async fn get_player_overview(controller: Address<Controller>, uuid: Uuid, query: PlayerOverviewQuery) -> ApiResult {
+    let statistics = if let Some(statistics) = controller.send(GetStatisticsDatabaseController)
+        .await.expect("controller disconnected") {
+        statistics
+    } else {
+        return Ok(send_http_status(StatusCode::NOT_FOUND));
+    };
+
+    let namespaces: Option<Vec<String>> = query.namespaces.as_deref()
+        .map(|namespaces| namespaces.split(',').filter(|namespace| !namespace.is_empty()).map(str::to_owned).collect());
+    if let Some(namespaces) = &namespaces {
+        if !namespaces.iter().all(|namespace| is_valid_namespace(namespace)) {
+            return Ok(send_http_status(StatusCode::BAD_REQUEST));
         }
     }
+
+    let overview = statistics.send(GetPlayerOverview { uuid, namespaces }).await.unwrap();
+    Ok(Box::new(warp::reply::json(&overview)))
+}
+
+async fn get_global_stats(controller: Address<Controller>, namespace: String) -> ApiResult {
+    let statistics = if let Some(statistics) = controller.send(GetStatisticsDatabaseController)
+        .await.expect("controller disconnected") {
+        statistics
+    } else {
+        return Ok(send_http_status(StatusCode::NOT_FOUND));
+    };
+
+    if !is_valid_namespace(&namespace) {
+        return Ok(send_http_status(StatusCode::BAD_REQUEST));
+    }
+
+    let res = statistics.send(GetGlobalStats { namespace }).await.unwrap();
+    Ok(send_stats_response(res))
+}
+
+#[derive(Deserialize)]
+struct ComparePlayersQuery {
+    // comma-separated player uuids and stat names
+    players: String,
+    stats: String,
+}
+
+async fn compare_players(controller: Address<Controller>, namespace: String, query: ComparePlayersQuery) -> ApiResult {
+    let statistics = if let Some(statistics) = controller.send(GetStatisticsDatabaseController)
+        .await.expect("controller disconnected") {
+        statistics
+    } else {
+        return Ok(send_http_status(StatusCode::NOT_FOUND));
+    };
+
+    if !is_valid_namespace(&namespace) {
+        return Ok(send_http_status(StatusCode::BAD_REQUEST));
+    }
+
+    let uuids: Result<Vec<Uuid>, _> = query.players.split(',').map(Uuid::parse_str).collect();
+    let uuids = match uuids {
+        Ok(uuids) => uuids,
+        Err(_) => return Ok(send_http_status(StatusCode::BAD_REQUEST)),
+    };
+    let stats = query.stats.split(',').map(|stat| stat.to_owned()).collect();
+
+    let res = statistics.send(ComparePlayers { uuids, namespace, stats }).await.unwrap();
+    Ok(send_stats_response(res.map(Some)))
 }
 
 async fn get_game_stats(controller: Address<Controller>, uuid: Uuid) -> ApiResult {
@@ -124,7 +517,531 @@ async fn get_game_stats(controller: Address<Controller>, uuid: Uuid) -> ApiResul
     }
 }
 
+#[derive(Deserialize)]
+struct WeightedLeaderboardQuery {
+    // comma-separated `stat:weight` pairs, e.g. `wins:3,kills:1,deaths:-0.5`
+    weights: String,
+    #[serde(default = "default_leaderboard_limit")]
+    limit: u32,
+    #[serde(default)]
+    rollup: bool,
+}
+
+fn default_leaderboard_limit() -> u32 {
+    MAX_LEADERBOARD_SIZE
+}
+
+async fn get_weighted_leaderboard(controller: Address<Controller>, namespace: String, query: WeightedLeaderboardQuery) -> ApiResult {
+    let statistics = if let Some(statistics) = controller.send(GetStatisticsDatabaseController)
+        .await.expect("controller disconnected") {
+        statistics
+    } else {
+        return Ok(send_http_status(StatusCode::NOT_FOUND));
+    };
+
+    if !is_valid_namespace(&namespace) {
+        return Ok(send_http_status(StatusCode::BAD_REQUEST));
+    }
+
+    let mut weights = HashMap::new();
+    for pair in query.weights.split(',') {
+        let weight = pair.split_once(':')
+            .and_then(|(stat, weight)| weight.parse::<f64>().ok().map(|weight| (stat, weight)));
+        match weight {
+            Some((stat, weight)) => weights.insert(stat.to_owned(), weight),
+            None => return Ok(send_http_status(StatusCode::BAD_REQUEST)),
+        };
+    }
+
+    let res = statistics.send(GetWeightedLeaderboard {
+        namespace,
+        weights,
+        limit: query.limit,
+        rollup: query.rollup,
+        include_flagged: false,
+    }).await.unwrap();
+    Ok(send_stats_response(res.map(Some)))
+}
+
+#[derive(Deserialize)]
+struct LeaderboardQuery {
+    #[serde(default = "default_leaderboard_limit")]
+    limit: u32,
+}
+
+#[derive(Deserialize)]
+struct StatLeaderboardQuery {
+    #[serde(default = "default_leaderboard_limit")]
+    limit: u32,
+    #[serde(default)]
+    rollup: bool,
+    // comma-separated player uuids to rank among themselves
+    players: Option<String>,
+    // "asc" or "desc", instead of whichever is better for the stat
+    order: Option<String>,
+}
+
+async fn get_leaderboard(controller: Address<Controller>, namespace: String, stat: String, query: StatLeaderboardQuery) -> ApiResult {
+    let statistics = if let Some(statistics) = controller.send(GetStatisticsDatabaseController)
+        .await.expect("controller disconnected") {
+        statistics
+    } else {
+        return Ok(send_http_status(StatusCode::NOT_FOUND));
+    };
+
+    if !is_valid_namespace(&namespace) {
+        return Ok(send_http_status(StatusCode::BAD_REQUEST));
+    }
+
+    let restrict_to = match query.players {
+        Some(players) => match players.split(',').map(Uuid::parse_str).collect::<Result<Vec<_>, _>>() {
+            Ok(uuids) => Some(uuids),
+            Err(_) => return Ok(send_http_status(StatusCode::BAD_REQUEST)),
+        },
+        None => None,
+    };
+
+    let higher_is_better = match query.order.as_deref() {
+        Some("desc") => Some(true),
+        Some("asc") => Some(false),
+        Some(_) => return Ok(send_http_status(StatusCode::BAD_REQUEST)),
+        None => None,
+    };
+
+    let res = statistics.send(GetLeaderboard {
+        namespace,
+        stat,
+        limit: query.limit,
+        rollup: query.rollup,
+        restrict_to,
+        include_flagged: false,
+        higher_is_better,
+    }).await.unwrap();
+    Ok(send_stats_response(res.map(Some)))
+}
+
+async fn get_network_leaderboard(controller: Address<Controller>, stat: String, query: LeaderboardQuery) -> ApiResult {
+    let statistics = if let Some(statistics) = controller.send(GetStatisticsDatabaseController)
+        .await.expect("controller disconnected") {
+        statistics
+    } else {
+        return Ok(send_http_status(StatusCode::NOT_FOUND));
+    };
+
+    let res = statistics.send(GetNetworkLeaderboard { stat, limit: query.limit }).await.unwrap();
+    Ok(send_stats_response(res.map(Some)))
+}
+
+#[derive(Deserialize)]
+struct LeaderboardWindowQuery {
+    #[serde(default = "default_leaderboard_window_radius")]
+    radius: u32,
+}
+
+fn default_leaderboard_window_radius() -> u32 {
+    5
+}
+
+async fn get_leaderboard_window(controller: Address<Controller>, namespace: String, stat: String, uuid: Uuid, query: LeaderboardWindowQuery) -> ApiResult {
+    let statistics = if let Some(statistics) = controller.send(GetStatisticsDatabaseController)
+        .await.expect("controller disconnected") {
+        statistics
+    } else {
+        return Ok(send_http_status(StatusCode::NOT_FOUND));
+    };
+
+    if !is_valid_namespace(&namespace) {
+        return Ok(send_http_status(StatusCode::BAD_REQUEST));
+    }
+
+    let res = statistics.send(GetLeaderboardWindow { namespace, stat, uuid, radius: query.radius }).await.unwrap();
+    Ok(send_stats_response(res))
+}
+
+#[derive(Deserialize)]
+struct LeaderboardsQuery {
+    // comma-separated stat names
+    stats: String,
+    #[serde(default = "default_leaderboard_limit")]
+    limit: u32,
+    #[serde(default)]
+    rollup: bool,
+}
+
+async fn get_leaderboards(controller: Address<Controller>, namespace: String, query: LeaderboardsQuery) -> ApiResult {
+    let statistics = if let Some(statistics) = controller.send(GetStatisticsDatabaseController)
+        .await.expect("controller disconnected") {
+        statistics
+    } else {
+        return Ok(send_http_status(StatusCode::NOT_FOUND));
+    };
+
+    if !is_valid_namespace(&namespace) {
+        return Ok(send_http_status(StatusCode::BAD_REQUEST));
+    }
+
+    let stats = query.stats.split(',').map(|stat| stat.to_owned()).collect();
+
+    let res = statistics.send(GetLeaderboards {
+        namespace,
+        stats,
+        limit: query.limit,
+        rollup: query.rollup,
+        include_flagged: false,
+    }).await.unwrap();
+    Ok(send_stats_response(res.map(Some)))
+}
+
+async fn get_player_percentile(controller: Address<Controller>, uuid: Uuid, namespace: String, stat: String) -> ApiResult {
+    let statistics = if let Some(statistics) = controller.send(GetStatisticsDatabaseController)
+        .await.expect("controller disconnected") {
+        statistics
+    } else {
+        return Ok(send_http_status(StatusCode::NOT_FOUND));
+    };
+
+    if !is_valid_namespace(&namespace) {
+        return Ok(send_http_status(StatusCode::BAD_REQUEST));
+    }
+
+    let res = statistics.send(GetPlayerPercentile { uuid, namespace, stat }).await.unwrap();
+    Ok(send_stats_response(res))
+}
+
+#[derive(Deserialize)]
+struct TrendingQuery {
+    #[serde(default = "default_trending_days")]
+    days: u64,
+    #[serde(default = "default_leaderboard_limit")]
+    limit: u32,
+}
+
+fn default_trending_days() -> u64 {
+    7
+}
+
+#[derive(Deserialize)]
+struct NamespaceTrendQuery {
+    from: DateTime<Utc>,
+    #[serde(default = "Utc::now")]
+    to: DateTime<Utc>,
+    #[serde(default = "default_trend_granularity")]
+    granularity: TrendGranularity,
+}
+
+fn default_trend_granularity() -> TrendGranularity {
+    TrendGranularity::Day
+}
+
+async fn get_namespace_trend(controller: Address<Controller>, namespace: String, stat: String, query: NamespaceTrendQuery) -> ApiResult {
+    let statistics = if let Some(statistics) = controller.send(GetStatisticsDatabaseController)
+        .await.expect("controller disconnected") {
+        statistics
+    } else {
+        return Ok(send_http_status(StatusCode::NOT_FOUND));
+    };
+
+    if !is_valid_namespace(&namespace) {
+        return Ok(send_http_status(StatusCode::BAD_REQUEST));
+    }
+
+    let res = statistics.send(GetNamespaceTrend {
+        namespace,
+        stat,
+        from: query.from,
+        to: query.to,
+        granularity: query.granularity,
+    }).await.unwrap();
+    Ok(send_stats_response(res.map(Some)))
+}
+
+async fn get_trending(controller: Address<Controller>, namespace: String, stat: String, query: TrendingQuery) -> ApiResult {
+    let statistics = if let Some(statistics) = controller.send(GetStatisticsDatabaseController)
+        .await.expect("controller disconnected") {
+        statistics
+    } else {
+        return Ok(send_http_status(StatusCode::NOT_FOUND));
+    };
+
+    if !is_valid_namespace(&namespace) {
+        return Ok(send_http_status(StatusCode::BAD_REQUEST));
+    }
+
+    let res = statistics.send(GetTrending {
+        namespace,
+        stat,
+        window: Duration::from_secs(query.days.saturating_mul(24 * 60 * 60)),
+        limit: query.limit,
+    }).await.unwrap();
+    Ok(send_stats_response(res.map(Some)))
+}
+
+#[derive(Deserialize)]
+struct ActivityQuery {
+    #[serde(default = "default_activity_hours")]
+    hours: u64,
+    #[serde(default = "default_leaderboard_limit")]
+    limit: u32,
+}
+
+fn default_activity_hours() -> u64 {
+    24
+}
+
+async fn get_namespace_activity(controller: Address<Controller>, query: ActivityQuery) -> ApiResult {
+    let statistics = if let Some(statistics) = controller.send(GetStatisticsDatabaseController)
+        .await.expect("controller disconnected") {
+        statistics
+    } else {
+        return Ok(send_http_status(StatusCode::NOT_FOUND));
+    };
+
+    let res = statistics.send(GetNamespaceActivity {
+        window: Duration::from_secs(query.hours.saturating_mul(60 * 60)),
+        limit: query.limit,
+    }).await.unwrap();
+    Ok(send_stats_response(res.map(Some)))
+}
+
+async fn get_playtime_leaderboard(controller: Address<Controller>, query: LeaderboardQuery) -> ApiResult {
+    let statistics = if let Some(statistics) = controller.send(GetStatisticsDatabaseController)
+        .await.expect("controller disconnected") {
+        statistics
+    } else {
+        return Ok(send_http_status(StatusCode::NOT_FOUND));
+    };
+
+    let res = statistics.send(GetPlaytimeLeaderboard { limit: query.limit, include_flagged: false }).await.unwrap();
+    Ok(send_stats_response(res.map(Some)))
+}
+
+async fn get_stat_metadata(controller: Address<Controller>, namespace: String) -> ApiResult {
+    let statistics = if let Some(statistics) = controller.send(GetStatisticsDatabaseController)
+        .await.expect("controller disconnected") {
+        statistics
+    } else {
+        return Ok(send_http_status(StatusCode::NOT_FOUND));
+    };
+
+    if !is_valid_namespace(&namespace) {
+        return Ok(send_http_status(StatusCode::BAD_REQUEST));
+    }
+
+    let res = statistics.send(GetStatMetadata { namespace }).await.unwrap();
+    Ok(send_stats_response(res.map(Some)))
+}
+
+async fn get_namespace_schema(controller: Address<Controller>, namespace: String) -> ApiResult {
+    let statistics = if let Some(statistics) = controller.send(GetStatisticsDatabaseController)
+        .await.expect("controller disconnected") {
+        statistics
+    } else {
+        return Ok(send_http_status(StatusCode::NOT_FOUND));
+    };
+
+    if !is_valid_namespace(&namespace) {
+        return Ok(send_http_status(StatusCode::BAD_REQUEST));
+    }
+
+    let res = statistics.send(GetNamespaceSchema { namespace }).await.unwrap();
+    Ok(send_stats_response(res.map(Some)))
+}
+
+async fn list_stats(controller: Address<Controller>, namespace: String) -> ApiResult {
+    let statistics = if let Some(statistics) = controller.send(GetStatisticsDatabaseController)
+        .await.expect("controller disconnected") {
+        statistics
+    } else {
+        return Ok(send_http_status(StatusCode::NOT_FOUND));
+    };
+
+    if !is_valid_namespace(&namespace) {
+        return Ok(send_http_status(StatusCode::BAD_REQUEST));
+    }
+
+    let res = statistics.send(ListStats { namespace }).await.unwrap();
+    Ok(send_stats_response(res.map(Some)))
+}
+
+#[derive(Deserialize)]
+struct SearchPlayersQuery {
+    prefix: String,
+    #[serde(default = "default_player_search_limit")]
+    limit: u32,
+}
+
+fn default_player_search_limit() -> u32 {
+    MAX_PLAYER_SEARCH_RESULTS
+}
+
+async fn search_players(controller: Address<Controller>, query: SearchPlayersQuery) -> ApiResult {
+    let statistics = if let Some(statistics) = controller.send(GetStatisticsDatabaseController)
+        .await.expect("controller disconnected") {
+        statistics
+    } else {
+        return Ok(send_http_status(StatusCode::NOT_FOUND));
+    };
+
+    let res = statistics.send(SearchPlayers {
+        prefix: query.prefix,
+        limit: query.limit,
+    }).await.unwrap();
+    Ok(send_stats_response(res.map(Some)))
+}
+
+async fn lookup_player(controller: Address<Controller>, username: String) -> ApiResult {
+    let statistics = if let Some(statistics) = controller.send(GetStatisticsDatabaseController)
+        .await.expect("controller disconnected") {
+        statistics
+    } else {
+        return Ok(send_http_status(StatusCode::NOT_FOUND));
+    };
+
+    let res = statistics.send(LookupPlayer { username }).await.unwrap();
+    Ok(send_stats_response(res))
+}
+
+async fn get_player_flags(controller: Address<Controller>, uuid: Uuid) -> ApiResult {
+    let statistics = if let Some(statistics) = controller.send(GetStatisticsDatabaseController)
+        .await.expect("controller disconnected") {
+        statistics
+    } else {
+        return Ok(send_http_status(StatusCode::NOT_FOUND));
+    };
+
+    let res = statistics.send(GetFlags { uuid }).await.unwrap();
+    Ok(send_stats_response(res.map(Some)))
+}
+
+#[derive(Deserialize)]
+struct StatChangesQuery {
+    namespace: Option<String>,
+}
+
+// Browsers reconnecting an event stream send the id of the last event they saw, so they're caught
+// up on whatever was sent while they were away, or sent a `gap` event when that can't be done.
+async fn get_stat_changes(controller: Address<Controller>, query: StatChangesQuery, last_event_id: Option<u64>) -> ApiResult {
+    if let Some(namespace) = &query.namespace {
+        if !is_valid_namespace(namespace) {
+            return Ok(send_http_status(StatusCode::BAD_REQUEST));
+        }
+    }
+
+    let subscribe = SubscribeStatChanges { namespace: query.namespace, after: last_event_id };
+    let subscription = match controller.send(subscribe).await.expect("controller disconnected") {
+        Some(subscription) => subscription,
+        None => return Ok(send_http_status(StatusCode::NOT_FOUND)),
+    };
+
+    let events = futures::stream::unfold(subscription, |mut subscription| async move {
+        subscription.recv().await.map(|change| (change, subscription))
+    });
+
+    // A gap has no id of its own, so a browser that reconnects after one is still told about it.
+    let events = events.map(|event| match event {
+        StatChangeEvent::Changed(change) => {
+            warp::sse::Event::default()
+                .id(change.id.to_string())
+                .json_data(&change)
+        }
+        StatChangeEvent::Gap => Ok(warp::sse::Event::default().event("gap").data("")),
+    });
+    Ok(Box::new(warp::sse::reply(warp::sse::keep_alive().stream(events))))
+}
+
+fn leaderboard_path() -> impl Filter<Extract = (String, String), Error = warp::Rejection> + Clone {
+    warp::path("stats")
+        .and(warp::path("leaderboard"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+}
+
+// The network and weighted boards have their own prefixes, so that they can't be mistaken for
+// the leaderboard of a namespace called `network` or a stat called `weighted`.
+fn network_leaderboard_path() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    warp::path("stats")
+        .and(warp::path("network_leaderboard"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+}
+
+fn weighted_leaderboard_path() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    warp::path("stats")
+        .and(warp::path("weighted_leaderboard"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+}
+
+// `?namespaces=bedwars,skywars`, where leaving it out (or empty) means every namespace.
+fn parse_namespaces(namespaces: Option<&str>) -> Vec<String> {
+    namespaces
+        .map(|namespaces| namespaces.split(',').filter(|namespace| !namespace.is_empty()).map(str::to_owned).collect())
+        .unwrap_or_default()
+}
+
+// Only lets through requests carrying the admin token. Anything else is turned away as if the
+// route didn't exist, as is everything when there's no token configured.
+fn admin_only(token: Option<String>) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |authorization: Option<String>| {
+            let authorized = is_authorized(token.as_deref(), authorization.as_deref());
+            async move {
+                if authorized {
+                    Ok(())
+                } else {
+                    Err(warp::reject::not_found())
+                }
+            }
+        })
+        .untuple_one()
+}
+
+fn is_authorized(token: Option<&str>, authorization: Option<&str>) -> bool {
+    match (token, authorization.and_then(|authorization| authorization.strip_prefix("Bearer "))) {
+        (Some(token), Some(given)) => !token.is_empty() && tokens_match(token, given),
+        _ => false,
+    }
+}
+
+// Compares MACs of the two rather than the tokens themselves, since the MACs are checked in constant
+// time. Comparing the tokens directly would give away how much of a wrong token was right.
+fn tokens_match(token: &str, given: &str) -> bool {
+    let mac = |value: &str| {
+        let mut mac = Hmac::<Sha256>::new_varkey(token.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(value.as_bytes());
+        mac
+    };
+    mac(given).verify(&mac(token).finalize().into_bytes()).is_ok()
+}
+
+fn send_stats_response<T: Serialize>(res: Result<Option<T>, StatisticsDatabaseError>) -> Box<dyn warp::Reply> {
+    match res {
+        Ok(Some(stats)) => Box::new(warp::reply::json(&stats)),
+        Ok(None) => send_http_status(StatusCode::NOT_FOUND),
+        Err(e) => handle_server_error(&e),
+    }
+}
+
+fn is_valid_namespace(namespace: &str) -> bool {
+    // `:` separates sub-namespaces, like `bedwars:solo`
+    namespace.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
 fn handle_server_error(e: &StatisticsDatabaseError) -> Box<dyn warp::Reply> {
+    if let StatisticsDatabaseError::InvalidRequest(message) = e {
+        return Box::new(warp::reply::with_status(message.clone(), StatusCode::BAD_REQUEST));
+    }
+    if let StatisticsDatabaseError::Timeout | StatisticsDatabaseError::ConnectionError(_) = e {
+        log::warn!("error handling request: {}", e);
+        return send_http_status(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    if let StatisticsDatabaseError::CorruptData(error) = e {
+        tracing::warn!(%error, "corrupt statistics data while handling request");
+        return send_http_status(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
     log::warn!("error handling request: {}", e);
     send_http_status(StatusCode::INTERNAL_SERVER_ERROR)
 }
@@ -132,3 +1049,112 @@ fn handle_server_error(e: &StatisticsDatabaseError) -> Box<dyn warp::Reply> {
 fn send_http_status(status: StatusCode) -> Box<dyn warp::Reply> {
     Box::new(warp::reply::with_status(status.canonical_reason().unwrap_or(""), status))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admin_routes_need_the_token() {
+        assert!(is_authorized(Some("secret"), Some("Bearer secret")));
+        assert!(!is_authorized(Some("secret"), Some("Bearer wrong")));
+        assert!(!is_authorized(Some("secret"), Some("secret")));
+        assert!(!is_authorized(Some("secret"), None));
+    }
+
+    #[test]
+    fn tokens_only_match_exactly() {
+        assert!(tokens_match("secret", "secret"));
+        assert!(!tokens_match("secret", "secre"));
+        assert!(!tokens_match("secret", "secrets"));
+        assert!(!tokens_match("secret", ""));
+    }
+
+    #[test]
+    fn admin_routes_are_closed_without_a_token() {
+        assert!(!is_authorized(None, Some("Bearer secret")));
+        assert!(!is_authorized(Some(""), Some("Bearer ")));
+    }
+
+    #[test]
+    fn messagepack_player_stats_decode_to_what_was_sent() {
+        use crate::statistics::model::{PlayerStatsResponse, StatValue, TypedPlayerStatsResponse};
+
+        let mut stats = HashMap::new();
+        stats.insert("kills".to_owned(), 4.0);
+        stats.insert("fastest_win".to_owned(), 93.5);
+        let mut response: PlayerStatsResponse = HashMap::new();
+        response.insert("bedwars".to_owned(), stats);
+
+        let bytes = rmp_serde::to_vec_named(&response).unwrap();
+        let decoded: PlayerStatsResponse = rmp_serde::from_read_ref(&bytes).unwrap();
+        assert_eq!(decoded, response);
+
+        let mut stats = HashMap::new();
+        stats.insert("kills".to_owned(), StatValue::Int(9_007_199_254_740_993));
+        stats.insert("coins".to_owned(), StatValue::Decimal("12.34".parse().unwrap()));
+        let mut typed: TypedPlayerStatsResponse = HashMap::new();
+        typed.insert("bedwars".to_owned(), stats);
+
+        let bytes = rmp_serde::to_vec_named(&typed).unwrap();
+        let decoded: TypedPlayerStatsResponse = rmp_serde::from_read_ref(&bytes).unwrap();
+        assert_eq!(decoded, typed);
+    }
+
+    #[tokio::test]
+    async fn leaderboard_routes_do_not_overlap() {
+        let request = |path: &str| warp::test::request().path(path);
+
+        assert_eq!(request("/stats/network_leaderboard/kills").filter(&network_leaderboard_path()).await.unwrap(), "kills");
+        assert_eq!(request("/stats/weighted_leaderboard/bedwars").filter(&weighted_leaderboard_path()).await.unwrap(), "bedwars");
+
+        // a namespace called network and a stat called weighted are just another leaderboard
+        let (namespace, stat) = request("/stats/leaderboard/network/weighted").filter(&leaderboard_path()).await.unwrap();
+        assert_eq!((namespace.as_str(), stat.as_str()), ("network", "weighted"));
+        assert!(!request("/stats/leaderboard/network/weighted").matches(&network_leaderboard_path()).await);
+        assert!(!request("/stats/leaderboard/network/weighted").matches(&weighted_leaderboard_path()).await);
+
+        assert!(!request("/stats/network_leaderboard/kills").matches(&leaderboard_path()).await);
+        assert!(!request("/stats/weighted_leaderboard/bedwars").matches(&leaderboard_path()).await);
+        assert!(!request("/stats/network_leaderboard/kills/extra").matches(&network_leaderboard_path()).await);
+    }
+
+    #[tokio::test]
+    async fn admin_only_turns_away_requests_without_the_token() {
+        let filter = || warp::path("changes").and(admin_only(Some("secret".to_owned())));
+
+        assert!(warp::test::request().path("/changes").header("authorization", "Bearer secret").matches(&filter()).await);
+        assert!(!warp::test::request().path("/changes").header("authorization", "Bearer wrong").matches(&filter()).await);
+        assert!(!warp::test::request().path("/changes").matches(&filter()).await);
+
+        let unconfigured = warp::path("changes").and(admin_only(None));
+        assert!(!warp::test::request().path("/changes").header("authorization", "Bearer secret").matches(&unconfigured).await);
+    }
+
+    #[test]
+    fn several_namespaces_can_be_asked_for_at_once() {
+        assert_eq!(parse_namespaces(Some("bedwars,skywars")), vec!["bedwars", "skywars"]);
+        assert_eq!(parse_namespaces(Some("bedwars,,")), vec!["bedwars"]);
+        assert!(parse_namespaces(Some("")).is_empty());
+        assert!(parse_namespaces(None).is_empty());
+    }
+
+    #[tokio::test]
+    async fn gzipped_responses_decompress_to_the_original() {
+        use std::io::Read;
+
+        let body = serde_json::to_string(&(0..1000).map(|i| (format!("stat_{}", i), i)).collect::<HashMap<_, _>>()).unwrap();
+        let route = {
+            let body = body.clone();
+            warp::any().map(move || body.clone()).with(warp::compression::gzip())
+        };
+
+        let response = warp::test::request().header("accept-encoding", "gzip").reply(&route).await;
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        assert!(response.body().len() < body.len());
+
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(&response.body()[..]).read_to_string(&mut decompressed).unwrap();
+        assert_eq!(decompressed, body);
+    }
+}